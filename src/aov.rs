@@ -0,0 +1,195 @@
+// Arbitrary output variables: Extra images besides the final color that are useful for compositing
+
+use crate::color::RGBColor;
+use crate::render_params::AovParameters;
+
+/// A single extra output buffer. Values are stored as colors, but most AOVs only use some
+/// of the components (see `channels`).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Aov {
+    /// Distance from the camera to the first hit. Infinity where nothing was hit
+    Depth,
+
+    /// World space surface normal at the first hit
+    Normal,
+
+    /// Material color at the first hit, without any lighting
+    Albedo,
+
+    /// Texture coordinates at the first hit
+    Uv,
+
+    /// Index of the first hit object, starting at 1. Background pixels are 0
+    ObjectId,
+
+    /// Ambient occlusion at the first hit at full strength. 1 means unoccluded
+    Ao,
+
+    /// Light that reaches the camera through a reflection at the first hit
+    Reflection,
+
+    /// Light that reaches the camera through a refraction at the first hit
+    Refraction,
+
+    /// Number of samples that were taken for the pixel
+    Samples,
+}
+
+impl Aov {
+    pub const ALL: [Aov; 9] = [
+        Aov::Depth,
+        Aov::Normal,
+        Aov::Albedo,
+        Aov::Uv,
+        Aov::ObjectId,
+        Aov::Ao,
+        Aov::Reflection,
+        Aov::Refraction,
+        Aov::Samples,
+    ];
+
+    /// Used as layer name in EXR files and as file name suffix otherwise
+    pub fn name(self) -> &'static str {
+        match self {
+            Aov::Depth => "depth",
+            Aov::Normal => "normal",
+            Aov::Albedo => "albedo",
+            Aov::Uv => "uv",
+            Aov::ObjectId => "object_id",
+            Aov::Ao => "ao",
+            Aov::Reflection => "reflection",
+            Aov::Refraction => "refraction",
+            Aov::Samples => "samples",
+        }
+    }
+
+    /// Names of the used color components, in r, g, b order
+    pub fn channels(self) -> &'static [&'static str] {
+        match self {
+            Aov::Depth => &["Z"],
+            Aov::Normal => &["X", "Y", "Z"],
+            Aov::Albedo | Aov::Reflection | Aov::Refraction => &["R", "G", "B"],
+            Aov::Uv => &["U", "V"],
+            Aov::ObjectId => &["id"],
+            Aov::Ao => &["Y"],
+            Aov::Samples => &["count"],
+        }
+    }
+
+    /// Value of pixels where the camera ray didn't hit anything
+    pub fn background(self) -> RGBColor {
+        match self {
+            Aov::Depth => RGBColor::new(f64::INFINITY, f64::INFINITY, f64::INFINITY),
+            Aov::Ao => RGBColor::WHITE,
+            _ => RGBColor::BLACK,
+        }
+    }
+
+    /// Averaging depths or ids at the edges of objects produces values that belong to
+    /// neither object, so these AOVs take the sample closest to the pixel center instead.
+    /// The sample count isn't a property of a single sample at all
+    pub fn is_filtered(self) -> bool {
+        !matches!(self, Aov::Depth | Aov::ObjectId | Aov::Samples)
+    }
+
+    /// True for AOVs that contain light, which should be displayed like the final image.
+    /// All other AOVs contain data that is stored linearly.
+    pub fn is_radiance(self) -> bool {
+        matches!(self, Aov::Reflection | Aov::Refraction)
+    }
+
+    /// Maps the values of a whole buffer into the 0-1 range, so they can be stored in
+    /// 8 bit images and actually look like something
+    pub fn visualize(self, values: &[RGBColor]) -> Vec<RGBColor> {
+        match self {
+            Aov::Depth => {
+                // Near is black, far is white
+                let max_depth = values
+                    .iter()
+                    .map(|v| v.r)
+                    .filter(|d| d.is_finite())
+                    .fold(0.0, f64::max);
+
+                values
+                    .iter()
+                    .map(|v| {
+                        let d = if max_depth > 0.0 { (v.r / max_depth).min(1.0) } else { 1.0 };
+                        RGBColor::new(d, d, d)
+                    })
+                    .collect()
+            }
+            Aov::Normal => values.iter().map(|&v| v * 0.5 + RGBColor::WHITE * 0.5).collect(),
+            Aov::Uv => values
+                .iter()
+                .map(|v| RGBColor::new(v.r.rem_euclid(1.0), v.g.rem_euclid(1.0), 0.0))
+                .collect(),
+            Aov::ObjectId => values.iter().map(|v| id_color(v.r as u32)).collect(),
+            Aov::Samples => {
+                // Heatmap from blue (fewest samples) over green to red (most samples)
+                let (min, max) = values
+                    .iter()
+                    .fold((f64::INFINITY, 0.0f64), |(min, max), v| (min.min(v.r), max.max(v.r)));
+
+                values
+                    .iter()
+                    .map(|v| {
+                        let t = if max > min { (v.r - min) / (max - min) } else { 0.0 };
+                        heat_color(t)
+                    })
+                    .collect()
+            }
+            _ => values.to_vec(),
+        }
+    }
+}
+
+impl AovParameters {
+    pub fn enabled(&self) -> Vec<Aov> {
+        Aov::ALL
+            .iter()
+            .copied()
+            .filter(|&aov| match aov {
+                Aov::Depth => self.depth,
+                Aov::Normal => self.normal,
+                Aov::Albedo => self.albedo,
+                Aov::Uv => self.uv,
+                Aov::ObjectId => self.object_id,
+                Aov::Ao => self.ao,
+                Aov::Reflection => self.reflection,
+                Aov::Refraction => self.refraction,
+                Aov::Samples => self.samples,
+            })
+            .collect()
+    }
+}
+
+// Bright, distinct color for every id. The background stays black
+fn id_color(id: u32) -> RGBColor {
+    if id == 0 {
+        return RGBColor::BLACK;
+    }
+
+    // Golden ratio steps around the hue circle keep neighbouring ids apart
+    let hue = (id as f64 * 0.618_033_988_749_895).fract() * 6.0;
+    let x = 1.0 - (hue % 2.0 - 1.0).abs();
+
+    match hue as u32 {
+        0 => RGBColor::new(1.0, x, 0.0),
+        1 => RGBColor::new(x, 1.0, 0.0),
+        2 => RGBColor::new(0.0, 1.0, x),
+        3 => RGBColor::new(0.0, x, 1.0),
+        4 => RGBColor::new(x, 0.0, 1.0),
+        _ => RGBColor::new(1.0, 0.0, x),
+    }
+}
+
+// Blue for 0, green for 0.5 and red for 1
+fn heat_color(t: f64) -> RGBColor {
+    let t = t.clamp(0.0, 1.0);
+
+    if t < 0.5 {
+        RGBColor::new(0.0, 2.0 * t, 1.0 - 2.0 * t)
+    } else {
+        RGBColor::new(2.0 * t - 1.0, 2.0 - 2.0 * t, 0.0)
+    }
+}
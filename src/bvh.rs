@@ -0,0 +1,421 @@
+use crate::raytracing::Ray;
+use crate::vec::*;
+
+/// Maximum number of primitives that end up in a single leaf node
+const MAX_LEAF_SIZE: usize = 4;
+
+/// Axis aligned bounding box
+#[derive(Debug, Copy, Clone)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    pub const EMPTY: Aabb = Aabb {
+        min: Vec3 {
+            x: f64::INFINITY,
+            y: f64::INFINITY,
+            z: f64::INFINITY,
+        },
+        max: Vec3 {
+            x: f64::NEG_INFINITY,
+            y: f64::NEG_INFINITY,
+            z: f64::NEG_INFINITY,
+        },
+    };
+
+    pub fn from_points<I: IntoIterator<Item = Vec3>>(points: I) -> Aabb {
+        points
+            .into_iter()
+            .fold(Aabb::EMPTY, |aabb, p| aabb.union(Aabb { min: p, max: p }))
+    }
+
+    pub fn union(self, other: Aabb) -> Aabb {
+        Aabb {
+            min: Vec3::new(
+                self.min.x.min(other.min.x),
+                self.min.y.min(other.min.y),
+                self.min.z.min(other.min.z),
+            ),
+            max: Vec3::new(
+                self.max.x.max(other.max.x),
+                self.max.y.max(other.max.y),
+                self.max.z.max(other.max.z),
+            ),
+        }
+    }
+
+    /// Grows the box a tiny bit in every direction, so that floating point errors
+    /// in the intersection code of the enclosed geometry can never poke through it
+    pub fn padded(self) -> Aabb {
+        let largest = self
+            .min
+            .x
+            .abs()
+            .max(self.min.y.abs())
+            .max(self.min.z.abs())
+            .max(self.max.x.abs())
+            .max(self.max.y.abs())
+            .max(self.max.z.abs());
+
+        let pad = 1e-6 * (1.0 + largest);
+        let pad = Vec3::new(pad, pad, pad);
+
+        Aabb {
+            min: self.min - pad,
+            max: self.max + pad,
+        }
+    }
+
+    pub fn centroid(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    /// Returns the distance along the ray at which it enters the box (0 if the ray starts inside)
+    fn intersect(&self, ray: &Ray, inv_dir: Vec3) -> Option<f64> {
+        let (mut t_min, mut t_max) = slab(self.min.x, self.max.x, ray.origin.x, inv_dir.x);
+
+        let (ty_min, ty_max) = slab(self.min.y, self.max.y, ray.origin.y, inv_dir.y);
+        t_min = t_min.max(ty_min);
+        t_max = t_max.min(ty_max);
+
+        let (tz_min, tz_max) = slab(self.min.z, self.max.z, ray.origin.z, inv_dir.z);
+        t_min = t_min.max(tz_min).max(0.0);
+        t_max = t_max.min(tz_max);
+
+        if t_min <= t_max {
+            Some(t_min)
+        } else {
+            None
+        }
+    }
+
+    fn axis(v: Vec3, axis: usize) -> f64 {
+        match axis {
+            0 => v.x,
+            1 => v.y,
+            _ => v.z,
+        }
+    }
+}
+
+// Entry and exit distance of a ray for a pair of parallel planes. f64::min and f64::max
+// ignore NaNs, which appear when the ray is parallel to the slab and starts on one of its planes
+fn slab(min: f64, max: f64, origin: f64, inv_dir: f64) -> (f64, f64) {
+    let t0 = (min - origin) * inv_dir;
+    let t1 = (max - origin) * inv_dir;
+
+    (t0.min(t1), t0.max(t1))
+}
+
+enum BvhNode {
+    Inner {
+        bounds: Aabb,
+        left: usize,
+        right: usize,
+    },
+    Leaf {
+        bounds: Aabb,
+        start: usize,
+        count: usize,
+    },
+}
+
+impl BvhNode {
+    fn bounds(&self) -> &Aabb {
+        match self {
+            BvhNode::Inner { bounds, .. } | BvhNode::Leaf { bounds, .. } => bounds,
+        }
+    }
+}
+
+/// Bounding volume hierarchy over a set of primitives that are identified by their index
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    indices: Vec<usize>,
+}
+
+impl Bvh {
+    /// Builds a hierarchy over the supplied bounding boxes. The primitive at index i
+    /// in the slice will be reported as i during traversal.
+    pub fn build(bounds: &[Aabb]) -> Bvh {
+        let mut bvh = Bvh {
+            nodes: Vec::with_capacity(bounds.len() * 2),
+            indices: (0..bounds.len()).collect(),
+        };
+
+        if !bounds.is_empty() {
+            let centroids = bounds.iter().map(Aabb::centroid).collect::<Vec<_>>();
+            bvh.build_recursive(bounds, &centroids, 0, bounds.len());
+        }
+
+        bvh
+    }
+
+    fn build_recursive(
+        &mut self,
+        bounds: &[Aabb],
+        centroids: &[Vec3],
+        start: usize,
+        end: usize,
+    ) -> usize {
+        let node_bounds = self.indices[start..end]
+            .iter()
+            .fold(Aabb::EMPTY, |aabb, &i| aabb.union(bounds[i]));
+
+        let node_index = self.nodes.len();
+
+        if end - start <= MAX_LEAF_SIZE {
+            self.nodes.push(BvhNode::Leaf {
+                bounds: node_bounds,
+                start,
+                count: end - start,
+            });
+
+            return node_index;
+        }
+
+        // Split along the axis where the centroids are spread out the most
+        let centroid_bounds = Aabb::from_points(self.indices[start..end].iter().map(|&i| centroids[i]));
+        let extent = centroid_bounds.max - centroid_bounds.min;
+        let axis = if extent.x >= extent.y && extent.x >= extent.z {
+            0
+        } else if extent.y >= extent.z {
+            1
+        } else {
+            2
+        };
+
+        let mid = start + (end - start) / 2;
+
+        self.indices[start..end].select_nth_unstable_by(mid - start, |&a, &b| {
+            Aabb::axis(centroids[a], axis)
+                .partial_cmp(&Aabb::axis(centroids[b], axis))
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+
+        // Reserve the slot for this node, children are pushed after it
+        self.nodes.push(BvhNode::Leaf {
+            bounds: node_bounds,
+            start,
+            count: 0,
+        });
+
+        let left = self.build_recursive(bounds, centroids, start, mid);
+        let right = self.build_recursive(bounds, centroids, mid, end);
+
+        self.nodes[node_index] = BvhNode::Inner {
+            bounds: node_bounds,
+            left,
+            right,
+        };
+
+        node_index
+    }
+
+    /// Finds the closest hit along the ray. The supplied closure is called with the index
+    /// of every primitive whose bounding box is hit and must return the hit together with
+    /// its squared distance to the ray origin. Hits with equal distance are resolved in
+    /// favour of the lower index, which is exactly what a linear scan with min_by would do.
+    pub fn closest_hit<T, F>(&self, ray: &Ray, mut test: F) -> Option<(usize, T, f64)>
+    where
+        F: FnMut(usize) -> Option<(T, f64)>,
+    {
+        let mut closest: Option<(usize, T, f64)> = None;
+
+        if self.nodes.is_empty() {
+            return closest;
+        }
+
+        let inv_dir = Vec3::new(
+            1.0 / ray.direction.x(),
+            1.0 / ray.direction.y(),
+            1.0 / ray.direction.z(),
+        );
+
+        let mut stack = Vec::with_capacity(64);
+
+        if let Some(t) = self.nodes[0].bounds().intersect(ray, inv_dir) {
+            stack.push((0, t));
+        }
+
+        while let Some((node_index, t_enter)) = stack.pop() {
+            // Skip nodes that can only contain hits further away than what we already have
+            if let Some((_, _, best_sqr_dist)) = closest {
+                if t_enter * t_enter > best_sqr_dist {
+                    continue;
+                }
+            }
+
+            match self.nodes[node_index] {
+                BvhNode::Leaf { start, count, .. } => {
+                    for &index in &self.indices[start..start + count] {
+                        if let Some((hit, sqr_dist)) = test(index) {
+                            let is_closer = match closest {
+                                Some((best_index, _, best_sqr_dist)) => {
+                                    sqr_dist < best_sqr_dist
+                                        || (sqr_dist == best_sqr_dist && index < best_index)
+                                }
+                                None => true,
+                            };
+
+                            if is_closer {
+                                closest = Some((index, hit, sqr_dist));
+                            }
+                        }
+                    }
+                }
+                BvhNode::Inner { left, right, .. } => {
+                    let t_left = self.nodes[left].bounds().intersect(ray, inv_dir);
+                    let t_right = self.nodes[right].bounds().intersect(ray, inv_dir);
+
+                    // Push the closer child last so it gets visited first
+                    match (t_left, t_right) {
+                        (Some(tl), Some(tr)) if tl < tr => {
+                            stack.push((right, tr));
+                            stack.push((left, tl));
+                        }
+                        (Some(tl), Some(tr)) => {
+                            stack.push((left, tl));
+                            stack.push((right, tr));
+                        }
+                        (Some(tl), None) => stack.push((left, tl)),
+                        (None, Some(tr)) => stack.push((right, tr)),
+                        (None, None) => {}
+                    }
+                }
+            }
+        }
+
+        closest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::prelude::*;
+
+    struct Sphere {
+        center: Vec3,
+        radius: f64,
+    }
+
+    impl Sphere {
+        fn bounds(&self) -> Aabb {
+            let extent = Vec3::new(self.radius, self.radius, self.radius);
+
+            Aabb {
+                min: self.center - extent,
+                max: self.center + extent,
+            }
+        }
+
+        // Squared distance from the ray origin to the closest intersection in front of it
+        fn intersect(&self, ray: &Ray) -> Option<f64> {
+            let to_center = self.center - ray.origin;
+            let b = to_center.dot(ray.direction);
+            let c = to_center.sqr_length() - self.radius * self.radius;
+            let discriminant = b * b - c;
+
+            if discriminant < 0.0 {
+                return None;
+            }
+
+            let near = b - discriminant.sqrt();
+            let far = b + discriminant.sqrt();
+            let t = if near > 0.0 { near } else { far };
+
+            if t > 0.0 {
+                Some(t * t)
+            } else {
+                None
+            }
+        }
+    }
+
+    fn random_vec(rng: &mut SmallRng, extent: f64) -> Vec3 {
+        Vec3::new(
+            rng.gen_range(-extent..extent),
+            rng.gen_range(-extent..extent),
+            rng.gen_range(-extent..extent),
+        )
+    }
+
+    fn brute_force(spheres: &[Sphere], ray: &Ray) -> Option<(usize, f64)> {
+        spheres
+            .iter()
+            .enumerate()
+            .flat_map(|(index, sphere)| sphere.intersect(ray).map(|dist| (index, dist)))
+            .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+    }
+
+    #[test]
+    fn matches_brute_force() {
+        let mut rng = SmallRng::seed_from_u64(1);
+
+        let spheres = (0..500)
+            .map(|_| Sphere {
+                center: random_vec(&mut rng, 20.0),
+                radius: rng.gen_range(0.1..2.0),
+            })
+            .collect::<Vec<_>>();
+
+        let bvh = Bvh::build(&spheres.iter().map(Sphere::bounds).collect::<Vec<_>>());
+
+        let mut hits = 0;
+
+        for _ in 0..2000 {
+            let ray = Ray {
+                origin: random_vec(&mut rng, 30.0),
+                direction: random_vec(&mut rng, 1.0).normalized(),
+            };
+
+            let expected = brute_force(&spheres, &ray);
+            let actual = bvh
+                .closest_hit(&ray, |i| spheres[i].intersect(&ray).map(|dist| ((), dist)))
+                .map(|(index, _, dist)| (index, dist));
+
+            assert_eq!(actual, expected);
+
+            hits += actual.is_some() as usize;
+        }
+
+        // Make sure the rays actually test something
+        assert!(hits > 100);
+    }
+
+    #[test]
+    fn equal_distances_prefer_lower_index() {
+        let spheres = (0..10)
+            .map(|_| Sphere {
+                center: Vec3::new(0.0, 0.0, 5.0),
+                radius: 1.0,
+            })
+            .collect::<Vec<_>>();
+
+        let bvh = Bvh::build(&spheres.iter().map(Sphere::bounds).collect::<Vec<_>>());
+
+        let ray = Ray {
+            origin: Vec3::ZERO,
+            direction: Vec3Norm::FORWARD,
+        };
+
+        let hit = bvh.closest_hit(&ray, |i| spheres[i].intersect(&ray).map(|dist| ((), dist)));
+
+        assert_eq!(hit.map(|(index, _, _)| index), Some(0));
+    }
+
+    #[test]
+    fn empty_bvh_has_no_hits() {
+        let bvh = Bvh::build(&[]);
+
+        let ray = Ray {
+            origin: Vec3::ZERO,
+            direction: Vec3Norm::FORWARD,
+        };
+
+        assert!(bvh.closest_hit(&ray, |_| Some(((), 0.0))).is_none());
+    }
+}
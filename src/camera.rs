@@ -1,39 +1,203 @@
+use super::raytracing::Ray;
 use super::vec::*;
+use super::diagnostics::Diagnostics;
 use super::util;
+use super::sampler::{self, Sampler};
+use super::util::generate_optional_variant;
 use serde::Deserialize;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
 
-#[derive(Debug, Copy, Clone, Deserialize)]
+#[derive(Clone, Debug, Deserialize)]
 #[serde(default)]
-#[serde(deny_unknown_fields)] 
+#[serde(deny_unknown_fields)]
 pub struct Camera {
 
     #[serde(default = "default_position")]
     pub position: Vec3,
 
-    pub rotation: Vec3,
+    /// Euler angles in degrees. Must not be combined with target
+    pub rotation: Option<Vec3>,
+
+    /// Point that the camera looks at. Alternative to rotation
+    pub target: Option<Vec3>,
+
+    /// Only used together with target. The camera is tilted so that this direction points upwards in the image
+    #[serde(default = "default_up")]
+    pub up: Vec3,
+
+    /// Only used together with target. Rotation around the viewing direction in degrees
+    pub roll: f64,
+
+    /// Only used together with target. Sets the focus distance to the distance of the target
+    pub focus_on_target: bool,
+
     pub viewport: ViewPort,
     
     #[serde(default = "default_fov")]
-    pub fov_h: f64
+    pub fov_h: f64,
+
+    /// How pixels of the output image are mapped to rays
+    pub projection: Projection,
+
+    /// Diameter of the lens in world units. If set to 0, the camera is a perfect
+    /// pinhole camera and everything is in focus
+    pub aperture: f64,
+
+    /// Alternative way to specify the aperture. If set, the aperture diameter is
+    /// focal_length / f_stop and the aperture setting is ignored
+    pub f_stop: Option<f64>,
+
+    /// In world units. Only used to calculate the aperture from f_stop
+    #[serde(default = "default_focal_length")]
+    pub focal_length: f64,
+
+    /// Distance from the camera at which objects are perfectly sharp
+    #[serde(default = "default_focus_distance")]
+    pub focus_distance: f64,
+
+    /// Shape of the lens opening, which is visible in out of focus highlights (bokeh)
+    pub aperture_shape: ApertureShape
+}
+
+/// Camera where every value is optional, so that it can extend another camera.
+/// Nested tables like viewport are merged value by value
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct CameraOverride {
+    pub position: Option<Vec3>,
+    pub rotation: Option<Vec3>,
+    pub target: Option<Vec3>,
+    pub up: Option<Vec3>,
+    pub roll: Option<f64>,
+    pub focus_on_target: Option<bool>,
+    pub viewport: override_structs::ViewPort,
+    pub fov_h: Option<f64>,
+    pub projection: Option<Projection>,
+    pub aperture: Option<f64>,
+    pub f_stop: Option<f64>,
+    pub focal_length: Option<f64>,
+    pub focus_distance: Option<f64>,
+    pub aperture_shape: ApertureShapeOverride
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Projection {
+
+    /// Rays start at different points of the viewport and are spread evenly by angle
+    #[default]
+    Hybrid,
+
+    /// Pinhole camera where straight lines stay straight. Uses fov_h
+    Perspective,
+
+    /// Parallel rays that start on the viewport
+    Orthographic,
+
+    /// Equidistant fisheye, where fov_h is the field of view across the image width
+    Fisheye,
+
+    /// Full 360 degree panorama in latitude / longitude layout, like environment maps
+    Equirectangular,
+
+    /// Six 90 degree views in a 3x2 layout: +X, -X, +Y in the top row and -Y, +Z, -Z in the bottom row
+    Cubemap
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+#[serde(deny_unknown_fields)] 
+pub enum ApertureShape {
+    #[default]
+    Circle,
+
+    /// Regular polygon, like the opening of a lens with straight aperture blades
+    Polygon {
+        blades: u32,
+
+        /// In degrees
+        #[serde(default)]
+        rotation: f64
+    },
+
+    /// Grayscale image that is stretched over the lens. Brighter pixels let more light through.
+    /// Scene files only contain the path, the parser loads the image
+    #[serde(skip_deserializing)]
+    Image {
+        image: ApertureImage
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ApertureKind {
+    Circle,
+    Polygon,
+    Image
+}
+
+/// Aperture shape where every value is optional. Values that a camera doesn't set are taken
+/// from the shape of the camera that it extends, even if only the type is changed
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct ApertureShapeOverride {
+    #[serde(rename = "type")]
+    pub kind: Option<ApertureKind>,
+    pub blades: Option<u32>,
+    pub rotation: Option<f64>,
+
+    /// Path to a PNG file
+    pub path: Option<String>
+}
+
+/// Aperture mask that is sampled with a probability proportional to its brightness
+#[derive(Clone)]
+pub struct ApertureImage {
+    width: usize,
+    height: usize,
+
+    // Running sum of the pixel brightness, normalized to end with 1
+    cdf: Arc<Vec<f64>>
 }
 
 fn default_position() -> Vec3 {
     Vec3::new(0.0, 0.0, -10.0)
 }
 
+fn default_up() -> Vec3 {
+    Vec3Norm::UP.into()
+}
+
 fn default_fov() -> f64 {
     60.0
 }
 
-#[derive(Debug, Copy, Clone, Deserialize)]
-#[serde(deny_unknown_fields)] 
+fn default_focal_length() -> f64 {
+    0.05
+}
+
+fn default_focus_distance() -> f64 {
+    10.0
+}
+
+generate_optional_variant!(
+#[derive(Copy)]
 pub struct ViewPort {
     pub width: f64,
     pub height: f64
 }
+);
 
 impl ViewPort {
 
+    pub fn new(width: f64, height: f64) -> ViewPort {
+        ViewPort { width, height }
+    }
+
     pub fn aspect(&self) -> f64 {
         self.width / self.height
     }
@@ -56,31 +220,344 @@ impl Camera {
 
         Camera {
             position,
-            rotation,
+            rotation: Some(rotation),
+            viewport,
+            fov_h,
+            ..Camera::default()
+        }
+    }
+
+    /// Camera that looks from position to target
+    pub fn looking_at(position: Vec3, target: Vec3, viewport: ViewPort, fov_h: f64) -> Camera {
+
+        Camera {
+            position,
+            target: Some(target),
             viewport,
-            fov_h
+            fov_h,
+            ..Camera::default()
+        }
+    }
+
+    /// Rotation of the camera in degrees around the x, y and z axis (applied in that order),
+    /// either as specified or derived from the target
+    pub fn orientation(&self) -> Vec3 {
+
+        let target = match self.target {
+            Some(target) => target,
+            None => return self.rotation.unwrap_or(Vec3::ZERO)
+        };
+
+        // Orthonormal camera basis. Validation makes sure that none of these are degenerate
+        let forward = (target - self.position).normalized();
+        let right = self.up.cross(forward).normalized();
+        let up = forward.cross(right).normalized();
+
+        let right = right.rotate_around_axis(forward, self.roll);
+        let up = up.rotate_around_axis(forward, self.roll);
+
+        // The columns of the rotation matrix Rz * Ry * Rx are the rotated x, y and z axis
+        let (r00, r10, r20) = (right.x(), right.y(), right.z());
+        let (r11, r21) = (up.y(), up.z());
+        let (r12, r22) = (forward.y(), forward.z());
+
+        let pitch_y = (-r20).clamp(-1.0, 1.0).asin();
+
+        if pitch_y.cos() > 1e-9 {
+            Vec3::new(
+                r21.atan2(r22).to_degrees(),
+                pitch_y.to_degrees(),
+                r10.atan2(r00).to_degrees()
+            )
+        } else {
+            // Gimbal lock: Looking straight along the y axis of the rotated frame, so all of
+            // the remaining rotation can be expressed around the x axis
+            Vec3::new(
+                (-r12).atan2(r11).to_degrees(),
+                pitch_y.to_degrees(),
+                0.0
+            )
+        }
+    }
+
+    /// Distance from the camera at which objects are perfectly sharp
+    pub fn effective_focus_distance(&self) -> f64 {
+
+        match self.target {
+            Some(target) if self.focus_on_target => (target - self.position).length(),
+            _ => self.focus_distance
+        }
+    }
+
+    /// Aspect ratio of the output image that fits the projection best
+    pub fn aspect(&self) -> f64 {
+
+        match self.projection {
+            Projection::Equirectangular => 2.0,
+            Projection::Cubemap => 1.5,
+            _ => self.viewport.aspect()
+        }
+    }
+
+    /// Prepares the camera for generating rays
+    pub fn ray_generator(&self) -> RayGenerator<'_> {
+        RayGenerator {
+            camera: self,
+            rotation: self.orientation()
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn gen_ray(&self, sampler: &mut Sampler, x: f64, y: f64, width: usize, height: usize, dof_angle: f64, rotation: Vec3) -> Option<Ray> {
+
+        let w = width as f64;
+        let h = height as f64;
+
+        // Normalized image coordinates: -1 to 1 from left to right, y grows upwards with square pixels
+        let nx = 2.0 * x / w - 1.0;
+        let ny = (1.0 - 2.0 * y / h) * h / w;
+
+        let (origin, local_direction) = match self.projection {
+            Projection::Hybrid => return Some(self.gen_hybrid_ray(sampler, x, y, w, h, dof_angle, rotation)),
+            Projection::Perspective => {
+                let half_width = (self.fov_h.to_radians() / 2.0).tan();
+
+                (self.position, Vec3::new(nx * half_width, ny * half_width, 1.0).normalized())
+            },
+            Projection::Orthographic => {
+                let viewport_x = (x / w - 0.5) * self.viewport.width;
+                let viewport_y = (0.5 - y / h) * self.viewport.height;
+
+                (self.position + Vec3::new(viewport_x, viewport_y, 0.0).rotate(rotation), Vec3Norm::FORWARD)
+            },
+            Projection::Fisheye => {
+                let r = (nx * nx + ny * ny).sqrt();
+                let theta = r * self.fov_h.to_radians() / 2.0;
+
+                if r > 1.0 || theta > std::f64::consts::PI {
+                    return None;
+                }
+
+                let phi = ny.atan2(nx);
+
+                (self.position, Vec3::new(theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos()).normalized())
+            },
+            Projection::Equirectangular => {
+                // Same mapping as the environment map
+                let phi = (x / w - 0.5) * 2.0 * std::f64::consts::PI;
+                let theta = y / h * std::f64::consts::PI;
+
+                (self.position, Vec3::new(theta.sin() * phi.sin(), theta.cos(), theta.sin() * phi.cos()).normalized())
+            },
+            Projection::Cubemap => {
+                let face_w = w / 3.0;
+                let face_h = h / 2.0;
+
+                let column = (x / face_w).floor().clamp(0.0, 2.0);
+                let row = (y / face_h).floor().clamp(0.0, 1.0);
+
+                let fx = (x - column * face_w) / face_w * 2.0 - 1.0;
+                let fy = 1.0 - (y - row * face_h) / face_h * 2.0;
+
+                // Forward, right and up vector of every face
+                let (forward, right, up) = match (row as u32, column as u32) {
+                    (0, 0) => (Vec3Norm::RIGHT, Vec3Norm::BACK, Vec3Norm::UP),
+                    (0, 1) => (Vec3Norm::LEFT, Vec3Norm::FORWARD, Vec3Norm::UP),
+                    (0, _) => (Vec3Norm::UP, Vec3Norm::RIGHT, Vec3Norm::BACK),
+                    (_, 0) => (Vec3Norm::DOWN, Vec3Norm::RIGHT, Vec3Norm::FORWARD),
+                    (_, 1) => (Vec3Norm::FORWARD, Vec3Norm::RIGHT, Vec3Norm::UP),
+                    (_, _) => (Vec3Norm::BACK, Vec3Norm::LEFT, Vec3Norm::UP)
+                };
+
+                (self.position, (forward + right * fx + up * fy).normalized())
+            }
+        };
+
+        let mut direction = local_direction;
+
+        // Randomization for DoF, in the same way as for the hybrid projection
+        if dof_angle != 0.0 {
+            direction = sampler::uniform_cone(sampler.next_2d(), dof_angle, direction);
+        }
+
+        let direction = direction.rotate(rotation);
+
+        // The lens only makes sense for projections that have a focal plane
+        let (origin, direction) = match self.projection {
+            Projection::Perspective | Projection::Orthographic => self.apply_lens(sampler, origin, direction, rotation),
+            _ => (origin, direction)
+        };
+
+        Some(Ray { origin, direction })
+    }
+
+    // Rays start on the viewport and their angles grow linearly from pixel to pixel
+    #[allow(clippy::too_many_arguments)]
+    fn gen_hybrid_ray(&self, sampler: &mut Sampler, x: f64, y: f64, w: f64, h: f64, dof_angle: f64, rotation: Vec3) -> Ray {
+
+        // Distances between 2 pixels
+        let x_step = self.viewport.width / w;
+        let x_start = (x_step - self.viewport.width) / 2.0;
+
+        let y_step = -self.viewport.height / h;
+        let y_start = (self.viewport.height - y_step) / 2.0;
+
+        // Angle distances between two pixels
+        let fov_v = self.fov_h / self.viewport.aspect();
+
+        let x_angle_step = self.fov_h / w;
+        let x_angle_start = (x_angle_step - self.fov_h) / 2.0;
+
+        let y_angle_step = fov_v / h;
+        let y_angle_start = (y_angle_step - fov_v) / 2.0;
+
+        // The start values above already point to the pixel centers
+        let x = x - 0.5;
+        let y = y - 0.5;
+
+        let origin = Vec3::new(x_start + x * x_step, y_start + y * y_step, 0.0).rotate(rotation) + self.position;
+
+        let mut direction = Vec3Norm::FORWARD;
+
+        // Randomization for DoF
+        if dof_angle != 0.0 {
+            direction = sampler::uniform_cone(sampler.next_2d(), dof_angle, direction);
+        }
+
+        direction = direction
+            // Fov Influence
+            .rotate_y(x_angle_start + x * x_angle_step)
+            .rotate_x(y_angle_start + y * y_angle_step)
+            // Camera orientation influence
+            .rotate(rotation);
+
+        let (origin, direction) = self.apply_lens(sampler, origin, direction, rotation);
+
+        Ray { origin, direction }
+    }
+
+    /// Diameter of the lens in world units
+    pub fn aperture_diameter(&self) -> f64 {
+
+        match self.f_stop {
+            Some(f_stop) => self.focal_length / f_stop,
+            None => self.aperture
         }
     }
 
-    pub fn validate(&self) -> bool {
+    /// Returns true if only objects at the focus distance are sharp
+    pub fn has_lens(&self) -> bool {
+        self.aperture_diameter() > 0.0
+    }
 
-        let mut success = true;
+    /// Moves the origin of a pinhole camera ray to a random point on the lens and points it
+    /// towards the spot where the original ray intersects the focal plane
+    fn apply_lens(&self, sampler: &mut Sampler, origin: Vec3, direction: Vec3Norm, rotation: Vec3) -> (Vec3, Vec3Norm) {
 
-        if !util::is_in_range_exclusive(self.viewport.width, 0.0, std::f64::INFINITY) {
-            println!("Error: Vieport width must be positive and finite");
-            success = false;
+        if !self.has_lens() {
+            return (origin, direction);
         }
 
-        if !util::is_in_range_exclusive(self.viewport.height, 0.0, std::f64::INFINITY) {
-            println!("Error: Vieport height must be positive and finite");
-            success = false;
+        // The focal plane is parallel to the viewport
+        let forward = Vec3Norm::FORWARD.rotate(rotation);
+        let focus_point = origin + direction * (self.effective_focus_distance() / direction.dot(forward));
+
+        let (lens_x, lens_y) = self.aperture_shape.sample(sampler.next_2d());
+        let radius = self.aperture_diameter() / 2.0;
+
+        let lens_offset = Vec3::new(lens_x * radius, lens_y * radius, 0.0).rotate(rotation);
+        let lens_origin = origin + lens_offset;
+
+        (lens_origin, (focus_point - lens_origin).normalized())
+    }
+
+    pub fn validate(&self, diagnostics: &mut Diagnostics) {
+
+        if !util::is_in_range_exclusive(self.viewport.width, 0.0, f64::INFINITY) {
+            diagnostics.error("Vieport width must be positive and finite");
+        }
+
+        if !util::is_in_range_exclusive(self.viewport.height, 0.0, f64::INFINITY) {
+            diagnostics.error("Vieport height must be positive and finite");
         }
 
-        if !util::is_in_range(self.fov_h, 0.0, 180.0) {
-            println!("Warning: FoV outside of usual range. This can be intentional, but will look pretty weird.");
+        match self.projection {
+            Projection::Perspective => {
+                if !util::is_in_range_exclusive(self.fov_h, 0.0, 180.0) {
+                    diagnostics.error("FoV of a perspective camera must be between 0 and 180 degrees");
+                }
+            },
+            Projection::Fisheye => {
+                if !util::is_in_range_exclusive(self.fov_h, 0.0, 360.0) {
+                    diagnostics.error("FoV of a fisheye camera must be between 0 and 360 degrees");
+                }
+            },
+            Projection::Hybrid | Projection::Orthographic => {
+                if !util::is_in_range(self.fov_h, 0.0, 180.0) {
+                    diagnostics.warning("FoV outside of usual range. This can be intentional, but will look pretty weird.");
+                }
+            },
+            Projection::Equirectangular | Projection::Cubemap => {}
         }
 
-        success
+        if self.has_lens() && matches!(self.projection, Projection::Fisheye | Projection::Equirectangular | Projection::Cubemap) {
+            diagnostics.warning("Fisheye and panoramic projections ignore the camera aperture");
+        }
+
+        if !util::is_in_range(self.aperture, 0.0, f64::INFINITY) {
+            diagnostics.error("Camera aperture must be 0 or positive");
+        }
+
+        if let Some(f_stop) = self.f_stop {
+
+            if !util::is_in_range_exclusive(f_stop, 0.0, f64::INFINITY) {
+                diagnostics.error("Camera f_stop must be positive");
+            }
+
+            if !util::is_in_range_exclusive(self.focal_length, 0.0, f64::INFINITY) {
+                diagnostics.error("Camera focal_length must be positive when f_stop is set");
+            }
+        }
+
+        if !util::is_in_range_exclusive(self.focus_distance, 0.0, f64::INFINITY) {
+            diagnostics.error("Camera focus_distance must be positive");
+        }
+
+        if let Some(target) = self.target {
+
+            let forward = target - self.position;
+
+            if forward.is_zero() {
+                diagnostics.error("Camera target must not be equal to the camera position");
+            } else if self.up.is_zero() || self.up.cross(forward.normalized()).sqr_length() < 1e-12 {
+                diagnostics.error("Camera up vector must not be zero or parallel to the viewing direction");
+            }
+        } else if self.focus_on_target {
+            diagnostics.error("Camera focus_on_target requires a target");
+        }
+
+        if let ApertureShape::Polygon { blades, .. } = self.aperture_shape {
+            if blades < 3 {
+                diagnostics.error("Polygonal apertures need at least 3 blades");
+            }
+        }
+    }
+}
+
+/// Generates the rays of a camera. The orientation is derived from the target only once,
+/// instead of for every single ray
+pub struct RayGenerator<'a> {
+    camera: &'a Camera,
+    rotation: Vec3
+}
+
+impl RayGenerator<'_> {
+
+    /// Generates the camera ray for a point on the output image. x and y are continuous pixel
+    /// coordinates, so the center of the top left pixel is at (0.5, 0.5). Returns None for points
+    /// that are not covered by the projection (like the corners of a fisheye image)
+    pub fn gen_ray(&self, sampler: &mut Sampler, x: f64, y: f64, width: usize, height: usize, dof_angle: f64) -> Option<Ray> {
+        self.camera.gen_ray(sampler, x, y, width, height, dof_angle, self.rotation)
     }
 }
 
@@ -88,9 +565,215 @@ impl Default for Camera {
     fn default() -> Self {
         Camera {
             position: Vec3::new(0.0, 0.0, -10.0),
-            rotation: Vec3::ZERO,
+            rotation: None,
+            target: None,
+            up: default_up(),
+            roll: 0.0,
+            focus_on_target: false,
             viewport: ViewPort::default(),
-            fov_h: 60.0
+            fov_h: 60.0,
+            projection: Projection::Hybrid,
+            aperture: 0.0,
+            f_stop: None,
+            focal_length: default_focal_length(),
+            focus_distance: default_focus_distance(),
+            aperture_shape: ApertureShape::Circle
+        }
+    }
+}
+
+impl Camera {
+
+    pub fn override_with(&mut self, or: &CameraOverride) {
+        self.position = or.position.unwrap_or(self.position);
+        self.rotation = or.rotation.or(self.rotation);
+        self.target = or.target.or(self.target);
+        self.up = or.up.unwrap_or(self.up);
+        self.roll = or.roll.unwrap_or(self.roll);
+        self.focus_on_target = or.focus_on_target.unwrap_or(self.focus_on_target);
+        self.viewport.override_with(&or.viewport);
+        self.fov_h = or.fov_h.unwrap_or(self.fov_h);
+        self.projection = or.projection.unwrap_or(self.projection);
+        self.aperture = or.aperture.unwrap_or(self.aperture);
+        self.f_stop = or.f_stop.or(self.f_stop);
+        self.focal_length = or.focal_length.unwrap_or(self.focal_length);
+        self.focus_distance = or.focus_distance.unwrap_or(self.focus_distance);
+        self.aperture_shape.override_with(&or.aperture_shape);
+    }
+}
+
+impl CameraOverride {
+
+    /// Values that are not set here are taken from the parent. Rotation and target are
+    /// alternatives, so if either of them is set here, neither is taken from the parent
+    pub fn inherit_from(&mut self, parent: &CameraOverride) {
+        if self.rotation.is_none() && self.target.is_none() {
+            self.rotation = parent.rotation;
+            self.target = parent.target;
+        }
+
+        self.position = self.position.or(parent.position);
+        self.up = self.up.or(parent.up);
+        self.roll = self.roll.or(parent.roll);
+        self.focus_on_target = self.focus_on_target.or(parent.focus_on_target);
+        self.viewport.inherit_from(&parent.viewport);
+        self.fov_h = self.fov_h.or(parent.fov_h);
+        self.projection = self.projection.or(parent.projection);
+        self.aperture = self.aperture.or(parent.aperture);
+        self.f_stop = self.f_stop.or(parent.f_stop);
+        self.focal_length = self.focal_length.or(parent.focal_length);
+        self.focus_distance = self.focus_distance.or(parent.focus_distance);
+        self.aperture_shape.inherit_from(&parent.aperture_shape);
+    }
+}
+
+impl ApertureShape {
+
+    /// Maps a point in the unit square to a point on the aperture, so that well distributed
+    /// points stay well distributed. Both coordinates are in the range -1 to 1
+    pub fn sample(&self, [u, v]: [f64; 2]) -> (f64, f64) {
+
+        match self {
+            ApertureShape::Circle => {
+                let r = u.sqrt();
+                let phi = v * 2.0 * std::f64::consts::PI;
+
+                (r * phi.cos(), r * phi.sin())
+            },
+            ApertureShape::Polygon { blades, rotation } => {
+                // Like the circle, but u walks along the edges of the polygon instead of the
+                // circumference. Every triangle between the center and two neighbouring corners
+                // gets the same share of u, which is uniform because they all have the same area
+                let blades = (*blades).max(3) as f64;
+                let segment = (u * blades).floor().min(blades - 1.0);
+                let angle_step = 2.0 * std::f64::consts::PI / blades;
+
+                let start_angle = rotation.to_radians() + segment * angle_step;
+                let end_angle = start_angle + angle_step;
+
+                let t = u * blades - segment;
+                let r = v.sqrt();
+
+                (
+                    r * ((1.0 - t) * start_angle.cos() + t * end_angle.cos()),
+                    r * ((1.0 - t) * start_angle.sin() + t * end_angle.sin())
+                )
+            },
+            ApertureShape::Image { image } => image.sample(u, v)
         }
     }
+}
+
+impl ApertureShape {
+
+    fn override_with(&mut self, or: &ApertureShapeOverride) {
+
+        let (blades, rotation) = match *self {
+            ApertureShape::Polygon { blades, rotation } => (blades, rotation),
+            _ => (0, 0.0)
+        };
+
+        let kind = or.kind.unwrap_or(match self {
+            ApertureShape::Circle => ApertureKind::Circle,
+            ApertureShape::Polygon { .. } => ApertureKind::Polygon,
+            ApertureShape::Image { .. } => ApertureKind::Image
+        });
+
+        // Images are loaded by the parser, which can report problems with the file
+        match kind {
+            ApertureKind::Circle => *self = ApertureShape::Circle,
+            ApertureKind::Polygon => {
+                *self = ApertureShape::Polygon {
+                    blades: or.blades.unwrap_or(blades),
+                    rotation: or.rotation.unwrap_or(rotation)
+                }
+            },
+            ApertureKind::Image => {}
+        }
+    }
+}
+
+impl ApertureShapeOverride {
+
+    /// Values that are not set here are taken from the parent
+    pub fn inherit_from(&mut self, parent: &ApertureShapeOverride) {
+        self.kind = self.kind.or(parent.kind);
+        self.blades = self.blades.or(parent.blades);
+        self.rotation = self.rotation.or(parent.rotation);
+        self.path = self.path.clone().or_else(|| parent.path.clone());
+    }
+
+    /// Only meaningful after everything has been inherited, because the parent can supply
+    /// the values that are missing here
+    pub fn validate(&self, diagnostics: &mut Diagnostics) {
+
+        match self.kind {
+            None if self.blades.is_some() || self.rotation.is_some() || self.path.is_some() => {
+                diagnostics.error("Aperture shape needs a type");
+            },
+            Some(ApertureKind::Image) if self.path.is_none() => {
+                diagnostics.error("Image apertures need a path");
+            },
+            _ => {}
+        }
+    }
+}
+
+impl ApertureImage {
+
+    /// Loads a 24 bit PNG file. Completely black images are rejected, because no light
+    /// would get through them
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<ApertureImage, io::Error> {
+
+        let image = lodepng::decode24_file(path).map_err(io::Error::other)?;
+
+        let mut sum = 0.0;
+
+        let mut cdf = image.buffer
+            .iter()
+            .map(|pix| {
+                sum += pix.r as f64 + pix.g as f64 + pix.b as f64;
+                sum
+            })
+            .collect::<Vec<_>>();
+
+        if sum <= 0.0 {
+            return Err(io::Error::other("The image is completely black"));
+        }
+
+        for value in cdf.iter_mut() {
+            *value /= sum;
+        }
+
+        Ok(ApertureImage {
+            width: image.width,
+            height: image.height,
+            cdf: Arc::new(cdf)
+        })
+    }
+
+    fn sample(&self, u: f64, v: f64) -> (f64, f64) {
+
+        let index = self.cdf.partition_point(|&c| c <= u).min(self.cdf.len() - 1);
+
+        // Where u falls inside of the pixel's part of the cdf picks the position inside of
+        // the pixel, so no extra random numbers are needed
+        let start = if index > 0 { self.cdf[index - 1] } else { 0.0 };
+        let within = ((u - start) / (self.cdf[index] - start)).clamp(0.0, 1.0);
+
+        let x = (index % self.width) as f64 + within;
+        let y = (index / self.width) as f64 + v;
+
+        // Image rows go from top to bottom
+        (
+            x / self.width as f64 * 2.0 - 1.0,
+            1.0 - y / self.height as f64 * 2.0
+        )
+    }
+}
+
+impl std::fmt::Debug for ApertureImage {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "ApertureImage({}x{})", self.width, self.height)
+    }
 }
\ No newline at end of file
@@ -1,3 +1,4 @@
+use crate::diagnostics::Diagnostics;
 use crate::util;
 
 // TODO: Support other color formats?
@@ -27,20 +28,23 @@ impl RGBColor {
         RGBColor { r, g, b }
     }
 
-    pub fn validate(&self) -> bool {
+    pub fn validate(&self, diagnostics: &mut Diagnostics) {
 
         if  !util::is_in_range(self.r, 0.0, 1.0) ||
             !util::is_in_range(self.g, 0.0, 1.0) ||
             !util::is_in_range(self.b, 0.0, 1.0) {
-            println!("Warning: Color contains one or more components outside of usual range 0-1. This can be deliberate, but might look weird.");
+            diagnostics.warning("Color contains one or more components outside of usual range 0-1. This can be deliberate, but might look weird.");
         }
-        
-        true
     }
 
     pub fn lerp_towards(self, other: Self, t: f64) -> Self {
         self * (1.0 - t) + other * t
     }
+
+    /// Perceived brightness with the Rec. 709 weights
+    pub fn luminance(self) -> f64 {
+        0.2126 * self.r + 0.7152 * self.g + 0.0722 * self.b
+    }
 }
 
 impl From<RGBColor> for [u8;3] {
@@ -101,6 +105,20 @@ impl std::ops::Add for RGBColor {
 
 }
 
+impl std::ops::Sub for RGBColor {
+
+    type Output = RGBColor;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        RGBColor {
+            r: self.r - rhs.r,
+            g: self.g - rhs.g,
+            b: self.b - rhs.b
+        }
+    }
+
+}
+
 impl std::ops::AddAssign for RGBColor {
 
     fn add_assign(&mut self, other: RGBColor) {
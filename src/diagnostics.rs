@@ -0,0 +1,282 @@
+// Problems with scene files and render settings. Turning them into messages for humans
+// is up to the user of the library
+
+use std::fmt;
+use std::io;
+use std::ops::Range;
+use std::path::PathBuf;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Severity {
+    /// The scene can't be rendered like this
+    Error,
+
+    /// The scene can be rendered, but probably doesn't look like intended
+    Warning,
+}
+
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+
+    /// Byte range in the scene file that the problem belongs to, if it is known
+    pub span: Option<Range<usize>>,
+
+    /// Index of the source that the span points into. 0 is the scene file itself,
+    /// files that it includes follow in the order they were loaded
+    pub file: usize,
+}
+
+/// Collects all problems that are found while validating something, so they can be
+/// reported at once instead of stopping at the first one
+#[derive(Clone, Debug, Default)]
+pub struct Diagnostics {
+    list: Vec<Diagnostic>,
+
+    // Source that everything which is reported right now belongs to
+    file: usize,
+}
+
+impl Diagnostics {
+    pub fn new() -> Diagnostics {
+        Diagnostics::default()
+    }
+
+    pub fn error<S: Into<String>>(&mut self, message: S) {
+        self.push(Severity::Error, message.into(), None);
+    }
+
+    pub fn warning<S: Into<String>>(&mut self, message: S) {
+        self.push(Severity::Warning, message.into(), None);
+    }
+
+    pub fn error_at<S: Into<String>>(&mut self, span: Range<usize>, message: S) {
+        self.push(Severity::Error, message.into(), Some(span));
+    }
+
+    pub fn warning_at<S: Into<String>>(&mut self, span: Range<usize>, message: S) {
+        self.push(Severity::Warning, message.into(), Some(span));
+    }
+
+    /// Everything that is reported inside of f without a span of its own points at the given
+    /// span. This way, validation code doesn't need to know where the values came from
+    pub fn in_span<F, R>(&mut self, span: Range<usize>, f: F) -> R
+    where
+        F: FnOnce(&mut Diagnostics) -> R,
+    {
+        let first = self.list.len();
+        let result = f(self);
+
+        for diagnostic in &mut self.list[first..] {
+            if diagnostic.span.is_none() {
+                diagnostic.span = Some(span.clone());
+            }
+        }
+
+        result
+    }
+
+    /// Everything that is reported inside of f belongs to the source with the given index
+    pub fn in_file<F, R>(&mut self, file: usize, f: F) -> R
+    where
+        F: FnOnce(&mut Diagnostics) -> R,
+    {
+        let previous = std::mem::replace(&mut self.file, file);
+        let result = f(self);
+        self.file = previous;
+
+        result
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.list.iter().any(|d| d.severity == Severity::Error)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Diagnostic> {
+        self.list.iter()
+    }
+
+    pub fn into_vec(self) -> Vec<Diagnostic> {
+        self.list
+    }
+
+    fn push(&mut self, severity: Severity, message: String, span: Option<Range<usize>>) {
+        self.list.push(Diagnostic {
+            severity,
+            message,
+            span,
+            file: self.file,
+        });
+    }
+}
+
+/// Content of a scene file, which is needed to show the lines that diagnostics point at
+#[derive(Clone, Debug)]
+pub struct Source {
+    pub path: PathBuf,
+    pub content: String,
+}
+
+/// Everything that can go wrong while loading a scene
+#[derive(Debug)]
+pub enum RaysError {
+    /// The scene file could not be read
+    Io { path: PathBuf, error: io::Error },
+
+    /// The scene file or the files it includes contain one or more errors. All problems
+    /// that were found are listed, including the warnings
+    Scene {
+        /// The scene file first, followed by all files that could be included
+        sources: Vec<Source>,
+        diagnostics: Vec<Diagnostic>,
+    },
+}
+
+impl fmt::Display for RaysError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RaysError::Io { path, error } => {
+                write!(f, "Could not read {}: {}", path.display(), error)
+            }
+            RaysError::Scene {
+                sources,
+                diagnostics,
+            } => write!(
+                f,
+                "{} contains {}",
+                sources[0].path.display(),
+                count_errors(diagnostics)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RaysError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RaysError::Io { error, .. } => Some(error),
+            RaysError::Scene { .. } => None,
+        }
+    }
+}
+
+/// Reasons why a scene can't be rendered
+#[derive(Debug)]
+pub enum RenderError {
+    /// The render params, camera, scene or post effects contain errors. All problems
+    /// that were found are listed, including the warnings
+    Invalid(Vec<Diagnostic>),
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RenderError::Invalid(diagnostics) => {
+                write!(f, "Render settings contain {}", count_errors(diagnostics))
+            }
+        }
+    }
+}
+
+impl std::error::Error for RenderError {}
+
+impl RenderError {
+    /// Error for images without pixels or with a buffer that doesn't match their size
+    pub(crate) fn check_image_size(
+        kind: &str,
+        width: usize,
+        height: usize,
+        pixel_count: usize,
+    ) -> Result<(), RenderError> {
+        let message = if width == 0 || height == 0 {
+            format!("{} must be at least 1x1 pixels, not {}x{}", kind, width, height)
+        } else if pixel_count != width * height {
+            format!("{} is {}x{} pixels, but has {} colors", kind, width, height, pixel_count)
+        } else {
+            return Ok(());
+        };
+
+        let mut diagnostics = Diagnostics::new();
+        diagnostics.error(message);
+
+        Err(RenderError::Invalid(diagnostics.into_vec()))
+    }
+}
+
+// Problems with images that were just loaded are reported like problems with reading the file
+impl From<RenderError> for io::Error {
+    fn from(error: RenderError) -> io::Error {
+        let RenderError::Invalid(diagnostics) = error;
+
+        let messages = diagnostics
+            .iter()
+            .filter(|d| d.severity == Severity::Error)
+            .map(|d| d.message.as_str())
+            .collect::<Vec<_>>();
+
+        io::Error::new(io::ErrorKind::InvalidData, messages.join(", "))
+    }
+}
+
+// "1 error", "3 errors"
+fn count_errors(diagnostics: &[Diagnostic]) -> String {
+    let errors = diagnostics
+        .iter()
+        .filter(|d| d.severity == Severity::Error)
+        .count();
+
+    format!("{} error{}", errors, if errors == 1 { "" } else { "s" })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scopes_fill_in_spans_and_files() {
+        let mut diagnostics = Diagnostics::new();
+
+        diagnostics.error("outside");
+        diagnostics.in_file(2, |d| {
+            d.in_span(10..20, |d| {
+                d.warning("inherits the span");
+                d.error_at(12..14, "keeps its own span");
+            });
+            d.error("no span");
+        });
+
+        let list = diagnostics.into_vec();
+        let summary = list
+            .iter()
+            .map(|d| (d.message.as_str(), d.severity, d.span.clone(), d.file))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            summary,
+            vec![
+                ("outside", Severity::Error, None, 0),
+                ("inherits the span", Severity::Warning, Some(10..20), 2),
+                ("keeps its own span", Severity::Error, Some(12..14), 2),
+                ("no span", Severity::Error, None, 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn warnings_are_not_errors() {
+        let mut diagnostics = Diagnostics::new();
+        assert!(diagnostics.is_empty());
+
+        diagnostics.warning("just a warning");
+        assert!(!diagnostics.has_errors());
+
+        diagnostics.error("an error");
+        assert!(diagnostics.has_errors());
+        assert_eq!(count_errors(&diagnostics.into_vec()), "1 error");
+    }
+}
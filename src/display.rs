@@ -0,0 +1,375 @@
+// Display transform that turns linear render output into 8 bit colors
+
+use crate::color::RGBColor;
+use crate::render_params::DisplayParameters;
+use rand::prelude::*;
+use serde::Deserialize;
+use std::sync::OnceLock;
+
+/// Operator that compresses overbright colors into the displayable 0-1 range
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ToneMap {
+    /// Values above 1 are simply clipped
+    #[default]
+    None,
+
+    /// x / (1 + x). Never clips, but flattens highlights
+    Reinhard,
+
+    /// Hable's Uncharted 2 curve with a slight toe in the shadows
+    Filmic,
+
+    /// Narkowicz' fit of the ACES reference rendering transform. Punchy contrast
+    Aces,
+}
+
+/// Noise that is added before colors are rounded to 8 bits. This hides banding in
+/// smooth gradients at the cost of a very fine pattern.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Dither {
+    #[default]
+    None,
+
+    /// 8x8 Bayer matrix. Cheap, but the pattern is clearly visible when zoomed in
+    Ordered,
+
+    /// Tiled 64x64 blue noise texture, which is much less obvious to the eye
+    BlueNoise,
+}
+
+impl ToneMap {
+    pub fn apply(self, x: f64) -> f64 {
+        match self {
+            ToneMap::None => x,
+            ToneMap::Reinhard => x / (1.0 + x),
+            ToneMap::Filmic => {
+                const EXPOSURE_BIAS: f64 = 2.0;
+                const WHITE_POINT: f64 = 11.2;
+
+                fn curve(x: f64) -> f64 {
+                    const A: f64 = 0.15;
+                    const B: f64 = 0.50;
+                    const C: f64 = 0.10;
+                    const D: f64 = 0.20;
+                    const E: f64 = 0.02;
+                    const F: f64 = 0.30;
+
+                    ((x * (A * x + C * B) + D * E) / (x * (A * x + B) + D * F)) - E / F
+                }
+
+                curve(x * EXPOSURE_BIAS) / curve(WHITE_POINT)
+            }
+            ToneMap::Aces => {
+                const A: f64 = 2.51;
+                const B: f64 = 0.03;
+                const C: f64 = 2.43;
+                const D: f64 = 0.59;
+                const E: f64 = 0.14;
+
+                (x * (A * x + B)) / (x * (C * x + D) + E)
+            }
+        }
+    }
+}
+
+impl Dither {
+    /// Offset in the range 0-1 that is added before rounding down. Without dithering
+    /// values are truncated, which keeps the output identical to older versions
+    fn threshold(self, x: usize, y: usize) -> f64 {
+        match self {
+            Dither::None => 0.0,
+            Dither::Ordered => {
+                const BAYER_2: [[usize; 2]; 2] = [[0, 2], [3, 1]];
+
+                // Builds the 8x8 matrix recursively out of the 2x2 one
+                let mut value = 0;
+
+                for level in 0..3 {
+                    let (bx, by) = ((x >> level) & 1, (y >> level) & 1);
+                    value += BAYER_2[by][bx] << (2 * (2 - level));
+                }
+
+                (value as f64 + 0.5) / 64.0
+            }
+            Dither::BlueNoise => blue_noise(x, y),
+        }
+    }
+}
+
+impl DisplayParameters {
+    /// Applies exposure, tone mapping and the transfer function, then quantizes the
+    /// result to 8 bits. The pixel position is only needed for dithering.
+    pub fn to_bytes(&self, color: RGBColor, x: usize, y: usize) -> [u8; 3] {
+        let scale = 2f64.powf(self.exposure);
+        let threshold = self.dither.threshold(x, y);
+
+        let encode = |value: f64| {
+            let mut value = self.tone_map.apply((value * scale).max(0.0)).min(1.0);
+
+            if self.srgb {
+                value = srgb_oetf(value);
+            }
+
+            // Casting saturates, so values that dither outside of the range are fine
+            (value * 255.0 + threshold).floor() as u8
+        };
+
+        [encode(color.r), encode(color.g), encode(color.b)]
+    }
+}
+
+fn srgb_oetf(x: f64) -> f64 {
+    if x <= 0.003_130_8 {
+        x * 12.92
+    } else {
+        1.055 * x.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+const BLUE_NOISE_SIZE: usize = 64;
+
+/// Value of the tiled blue noise texture in the range 0-1. Neighbouring pixels have very
+/// different values, and every value occurs equally often
+pub fn blue_noise(x: usize, y: usize) -> f64 {
+    let texture = blue_noise_texture();
+
+    texture[(y % BLUE_NOISE_SIZE) * BLUE_NOISE_SIZE + x % BLUE_NOISE_SIZE]
+}
+
+// Generated once with the void-and-cluster method (Ulichney 1993). Every value in
+// the range 0-1 occurs exactly once.
+fn blue_noise_texture() -> &'static [f64] {
+    static TEXTURE: OnceLock<Vec<f64>> = OnceLock::new();
+
+    TEXTURE.get_or_init(|| {
+        const N: usize = BLUE_NOISE_SIZE * BLUE_NOISE_SIZE;
+        const SIGMA: f64 = 1.5;
+
+        // Gaussian energy that a set pixel spreads to its surroundings, wrapping around the edges
+        let kernel = (0..N)
+            .map(|i| {
+                let wrap = |d: usize| d.min(BLUE_NOISE_SIZE - d) as f64;
+                let (dx, dy) = (wrap(i % BLUE_NOISE_SIZE), wrap(i / BLUE_NOISE_SIZE));
+
+                (-(dx * dx + dy * dy) / (2.0 * SIGMA * SIGMA)).exp()
+            })
+            .collect::<Vec<_>>();
+
+        let toggle = |pattern: &mut [bool], energy: &mut [f64], pixel: usize| {
+            pattern[pixel] = !pattern[pixel];
+            let sign = if pattern[pixel] { 1.0 } else { -1.0 };
+            let (px, py) = (pixel % BLUE_NOISE_SIZE, pixel / BLUE_NOISE_SIZE);
+
+            for (i, e) in energy.iter_mut().enumerate() {
+                let dx = (i % BLUE_NOISE_SIZE + BLUE_NOISE_SIZE - px) % BLUE_NOISE_SIZE;
+                let dy = (i / BLUE_NOISE_SIZE + BLUE_NOISE_SIZE - py) % BLUE_NOISE_SIZE;
+                *e += sign * kernel[dy * BLUE_NOISE_SIZE + dx];
+            }
+        };
+
+        // Set pixel with the highest energy, or the empty pixel with the lowest energy
+        let tightest_cluster = |pattern: &[bool], energy: &[f64]| {
+            (0..N)
+                .filter(|&i| pattern[i])
+                .max_by(|&a, &b| energy[a].total_cmp(&energy[b]))
+                .unwrap()
+        };
+
+        let largest_void = |pattern: &[bool], energy: &[f64]| {
+            (0..N)
+                .filter(|&i| !pattern[i])
+                .min_by(|&a, &b| energy[a].total_cmp(&energy[b]))
+                .unwrap()
+        };
+
+        // Random initial pattern, which is then evened out by moving points from
+        // clusters into voids until that doesn't change anything anymore
+        let mut rng = SmallRng::seed_from_u64(0);
+        let mut pattern = vec![false; N];
+        let mut energy = vec![0.0; N];
+
+        let initial_count = N / 10;
+
+        while pattern.iter().filter(|&&p| p).count() < initial_count {
+            let pixel = rng.gen_range(0..N);
+
+            if !pattern[pixel] {
+                toggle(&mut pattern, &mut energy, pixel);
+            }
+        }
+
+        loop {
+            let cluster = tightest_cluster(&pattern, &energy);
+            toggle(&mut pattern, &mut energy, cluster);
+
+            let void = largest_void(&pattern, &energy);
+
+            if void == cluster {
+                toggle(&mut pattern, &mut energy, void);
+                break;
+            }
+
+            toggle(&mut pattern, &mut energy, void);
+        }
+
+        let mut ranks = vec![0; N];
+
+        // Ranks below the initial pattern: Remove points from clusters
+        {
+            let mut pattern = pattern.clone();
+            let mut energy = energy.clone();
+
+            for rank in (0..initial_count).rev() {
+                let cluster = tightest_cluster(&pattern, &energy);
+                toggle(&mut pattern, &mut energy, cluster);
+                ranks[cluster] = rank;
+            }
+        }
+
+        // Ranks above the initial pattern: Fill up voids
+        for rank in initial_count..N {
+            let void = largest_void(&pattern, &energy);
+            toggle(&mut pattern, &mut energy, void);
+            ranks[void] = rank;
+        }
+
+        ranks
+            .into_iter()
+            .map(|rank| (rank as f64 + 0.5) / N as f64)
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-6, "{} != {}", a, b);
+    }
+
+    fn gray(value: f64) -> RGBColor {
+        RGBColor::new(value, value, value)
+    }
+
+    fn params(exposure: f64, tone_map: ToneMap, srgb: bool, dither: Dither) -> DisplayParameters {
+        DisplayParameters { exposure, tone_map, srgb, dither }
+    }
+
+    #[test]
+    fn tone_maps_start_at_black_and_rise() {
+        for &tone_map in &[ToneMap::None, ToneMap::Reinhard, ToneMap::Filmic, ToneMap::Aces] {
+            assert_close(tone_map.apply(0.0), 0.0);
+
+            let values = (0..200).map(|i| tone_map.apply(i as f64 * 0.1)).collect::<Vec<_>>();
+            assert!(values.windows(2).all(|w| w[1] > w[0]), "{:?} isn't increasing", tone_map);
+        }
+    }
+
+    #[test]
+    fn tone_map_reference_values() {
+        assert_close(ToneMap::None.apply(3.0), 3.0);
+
+        assert_close(ToneMap::Reinhard.apply(1.0), 0.5);
+        assert_close(ToneMap::Reinhard.apply(3.0), 0.75);
+        assert!(ToneMap::Reinhard.apply(1000.0) < 1.0);
+
+        // The exposure bias maps half the white point to 1
+        assert_close(ToneMap::Filmic.apply(5.6), 1.0);
+        assert!(ToneMap::Filmic.apply(0.01) < 0.01, "no toe");
+
+        assert_close(ToneMap::Aces.apply(1.0), 2.54 / 3.16);
+        // Saturates slightly above 1, the display transform clips the rest
+        assert_close(ToneMap::Aces.apply(1e9), 2.51 / 2.43);
+    }
+
+    #[test]
+    fn srgb_oetf_endpoints_and_linear_segment() {
+        assert_close(srgb_oetf(0.0), 0.0);
+        assert_close(srgb_oetf(1.0), 1.0);
+        assert_close(srgb_oetf(0.002), 0.002 * 12.92);
+
+        // Both segments meet at the threshold
+        let threshold = 0.003_130_8;
+        assert!((srgb_oetf(threshold) - srgb_oetf(threshold + 1e-9)).abs() < 1e-6);
+
+        assert_eq!(params(0.0, ToneMap::None, true, Dither::None).to_bytes(gray(0.5), 0, 0), [187; 3]);
+    }
+
+    #[test]
+    fn exposure_is_in_stops() {
+        let bytes = |exposure: f64, value: f64| {
+            params(exposure, ToneMap::None, false, Dither::None).to_bytes(gray(value), 0, 0)[0]
+        };
+
+        assert_eq!(bytes(0.0, 0.5), 127);
+        assert_eq!(bytes(1.0, 0.25), 127);
+        assert_eq!(bytes(3.0, 0.0625), 127);
+        assert_eq!(bytes(-2.0, 2.0), 127);
+    }
+
+    #[test]
+    fn undithered_output_is_truncated_and_clipped() {
+        let display = DisplayParameters::default();
+
+        assert_eq!(display.to_bytes(RGBColor::new(0.999, 1.0, 5.0), 0, 0), [254, 255, 255]);
+        assert_eq!(display.to_bytes(RGBColor::new(-1.0, 0.0, 1.0 / 255.0 - 1e-6), 0, 0), [0, 0, 0]);
+    }
+
+    #[test]
+    fn bayer_thresholds_cover_every_level_once() {
+        let mut thresholds = (0..64)
+            .map(|i| Dither::Ordered.threshold(i % 8, i / 8))
+            .collect::<Vec<_>>();
+
+        for (i, &threshold) in thresholds.iter().enumerate() {
+            assert_close(Dither::Ordered.threshold(i % 8 + 8, i / 8 + 16), threshold);
+        }
+
+        thresholds.sort_by(f64::total_cmp);
+
+        for (i, threshold) in thresholds.into_iter().enumerate() {
+            assert_close(threshold, (i as f64 + 0.5) / 64.0);
+        }
+
+        // Neighbours are far apart in the matrix
+        assert_close((Dither::Ordered.threshold(0, 0) - Dither::Ordered.threshold(1, 0)).abs(), 0.5);
+    }
+
+    #[test]
+    fn blue_noise_covers_every_level_once() {
+        let mut values = (0..BLUE_NOISE_SIZE * BLUE_NOISE_SIZE)
+            .map(|i| blue_noise(i % BLUE_NOISE_SIZE, i / BLUE_NOISE_SIZE))
+            .collect::<Vec<_>>();
+
+        assert_close(blue_noise(3, 5), blue_noise(3 + BLUE_NOISE_SIZE, 5 + 2 * BLUE_NOISE_SIZE));
+
+        values.sort_by(f64::total_cmp);
+        let n = values.len() as f64;
+
+        for (i, value) in values.into_iter().enumerate() {
+            assert_close(value, (i as f64 + 0.5) / n);
+        }
+    }
+
+    #[test]
+    fn dithering_preserves_the_average() {
+        // A level between two 8 bit values becomes a mix of both
+        let value = 100.3 / 255.0;
+
+        for &(dither, size) in &[(Dither::Ordered, 8), (Dither::BlueNoise, BLUE_NOISE_SIZE)] {
+            let display = params(0.0, ToneMap::None, false, dither);
+
+            let bytes = (0..size * size)
+                .map(|i| display.to_bytes(gray(value), i % size, i / size)[0])
+                .collect::<Vec<_>>();
+
+            assert!(bytes.iter().all(|&b| b == 100 || b == 101));
+
+            let mean = bytes.iter().map(|&b| b as f64).sum::<f64>() / (size * size) as f64;
+            assert!((mean - 100.3).abs() < 1.0 / 64.0, "{:?}: {}", dither, mean);
+        }
+    }
+}
@@ -0,0 +1,268 @@
+use crate::color::RGBColor;
+use crate::diagnostics::{Diagnostics, RenderError};
+use crate::hdr;
+use crate::parser::const_f64_one;
+use crate::util;
+use crate::vec::*;
+use serde::Deserialize;
+use std::f64::consts::PI;
+use std::io;
+use std::path::Path;
+
+/// Equirectangular image that surrounds the whole scene. It is shown wherever
+/// a ray doesn't hit anything and lights the scene like a huge area light.
+pub struct Environment {
+    width: usize,
+    height: usize,
+    pixels: Vec<RGBColor>,
+
+    rotation: Vec3,
+    intensity: f64,
+
+    // Importance sampling tables. The marginal cdf picks a row, the conditional cdf
+    // of that row picks a column. Both start with 0 and end with 1.
+    marginal_cdf: Vec<f64>,
+    conditional_cdfs: Vec<f64>,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct EnvironmentInit {
+    /// Path to a Radiance HDR (.hdr) or 24bit PNG file
+    pub path: String,
+
+    pub rotation: Vec3,
+
+    #[serde(default = "const_f64_one")]
+    pub intensity: f64,
+}
+
+pub struct EnvironmentSample {
+    pub direction: Vec3Norm,
+    pub radiance: RGBColor,
+
+    /// Probability density with respect to solid angle
+    pub pdf: f64,
+}
+
+impl EnvironmentInit {
+    pub fn new<S: Into<String>>(path: S) -> EnvironmentInit {
+        EnvironmentInit {
+            path: path.into(),
+            ..EnvironmentInit::default()
+        }
+    }
+
+    pub fn with_rotation(self, rotation: Vec3) -> EnvironmentInit {
+        EnvironmentInit { rotation, ..self }
+    }
+
+    pub fn with_intensity(self, intensity: f64) -> EnvironmentInit {
+        EnvironmentInit { intensity, ..self }
+    }
+}
+
+// Same defaults as in scene files
+impl Default for EnvironmentInit {
+    fn default() -> Self {
+        EnvironmentInit {
+            path: String::new(),
+            rotation: Vec3::ZERO,
+            intensity: 1.0,
+        }
+    }
+}
+
+impl Environment {
+    pub fn load(init: &EnvironmentInit) -> Result<Environment, io::Error> {
+        let is_hdr = Path::new(&init.path)
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("hdr"));
+
+        let (width, height, pixels) = if is_hdr {
+            let image = hdr::read_hdr(&init.path)?;
+            (image.width, image.height, image.pixels)
+        } else {
+            let image = lodepng::decode24_file(&init.path).map_err(io::Error::other)?;
+            let pixels = image
+                .buffer
+                .iter()
+                .map(|pix| {
+                    RGBColor::new(
+                        pix.r as f64 / 255.0,
+                        pix.g as f64 / 255.0,
+                        pix.b as f64 / 255.0,
+                    )
+                })
+                .collect();
+
+            (image.width, image.height, pixels)
+        };
+
+        Ok(Environment::new(width, height, pixels, init.rotation, init.intensity)?)
+    }
+
+    pub fn new(
+        width: usize,
+        height: usize,
+        pixels: Vec<RGBColor>,
+        rotation: Vec3,
+        intensity: f64,
+    ) -> Result<Environment, RenderError> {
+        RenderError::check_image_size("Environment", width, height, pixels.len())?;
+
+        let mut env = Environment {
+            width,
+            height,
+            pixels,
+            rotation,
+            intensity,
+            marginal_cdf: Vec::with_capacity(height + 1),
+            conditional_cdfs: Vec::with_capacity(height * (width + 1)),
+        };
+
+        env.build_sampling_tables();
+
+        Ok(env)
+    }
+
+    fn build_sampling_tables(&mut self) {
+        let mut row_weights = Vec::with_capacity(self.height);
+
+        for y in 0..self.height {
+            // Rows close to the poles cover less solid angle
+            let sin_theta = ((y as f64 + 0.5) / self.height as f64 * PI).sin();
+
+            let start = self.conditional_cdfs.len();
+            let mut sum = 0.0;
+            self.conditional_cdfs.push(0.0);
+
+            for x in 0..self.width {
+                sum += self.pixels[x + y * self.width].luminance() * sin_theta;
+                self.conditional_cdfs.push(sum);
+            }
+
+            normalize_cdf(&mut self.conditional_cdfs[start..]);
+            row_weights.push(sum);
+        }
+
+        let mut sum = 0.0;
+        self.marginal_cdf.push(0.0);
+
+        for weight in row_weights {
+            sum += weight;
+            self.marginal_cdf.push(sum);
+        }
+
+        normalize_cdf(&mut self.marginal_cdf);
+    }
+
+    /// Radiance arriving from the supplied direction
+    pub fn radiance(&self, direction: Vec3Norm) -> RGBColor {
+        // Transform into the local space of the environment
+        let local = direction
+            .rotate_z(-self.rotation.z)
+            .rotate_y(-self.rotation.y)
+            .rotate_x(-self.rotation.x);
+
+        let u = 0.5 + local.x().atan2(local.z()) / (2.0 * PI);
+        let v = local.y().clamp(-1.0, 1.0).acos() / PI;
+
+        self.lookup_bilinear(u, v) * self.intensity
+    }
+
+    /// Picks a direction with a probability proportional to the brightness of the environment
+    pub fn sample(&self, u1: f64, u2: f64) -> EnvironmentSample {
+        let (y, v_offset) = sample_cdf(&self.marginal_cdf, u1);
+
+        let row = &self.conditional_cdfs[y * (self.width + 1)..(y + 1) * (self.width + 1)];
+        let (x, u_offset) = sample_cdf(row, u2);
+
+        let u = (x as f64 + u_offset) / self.width as f64;
+        let v = (y as f64 + v_offset) / self.height as f64;
+
+        let theta = v * PI;
+        let phi = (u - 0.5) * 2.0 * PI;
+
+        let local = Vec3Norm::new(theta.sin() * phi.sin(), theta.cos(), theta.sin() * phi.cos());
+
+        let direction = local.rotate(self.rotation);
+
+        // Probability of the picked pixel, converted from image area to solid angle
+        let pixel_probability = (self.marginal_cdf[y + 1] - self.marginal_cdf[y]) * (row[x + 1] - row[x]);
+        let sin_theta = theta.sin();
+
+        let pdf = if sin_theta > 0.0 {
+            pixel_probability * (self.width * self.height) as f64 / (2.0 * PI * PI * sin_theta)
+        } else {
+            0.0
+        };
+
+        EnvironmentSample {
+            direction,
+            radiance: self.pixels[x + y * self.width] * self.intensity,
+            pdf,
+        }
+    }
+
+    // Horizontal lookups wrap around, vertical ones are clamped at the poles
+    fn lookup_bilinear(&self, u: f64, v: f64) -> RGBColor {
+        let x = u * self.width as f64 - 0.5;
+        let y = (v * self.height as f64 - 0.5).clamp(0.0, (self.height - 1) as f64);
+
+        let x0 = x.floor();
+        let y0 = y.floor();
+        let tx = x - x0;
+        let ty = y - y0;
+
+        let x0 = (x0 as isize).rem_euclid(self.width as isize) as usize;
+        let x1 = (x0 + 1) % self.width;
+        let y0 = y0 as usize;
+        let y1 = (y0 + 1).min(self.height - 1);
+
+        let pixel = |x: usize, y: usize| self.pixels[x + y * self.width];
+
+        let top = pixel(x0, y0).lerp_towards(pixel(x1, y0), tx);
+        let bottom = pixel(x0, y1).lerp_towards(pixel(x1, y1), tx);
+
+        top.lerp_towards(bottom, ty)
+    }
+
+    pub fn validate(&self, diagnostics: &mut Diagnostics) {
+        if !util::is_in_range(self.intensity, 0.0, f64::INFINITY) {
+            diagnostics.error("Environment intensity must be 0 or positive");
+        }
+    }
+}
+
+// Turns a running sum into a cdf. Completely black rows become uniform.
+fn normalize_cdf(cdf: &mut [f64]) {
+    let total = cdf[cdf.len() - 1];
+    let n = (cdf.len() - 1) as f64;
+
+    for (i, value) in cdf.iter_mut().enumerate() {
+        *value = if total > 0.0 { *value / total } else { i as f64 / n };
+    }
+}
+
+// Returns the index of the bucket that contains u and the relative position of u inside of it
+fn sample_cdf(cdf: &[f64], u: f64) -> (usize, f64) {
+    let buckets = cdf.len() - 1;
+
+    // First entry that is larger than u, minus one
+    let index = cdf
+        .partition_point(|&c| c <= u)
+        .saturating_sub(1)
+        .min(buckets - 1);
+
+    // Guard against empty buckets, which can only be selected due to rounding
+    let width = cdf[index + 1] - cdf[index];
+    let offset = if width > 0.0 {
+        ((u - cdf[index]) / width).clamp(0.0, 1.0)
+    } else {
+        0.5
+    };
+
+    (index, offset)
+}
@@ -0,0 +1,418 @@
+// Writing of single part scanline OpenEXR images
+// Format description: https://openexr.com/en/latest/OpenEXRFileLayout.html
+
+use flate2::write::ZlibEncoder;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Precision of the color channels in an EXR file
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ExrPixelType {
+    /// 16 bit floats. Plenty for colors and half the size
+    #[default]
+    Half,
+
+    /// 32 bit floats
+    Float,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ExrCompression {
+    None,
+
+    /// Run length encoding. Fast, but only effective on flat areas
+    Rle,
+
+    /// Lossless deflate compression of blocks of 16 scanlines
+    #[default]
+    Zip,
+}
+
+/// A named image channel, like "R" or "depth.Z"
+pub struct ExrChannel {
+    pub name: String,
+    pub pixel_type: ExrPixelType,
+    pub values: Vec<f32>,
+}
+
+impl ExrCompression {
+    fn id(self) -> u8 {
+        match self {
+            ExrCompression::None => 0,
+            ExrCompression::Rle => 1,
+            ExrCompression::Zip => 3,
+        }
+    }
+
+    fn lines_per_block(self) -> usize {
+        match self {
+            ExrCompression::None | ExrCompression::Rle => 1,
+            ExrCompression::Zip => 16,
+        }
+    }
+}
+
+impl ExrPixelType {
+    fn id(self) -> i32 {
+        match self {
+            ExrPixelType::Half => 1,
+            ExrPixelType::Float => 2,
+        }
+    }
+
+    fn size(self) -> usize {
+        match self {
+            ExrPixelType::Half => 2,
+            ExrPixelType::Float => 4,
+        }
+    }
+}
+
+/// Writes an arbitrary set of channels. Every channel must contain width * height values,
+/// row by row from the top left. Channels with a dot in their name, like "normal.X",
+/// show up as layers in most applications.
+pub fn write_exr<P: AsRef<Path>>(
+    path: P,
+    width: usize,
+    height: usize,
+    mut channels: Vec<ExrChannel>,
+    compression: ExrCompression,
+) -> Result<(), io::Error> {
+    assert!(width > 0 && height > 0);
+    assert!(channels.iter().all(|c| c.values.len() == width * height));
+
+    // Readers expect the channels in alphabetical order
+    channels.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut bytes = Vec::new();
+
+    // Magic number and version 2 (single part scanline file)
+    bytes.extend_from_slice(&[0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0]);
+
+    write_header(&mut bytes, width, height, &channels, compression);
+
+    // The offset table is filled in after the blocks are written
+    let lines_per_block = compression.lines_per_block();
+    let block_count = height.div_ceil(lines_per_block);
+    let offset_table_start = bytes.len();
+    bytes.resize(offset_table_start + block_count * 8, 0);
+
+    let bytes_per_line = channels.iter().map(|c| c.pixel_type.size()).sum::<usize>() * width;
+
+    for block in 0..block_count {
+        let first_line = block * lines_per_block;
+        let last_line = (first_line + lines_per_block).min(height);
+
+        // Within a line, all values of one channel are stored together
+        let mut raw = Vec::with_capacity((last_line - first_line) * bytes_per_line);
+
+        for y in first_line..last_line {
+            for channel in &channels {
+                for &value in &channel.values[y * width..(y + 1) * width] {
+                    match channel.pixel_type {
+                        ExrPixelType::Half => raw.extend_from_slice(&f32_to_half(value).to_le_bytes()),
+                        ExrPixelType::Float => raw.extend_from_slice(&value.to_le_bytes()),
+                    }
+                }
+            }
+        }
+
+        let data = match compression {
+            ExrCompression::None => raw,
+            ExrCompression::Rle => compress_or_raw(raw, |data| Ok(rle_compress(data)))?,
+            ExrCompression::Zip => compress_or_raw(raw, |data| {
+                let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            })?,
+        };
+
+        let offset = bytes.len() as u64;
+        bytes[offset_table_start + block * 8..offset_table_start + (block + 1) * 8]
+            .copy_from_slice(&offset.to_le_bytes());
+
+        bytes.extend_from_slice(&(first_line as i32).to_le_bytes());
+        bytes.extend_from_slice(&(data.len() as i32).to_le_bytes());
+        bytes.extend_from_slice(&data);
+    }
+
+    std::fs::write(path, bytes)
+}
+
+fn write_header(
+    bytes: &mut Vec<u8>,
+    width: usize,
+    height: usize,
+    channels: &[ExrChannel],
+    compression: ExrCompression,
+) {
+    let mut attribute = |name: &str, kind: &str, value: &[u8]| {
+        bytes.extend_from_slice(name.as_bytes());
+        bytes.push(0);
+        bytes.extend_from_slice(kind.as_bytes());
+        bytes.push(0);
+        bytes.extend_from_slice(&(value.len() as i32).to_le_bytes());
+        bytes.extend_from_slice(value);
+    };
+
+    let mut channel_list = Vec::new();
+
+    for channel in channels {
+        channel_list.extend_from_slice(channel.name.as_bytes());
+        channel_list.push(0);
+        channel_list.extend_from_slice(&channel.pixel_type.id().to_le_bytes());
+        // pLinear and three reserved bytes
+        channel_list.extend_from_slice(&[0, 0, 0, 0]);
+        // x and y sampling
+        channel_list.extend_from_slice(&1i32.to_le_bytes());
+        channel_list.extend_from_slice(&1i32.to_le_bytes());
+    }
+
+    channel_list.push(0);
+
+    let mut window = Vec::new();
+
+    for value in &[0, 0, width as i32 - 1, height as i32 - 1] {
+        window.extend_from_slice(&value.to_le_bytes());
+    }
+
+    attribute("channels", "chlist", &channel_list);
+    attribute("compression", "compression", &[compression.id()]);
+    attribute("dataWindow", "box2i", &window);
+    attribute("displayWindow", "box2i", &window);
+    // Increasing y
+    attribute("lineOrder", "lineOrder", &[0]);
+    attribute("pixelAspectRatio", "float", &1f32.to_le_bytes());
+    attribute("screenWindowCenter", "v2f", &[0; 8]);
+    attribute("screenWindowWidth", "float", &1f32.to_le_bytes());
+
+    // End of header
+    bytes.push(0);
+}
+
+// Both RLE and ZIP compression are applied to reordered and delta encoded data.
+// If compression doesn't pay off, readers expect the raw data instead.
+fn compress_or_raw<F>(raw: Vec<u8>, compress: F) -> Result<Vec<u8>, io::Error>
+where
+    F: Fn(&[u8]) -> Result<Vec<u8>, io::Error>,
+{
+    // Split the bytes into two halves: even and odd indices
+    let half = raw.len().div_ceil(2);
+    let mut prepared = vec![0u8; raw.len()];
+
+    for (i, &byte) in raw.iter().enumerate() {
+        if i % 2 == 0 {
+            prepared[i / 2] = byte;
+        } else {
+            prepared[half + i / 2] = byte;
+        }
+    }
+
+    // Store differences between neighbouring bytes
+    for i in (1..prepared.len()).rev() {
+        prepared[i] = prepared[i].wrapping_sub(prepared[i - 1]).wrapping_add(128);
+    }
+
+    let compressed = compress(&prepared)?;
+
+    if compressed.len() < raw.len() {
+        Ok(compressed)
+    } else {
+        Ok(raw)
+    }
+}
+
+// Runs of 3 to 128 equal bytes are stored as (length - 1, byte), everything
+// else as (-length, bytes...) with at most 127 literal bytes
+fn rle_compress(data: &[u8]) -> Vec<u8> {
+    const MIN_RUN: usize = 3;
+    const MAX_RUN: usize = 128;
+    const MAX_LITERAL: usize = 127;
+
+    let run_length = |start: usize| {
+        data[start..]
+            .iter()
+            .take(MAX_RUN)
+            .take_while(|&&b| b == data[start])
+            .count()
+    };
+
+    let mut out = Vec::with_capacity(data.len());
+    let mut pos = 0;
+
+    while pos < data.len() {
+        let run = run_length(pos);
+
+        if run >= MIN_RUN {
+            out.push((run - 1) as u8);
+            out.push(data[pos]);
+            pos += run;
+        } else {
+            // Collect literals until the next run starts
+            let start = pos;
+
+            while pos < data.len() && pos - start < MAX_LITERAL && run_length(pos) < MIN_RUN {
+                pos += 1;
+            }
+
+            out.push((-((pos - start) as i32)) as u8);
+            out.extend_from_slice(&data[start..pos]);
+        }
+    }
+
+    out
+}
+
+// Rounds to the nearest representable half float. Values that are too large become infinity.
+fn f32_to_half(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x007f_ffff;
+
+    // NaN and infinity
+    if exponent == 0xff {
+        return sign | 0x7c00 | if mantissa != 0 { 0x0200 } else { 0 };
+    }
+
+    let half_exponent = exponent - 127 + 15;
+
+    if half_exponent >= 0x1f {
+        return sign | 0x7c00;
+    }
+
+    if half_exponent <= 0 {
+        // Subnormal half or zero
+        if half_exponent < -10 {
+            return sign;
+        }
+
+        let mantissa = mantissa | 0x0080_0000;
+        let shift = (14 - half_exponent) as u32;
+        let rounded = (mantissa + (1 << (shift - 1))) >> shift;
+
+        return sign | rounded as u16;
+    }
+
+    // Round to nearest. A carry into the exponent is still the correct result
+    let rounded = ((half_exponent as u32) << 10 | (mantissa >> 13)) + ((mantissa >> 12) & 1);
+
+    if rounded >= 0x7c00 {
+        sign | 0x7c00
+    } else {
+        sign | rounded as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::prelude::*;
+
+    fn rle_decompress(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut pos = 0;
+
+        while pos < data.len() {
+            let count = data[pos] as i8;
+            pos += 1;
+
+            if count < 0 {
+                let len = -(count as i32) as usize;
+                out.extend_from_slice(&data[pos..pos + len]);
+                pos += len;
+            } else {
+                out.extend(std::iter::repeat_n(data[pos], count as usize + 1));
+                pos += 1;
+            }
+        }
+
+        out
+    }
+
+    #[test]
+    fn half_of_exact_values() {
+        assert_eq!(f32_to_half(0.0), 0x0000);
+        assert_eq!(f32_to_half(-0.0), 0x8000);
+        assert_eq!(f32_to_half(1.0), 0x3c00);
+        assert_eq!(f32_to_half(0.5), 0x3800);
+        assert_eq!(f32_to_half(-2.0), 0xc000);
+        assert_eq!(f32_to_half(1.0 + 2f32.powi(-10)), 0x3c01);
+        assert_eq!(f32_to_half(65504.0), 0x7bff);
+    }
+
+    #[test]
+    fn half_rounds_to_nearest() {
+        assert_eq!(f32_to_half(0.1), 0x2e66);
+        assert_eq!(f32_to_half(1.0 + 2f32.powi(-12)), 0x3c00);
+        assert_eq!(f32_to_half(1.0 + 3.0 * 2f32.powi(-12)), 0x3c01);
+        // Rounding up may carry into the exponent
+        assert_eq!(f32_to_half(2.0 - 2f32.powi(-12)), 0x4000);
+    }
+
+    #[test]
+    fn half_overflow_and_special_values() {
+        assert_eq!(f32_to_half(65520.0), 0x7c00);
+        assert_eq!(f32_to_half(1e10), 0x7c00);
+        assert_eq!(f32_to_half(-1e10), 0xfc00);
+        assert_eq!(f32_to_half(f32::INFINITY), 0x7c00);
+        assert_eq!(f32_to_half(f32::NEG_INFINITY), 0xfc00);
+        assert_eq!(f32_to_half(f32::NAN) & 0x7c00, 0x7c00);
+        assert_ne!(f32_to_half(f32::NAN) & 0x03ff, 0);
+    }
+
+    #[test]
+    fn half_subnormals() {
+        assert_eq!(f32_to_half(2f32.powi(-14)), 0x0400);
+        assert_eq!(f32_to_half(2f32.powi(-15)), 0x0200);
+        assert_eq!(f32_to_half(2f32.powi(-24)), 0x0001);
+        assert_eq!(f32_to_half(-2f32.powi(-24)), 0x8001);
+        assert_eq!(f32_to_half(2f32.powi(-30)), 0x0000);
+    }
+
+    #[test]
+    fn rle_runs_and_literals() {
+        assert_eq!(rle_compress(&[7, 7, 7, 7]), vec![3, 7]);
+        assert_eq!(rle_compress(&[1, 2]), vec![0xfe, 1, 2]);
+        assert_eq!(rle_compress(&[1, 2, 5, 5, 5]), vec![0xfe, 1, 2, 2, 5]);
+        // Runs of two are not worth it
+        assert_eq!(rle_compress(&[4, 4, 9]), vec![0xfd, 4, 4, 9]);
+        assert!(rle_compress(&[]).is_empty());
+    }
+
+    #[test]
+    fn rle_splits_long_runs_and_literals() {
+        let run = vec![3u8; 300];
+        assert_eq!(rle_compress(&run), vec![127, 3, 127, 3, 43, 3]);
+
+        let literals = (0..200).map(|i| i as u8).collect::<Vec<_>>();
+        let compressed = rle_compress(&literals);
+        assert_eq!(compressed[0], (-127i8) as u8);
+        assert_eq!(compressed[128], (-73i8) as u8);
+        assert_eq!(compressed.len(), 202);
+    }
+
+    #[test]
+    fn rle_round_trip() {
+        let mut data = Vec::new();
+
+        for i in 0..2000u32 {
+            let value = (i / 7 % 5) as u8;
+            data.push(if i % 11 < 4 { (i * 31) as u8 } else { value });
+        }
+
+        assert_eq!(rle_decompress(&rle_compress(&data)), data);
+    }
+
+    #[test]
+    fn incompressible_data_stays_raw() {
+        let mut rng = SmallRng::seed_from_u64(1);
+        let raw = (0..64).map(|_| rng.gen()).collect::<Vec<u8>>();
+        let stored = compress_or_raw(raw.clone(), |data| Ok(rle_compress(data))).unwrap();
+        assert_eq!(stored, raw);
+
+        let flat = vec![0u8; 64];
+        let stored = compress_or_raw(flat.clone(), |data| Ok(rle_compress(data))).unwrap();
+        assert!(stored.len() < flat.len());
+    }
+}
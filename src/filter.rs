@@ -0,0 +1,392 @@
+use rand::prelude::*;
+use serde::Deserialize;
+use std::ops::{Div, Mul, Sub};
+use std::sync::OnceLock;
+
+/// Reconstruction filter that determines how much a sample contributes to a pixel,
+/// depending on its offset to the pixel center. All filters except the box filter
+/// extend into the neighbouring pixels.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PixelFilter {
+    /// Every sample inside of the pixel counts the same
+    #[default]
+    Box,
+
+    /// Linear falloff over a radius of one pixel
+    Tent,
+
+    /// Gaussian bell curve, slightly blurry but free of ringing
+    Gaussian,
+
+    /// Mitchell-Netravali filter (B = C = 1/3). Sharper than the gaussian filter,
+    /// but its negative lobes can cause slight halos around hard edges
+    Mitchell,
+}
+
+/// Offset of a sample to the pixel center. Samples are distributed proportionally to the
+/// absolute filter value, so apart from the lobe they fall into, they are all weighted equally.
+#[derive(Copy, Clone, Debug)]
+pub struct FilterSample {
+    pub dx: f64,
+    pub dy: f64,
+
+    /// True if the filter is negative at the offset
+    pub negative: bool,
+}
+
+impl FilterSample {
+    pub const CENTER: FilterSample = FilterSample {
+        dx: 0.0,
+        dy: 0.0,
+        negative: false,
+    };
+}
+
+// Tabulated distribution of the absolute value of the 1D filter
+struct FilterTable {
+    cdf: Vec<f64>,
+
+    // Ratio between the negative and the total volume of the 2D filter
+    negative_share: f64,
+}
+
+impl PixelFilter {
+    /// Distance from the pixel center (in pixels) after which the filter is 0
+    pub fn radius(self) -> f64 {
+        match self {
+            PixelFilter::Box => 0.5,
+            PixelFilter::Tent => 1.0,
+            PixelFilter::Gaussian => 1.5,
+            PixelFilter::Mitchell => 2.0,
+        }
+    }
+
+    /// Weight of a sample at the supplied offset (in pixels) to the pixel center
+    pub fn weight(self, dx: f64, dy: f64) -> f64 {
+        self.weight_1d(dx) * self.weight_1d(dy)
+    }
+
+    // All filters are separable
+    fn weight_1d(self, x: f64) -> f64 {
+        let x = x.abs();
+
+        match self {
+            PixelFilter::Box => {
+                if x <= 0.5 {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            PixelFilter::Tent => (1.0 - x).max(0.0),
+            PixelFilter::Gaussian => {
+                // Shifted down so that it reaches exactly 0 at the radius
+                let gaussian = |x: f64| (-2.0 * x * x).exp();
+
+                (gaussian(x) - gaussian(self.radius())).max(0.0)
+            }
+            PixelFilter::Mitchell => {
+                const B: f64 = 1.0 / 3.0;
+                const C: f64 = 1.0 / 3.0;
+
+                let weight = if x < 1.0 {
+                    (12.0 - 9.0 * B - 6.0 * C) * x * x * x
+                        + (-18.0 + 12.0 * B + 6.0 * C) * x * x
+                        + (6.0 - 2.0 * B)
+                } else if x < 2.0 {
+                    (-B - 6.0 * C) * x * x * x
+                        + (6.0 * B + 30.0 * C) * x * x
+                        + (-12.0 * B - 48.0 * C) * x
+                        + (8.0 * B + 24.0 * C)
+                } else {
+                    0.0
+                };
+
+                weight / 6.0
+            }
+        }
+    }
+
+    /// Returns count samples that are distributed like the filter. As many samples as possible
+    /// are stratified in a regular grid of cells and jittered inside of them, the rest is
+    /// distributed randomly.
+    pub fn gen_samples<R: Rng + ?Sized>(self, rng: &mut R, count: u32) -> Vec<FilterSample> {
+        let cells = (count as f64).sqrt().floor() as u32;
+
+        (0..count)
+            .map(|i| {
+                let (u, v) = if i < cells * cells {
+                    let cell_x = (i % cells) as f64;
+                    let cell_y = (i / cells) as f64;
+
+                    (
+                        (cell_x + rng.gen::<f64>()) / cells as f64,
+                        (cell_y + rng.gen::<f64>()) / cells as f64,
+                    )
+                } else {
+                    (rng.gen(), rng.gen())
+                };
+
+                let dx = self.sample_1d(u);
+                let dy = self.sample_1d(v);
+
+                FilterSample {
+                    dx,
+                    dy,
+                    negative: self.weight(dx, dy) < 0.0,
+                }
+            })
+            .collect()
+    }
+
+    /// Combines the summed up values (colors or alpha) of the samples in the positive and
+    /// negative lobes. Averaging both lobes separately keeps the result stable, even if only
+    /// a few samples end up in the negative lobes. A lobe without samples adds nothing.
+    pub fn combine_lobes<T>(
+        self,
+        positive: T,
+        positive_count: u32,
+        negative: T,
+        negative_count: u32,
+    ) -> T
+    where
+        T: Copy + Div<f64, Output = T> + Mul<f64, Output = T> + Sub<Output = T>,
+    {
+        match (positive_count, negative_count) {
+            // Nothing was summed up, so the sum is zero
+            (0, 0) => positive,
+            (_, 0) => positive / positive_count as f64,
+
+            // Only the negative lobes' share is subtracted, the value itself lies in the
+            // negative part of the filter and must not count as if it were positive
+            (0, _) => negative / negative_count as f64 * -self.table().negative_share,
+            _ => {
+                let share = self.table().negative_share;
+
+                positive / positive_count as f64 * (1.0 + share)
+                    - negative / negative_count as f64 * share
+            }
+        }
+    }
+
+    // Maps a uniform random number in the range 0-1 to an offset, with a probability
+    // proportional to the absolute filter value
+    fn sample_1d(self, u: f64) -> f64 {
+        let cdf = &self.table().cdf;
+        let bins = cdf.len() - 1;
+        let target = u * cdf[bins];
+
+        let bin = cdf
+            .partition_point(|&c| c <= target)
+            .saturating_sub(1)
+            .min(bins - 1);
+
+        let bin_mass = cdf[bin + 1] - cdf[bin];
+        let t = if bin_mass > 0.0 {
+            ((target - cdf[bin]) / bin_mass).clamp(0.0, 1.0)
+        } else {
+            0.5
+        };
+
+        let radius = self.radius();
+
+        -radius + (bin as f64 + t) * 2.0 * radius / bins as f64
+    }
+
+    fn table(self) -> &'static FilterTable {
+        static TABLES: [OnceLock<FilterTable>; 4] =
+            [OnceLock::new(), OnceLock::new(), OnceLock::new(), OnceLock::new()];
+
+        let index = match self {
+            PixelFilter::Box => 0,
+            PixelFilter::Tent => 1,
+            PixelFilter::Gaussian => 2,
+            PixelFilter::Mitchell => 3,
+        };
+
+        TABLES[index].get_or_init(|| {
+            const BINS: usize = 256;
+
+            let radius = self.radius();
+            let bin_size = 2.0 * radius / BINS as f64;
+
+            let mut cdf = vec![0.0; BINS + 1];
+            let (mut positive, mut negative) = (0.0, 0.0);
+
+            for bin in 0..BINS {
+                let weight = self.weight_1d(-radius + (bin as f64 + 0.5) * bin_size) * bin_size;
+
+                if weight >= 0.0 {
+                    positive += weight;
+                } else {
+                    negative -= weight;
+                }
+
+                cdf[bin + 1] = cdf[bin] + weight.abs();
+            }
+
+            // The 2D filter is negative where exactly one of the 1D filters is
+            let negative_2d = 2.0 * positive * negative;
+            let total_2d = (positive - negative) * (positive - negative);
+
+            FilterTable {
+                cdf,
+                negative_share: negative_2d / total_2d,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILTERS: [PixelFilter; 4] = [
+        PixelFilter::Box,
+        PixelFilter::Tent,
+        PixelFilter::Gaussian,
+        PixelFilter::Mitchell,
+    ];
+
+    fn samples(filter: PixelFilter, count: u32) -> Vec<FilterSample> {
+        filter.gen_samples(&mut SmallRng::seed_from_u64(7), count)
+    }
+
+    // Monte Carlo estimate of the filter weighted average of f, combined like the renderer does
+    fn estimate<F: Fn(f64, f64) -> f64>(filter: PixelFilter, count: u32, f: F) -> f64 {
+        let (mut positive, mut positive_count) = (0.0, 0);
+        let (mut negative, mut negative_count) = (0.0, 0);
+
+        for sample in samples(filter, count) {
+            if sample.negative {
+                negative += f(sample.dx, sample.dy);
+                negative_count += 1;
+            } else {
+                positive += f(sample.dx, sample.dy);
+                positive_count += 1;
+            }
+        }
+
+        filter.combine_lobes(positive, positive_count, negative, negative_count)
+    }
+
+    // Filter weighted average of f by numerical integration
+    fn integrate<F: Fn(f64, f64) -> f64>(filter: PixelFilter, f: F) -> f64 {
+        const STEPS: usize = 400;
+
+        let radius = filter.radius();
+        let step = 2.0 * radius / STEPS as f64;
+        let (mut sum, mut total_weight) = (0.0, 0.0);
+
+        for i in 0..STEPS {
+            for j in 0..STEPS {
+                let x = -radius + (i as f64 + 0.5) * step;
+                let y = -radius + (j as f64 + 0.5) * step;
+                let weight = filter.weight(x, y);
+
+                sum += weight * f(x, y);
+                total_weight += weight;
+            }
+        }
+
+        sum / total_weight
+    }
+
+    #[test]
+    fn samples_stay_within_radius() {
+        for &filter in &FILTERS {
+            for sample in samples(filter, 1000) {
+                assert!(sample.dx.abs() <= filter.radius(), "{:?}: {:?}", filter, sample);
+                assert!(sample.dy.abs() <= filter.radius(), "{:?}: {:?}", filter, sample);
+            }
+        }
+    }
+
+    #[test]
+    fn only_mitchell_has_negative_lobes() {
+        for &filter in &FILTERS {
+            let samples = samples(filter, 10000);
+            let negative = samples.iter().filter(|s| s.negative).count();
+
+            if filter == PixelFilter::Mitchell {
+                let share = negative as f64 / samples.len() as f64;
+                // Negative volume relative to the total absolute volume
+                let negative_share = filter.table().negative_share;
+                let expected = negative_share / (1.0 + 2.0 * negative_share);
+
+                assert!((share - expected).abs() < 0.01, "{} != {}", share, expected);
+
+                for sample in samples.iter().filter(|s| s.negative) {
+                    assert!(filter.weight(sample.dx, sample.dy) < 0.0);
+                }
+            } else {
+                assert_eq!(negative, 0, "{:?}", filter);
+            }
+        }
+    }
+
+    #[test]
+    fn box_samples_are_stratified() {
+        let cells = 4;
+        let mut hits = vec![0; cells * cells];
+
+        for sample in samples(PixelFilter::Box, (cells * cells) as u32) {
+            let x = ((sample.dx + 0.5) * cells as f64) as usize;
+            let y = ((sample.dy + 0.5) * cells as f64) as usize;
+
+            hits[y * cells + x] += 1;
+        }
+
+        assert!(hits.iter().all(|&h| h == 1), "{:?}", hits);
+    }
+
+    #[test]
+    fn samples_follow_the_filter() {
+        // The tent filter has 3/4 of its 1D mass within half a pixel of the center
+        let samples = samples(PixelFilter::Tent, 10000);
+        let inner = samples.iter().filter(|s| s.dx.abs() < 0.5).count() as f64;
+
+        assert!((inner / samples.len() as f64 - 0.75).abs() < 0.02);
+    }
+
+    #[test]
+    fn lobes_combine_to_filtered_average() {
+        let functions: [fn(f64, f64) -> f64; 3] = [
+            |_, _| 2.5,
+            |x, y| x * x + y * y,
+            |x, y| if x > 0.3 && y < 0.5 { 1.0 } else { 0.0 },
+        ];
+
+        for &filter in &FILTERS {
+            for f in &functions {
+                let expected = integrate(filter, f);
+                let estimate = estimate(filter, 40000, f);
+
+                assert!(
+                    (estimate - expected).abs() < 0.01 + 0.02 * expected.abs(),
+                    "{:?}: {} != {}",
+                    filter,
+                    estimate,
+                    expected
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn combine_single_lobes() {
+        let filter = PixelFilter::Mitchell;
+
+        assert_eq!(filter.combine_lobes(0.0, 0, 0.0, 0), 0.0);
+        assert_eq!(filter.combine_lobes(6.0, 3, 0.0, 0), 2.0);
+
+        let share = filter.table().negative_share;
+        assert!(share > 0.0);
+        assert_eq!(filter.combine_lobes(0.0, 0, 6.0, 2), -3.0 * share);
+
+        // Equal averages in both lobes give that average
+        let combined = filter.combine_lobes(8.0, 4, 2.0, 1);
+        assert!((combined - 2.0).abs() < 1e-12);
+    }
+}
@@ -2,6 +2,7 @@ use crate::parser::{const_f64_one, const_true};
 use crate::prelude::*;
 use serde::Deserialize;
 use crate::ray_target::*;
+use crate::bvh::Aabb;
 use crate::raytracing::*;
 use std::sync::Arc;
 
@@ -10,25 +11,61 @@ pub struct Cube {
     visible_to_camera: bool
 }
 
-#[derive(Default, Deserialize)]
+#[derive(Deserialize)]
 #[serde(default)]
 #[serde(deny_unknown_fields)]
 pub struct CubeInit {
-    origin: Vec3,
-    rotation: Vec3,
+    pub origin: Vec3,
+    pub rotation: Vec3,
 
+    // HALF of the cube extents
     #[serde(default = "const_f64_one")]
-    width: f64,
+    pub width: f64,
 
     #[serde(default = "const_f64_one")]
-    height: f64,
+    pub height: f64,
 
     #[serde(default = "const_f64_one")]
-    depth: f64,
+    pub depth: f64,
 
     #[serde(default = "const_true")]
     #[serde(rename = "visible-to-camera")]
-    visible_to_camera: bool
+    pub visible_to_camera: bool
+}
+
+impl CubeInit {
+    /// Width, height and depth are HALF of the extents
+    pub fn new(origin: Vec3, width: f64, height: f64, depth: f64) -> CubeInit {
+        CubeInit {
+            origin,
+            width,
+            height,
+            depth,
+            ..CubeInit::default()
+        }
+    }
+
+    pub fn with_rotation(self, rotation: Vec3) -> CubeInit {
+        CubeInit { rotation, ..self }
+    }
+
+    pub fn with_visible_to_camera(self, visible_to_camera: bool) -> CubeInit {
+        CubeInit { visible_to_camera, ..self }
+    }
+}
+
+// Same defaults as in scene files
+impl Default for CubeInit {
+    fn default() -> Self {
+        CubeInit {
+            origin: Vec3::ZERO,
+            rotation: Vec3::ZERO,
+            width: 1.0,
+            height: 1.0,
+            depth: 1.0,
+            visible_to_camera: true
+        }
+    }
 }
 
 impl Cube {
@@ -128,12 +165,16 @@ impl RayTarget for Cube {
         self.visible_to_camera
     }
 
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.planes.iter()
+            .flat_map(|p| p.bounding_box())
+            .reduce(Aabb::union)
+    }
+
     fn test_intersection(&self, ray: &Ray) -> Option<GeometryHitInfo> {
 
         self.planes.iter()
-            .map(|p| p.test_intersection(ray))
-            .filter(|hit| hit.is_some())
-            .map(|hit| hit.unwrap())
+            .flat_map(|p| p.test_intersection(ray))
             .min_by(|a, b| hit_dist_comp(ray.origin, a, b))
     }
 }
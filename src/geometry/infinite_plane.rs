@@ -2,6 +2,7 @@ use crate::vec::*;
 use crate::uv_mappers::*;
 use crate::raytracing::*;
 use crate::ray_target::*;
+use crate::bvh::Aabb;
 use crate::parser::{const_f64_one, const_true};
 use serde::Deserialize;
 use std::sync::Arc;
@@ -22,20 +23,57 @@ pub struct InifinitePlane {
     forwards: Vec3Norm
 }
 
-#[derive(Default, Deserialize)]
+#[derive(Deserialize)]
 #[serde(default)]
 #[serde(deny_unknown_fields)]
 pub struct InfinitePlaneInit {
-    origin: Vec3,
-    rotation: Vec3,
+    pub origin: Vec3,
+    pub rotation: Vec3,
 
+    /// Distances are multiplied by this to get uvs, so they wrap around every 1 / uv_scale units
     #[serde(default = "const_f64_one")]
     #[serde(rename = "uv-scale")]
-    uv_scale: f64,
+    pub uv_scale: f64,
 
     #[serde(default = "const_true")]
     #[serde(rename = "visible-to-camera")]
-    visible_to_camera: bool
+    pub visible_to_camera: bool
+}
+
+impl InfinitePlaneInit {
+
+    /// Plane facing upwards
+    pub fn new(origin: Vec3) -> InfinitePlaneInit {
+        InfinitePlaneInit {
+            origin,
+            ..InfinitePlaneInit::default()
+        }
+    }
+
+    pub fn with_rotation(self, rotation: Vec3) -> InfinitePlaneInit {
+        InfinitePlaneInit { rotation, ..self }
+    }
+
+    pub fn with_uv_scale(self, uv_scale: f64) -> InfinitePlaneInit {
+        InfinitePlaneInit { uv_scale, ..self }
+    }
+
+    pub fn with_visible_to_camera(self, visible_to_camera: bool) -> InfinitePlaneInit {
+        InfinitePlaneInit { visible_to_camera, ..self }
+    }
+}
+
+// Same defaults as in scene files
+impl Default for InfinitePlaneInit {
+
+    fn default() -> Self {
+        InfinitePlaneInit {
+            origin: Vec3::ZERO,
+            rotation: Vec3::ZERO,
+            uv_scale: 1.0,
+            visible_to_camera: true
+        }
+    }
 }
 
 impl InifinitePlane {
@@ -62,7 +100,7 @@ impl InifinitePlane {
 
         let cos_ray_to_plane = normal.dot(ray.direction);
 
-        if cos_ray_to_plane < -std::f64::EPSILON {
+        if cos_ray_to_plane < -f64::EPSILON {
 
             // angle larger 90 deg, so they have to meet at some point (but could still meet behind the ray origin)
 
@@ -91,6 +129,10 @@ impl RayTarget for InifinitePlane {
         self.visible_to_camera
     }
 
+    fn bounding_box(&self) -> Option<Aabb> {
+        None
+    }
+
     fn test_intersection(&self, ray: &Ray) -> Option<GeometryHitInfo> {
 
         let hitpoint = InifinitePlane::get_ray_intersection(self.origin, self.normal, ray)?;
@@ -101,11 +143,11 @@ impl RayTarget for InifinitePlane {
         let mut uv_y = (orig_to_hitpoint.dot(self.forwards) * self.uv_scale).fract();
 
         if uv_x < 0.0 {
-            uv_x = 1.0 + uv_x;
+            uv_x += 1.0;
         }
 
         if uv_y < 0.0 {
-            uv_y = 1.0 + uv_y;
+            uv_y += 1.0;
         }
 
         Some(GeometryHitInfo {
@@ -0,0 +1,584 @@
+use crate::bvh::*;
+use crate::diagnostics::Diagnostics;
+use crate::parser::{const_f64_one, const_true};
+use crate::ray_target::*;
+use crate::raytracing::*;
+use crate::util;
+use crate::uv_mappers::*;
+use crate::vec::*;
+use serde::Deserialize;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+pub struct Mesh {
+    triangles: Vec<Triangle>,
+    bvh: Bvh,
+    bounds: Option<Aabb>,
+
+    uv_mapper: Arc<dyn UvMapper>,
+    visible_to_camera: bool,
+    smooth_shading: bool,
+}
+
+struct Triangle {
+    // First vertex and the two edges leaving it, in world space
+    p0: Vec3,
+    e1: Vec3,
+    e2: Vec3,
+
+    face_normal: Vec3Norm,
+
+    vertex_normals: Option<[Vec3Norm; 3]>,
+    uvs: Option<[Vec2; 3]>,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct MeshInit {
+    /// Path to a Wavefront OBJ file
+    pub path: String,
+
+    pub origin: Vec3,
+    pub rotation: Vec3,
+
+    #[serde(default = "const_f64_one")]
+    pub scale: f64,
+
+    /// Interpolate vertex normals if the file contains them
+    #[serde(default = "const_true")]
+    #[serde(rename = "smooth-shading")]
+    pub smooth_shading: bool,
+
+    #[serde(default = "const_true")]
+    #[serde(rename = "visible-to-camera")]
+    pub visible_to_camera: bool,
+}
+
+impl MeshInit {
+    pub fn new<S: Into<String>>(path: S, origin: Vec3) -> MeshInit {
+        MeshInit {
+            path: path.into(),
+            origin,
+            ..MeshInit::default()
+        }
+    }
+
+    pub fn with_rotation(self, rotation: Vec3) -> MeshInit {
+        MeshInit { rotation, ..self }
+    }
+
+    pub fn with_scale(self, scale: f64) -> MeshInit {
+        MeshInit { scale, ..self }
+    }
+
+    pub fn with_smooth_shading(self, smooth_shading: bool) -> MeshInit {
+        MeshInit {
+            smooth_shading,
+            ..self
+        }
+    }
+
+    pub fn with_visible_to_camera(self, visible_to_camera: bool) -> MeshInit {
+        MeshInit {
+            visible_to_camera,
+            ..self
+        }
+    }
+
+    pub fn validate(&self, diagnostics: &mut Diagnostics) {
+        if !util::is_in_range_exclusive(self.scale, 0.0, f64::INFINITY) {
+            diagnostics.error("Mesh scale must be positive and finite");
+        }
+    }
+}
+
+// Same defaults as in scene files
+impl Default for MeshInit {
+    fn default() -> Self {
+        MeshInit {
+            path: String::new(),
+            origin: Vec3::ZERO,
+            rotation: Vec3::ZERO,
+            scale: 1.0,
+            smooth_shading: true,
+            visible_to_camera: true,
+        }
+    }
+}
+
+impl Mesh {
+    pub fn from_obj_file(init: &MeshInit, uv_mapper: Arc<dyn UvMapper>) -> Result<Mesh, io::Error> {
+        let obj = ObjData::load(&init.path)
+            .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", init.path, err)))?;
+
+        let transform_point = |p: Vec3| init.origin + (p * init.scale).rotate(init.rotation);
+        let transform_normal = |n: Vec3| {
+            if n.is_zero() {
+                None
+            } else {
+                Some(n.normalized().rotate(init.rotation))
+            }
+        };
+
+        let positions = obj.positions.into_iter().map(transform_point).collect::<Vec<_>>();
+        let normals = obj.normals.into_iter().map(transform_normal).collect::<Vec<_>>();
+
+        let mut triangles = Vec::with_capacity(obj.faces.len());
+
+        for face in &obj.faces {
+            let p = [positions[face[0].position], positions[face[1].position], positions[face[2].position]];
+
+            let e1 = p[1] - p[0];
+            let e2 = p[2] - p[0];
+            let cross = e1.cross(e2);
+
+            // Skip degenerate triangles, they can never be hit anyway
+            if cross.is_zero() {
+                continue;
+            }
+
+            let vertex_normals = match (face[0].normal, face[1].normal, face[2].normal) {
+                (Some(a), Some(b), Some(c)) => match (normals[a], normals[b], normals[c]) {
+                    (Some(a), Some(b), Some(c)) => Some([a, b, c]),
+                    _ => None,
+                },
+                _ => None,
+            };
+
+            let uvs = match (face[0].uv, face[1].uv, face[2].uv) {
+                (Some(a), Some(b), Some(c)) => Some([obj.uvs[a], obj.uvs[b], obj.uvs[c]]),
+                _ => None,
+            };
+
+            // Vertex normals are usually more trustworthy than the winding order
+            let face_normal = match vertex_normals {
+                Some([n0, n1, n2]) if cross.dot(n0 + n1 + n2) < 0.0 => -cross.normalized(),
+                _ => cross.normalized(),
+            };
+
+            triangles.push(Triangle {
+                p0: p[0],
+                e1,
+                e2,
+                face_normal,
+                vertex_normals,
+                uvs,
+            });
+        }
+
+        let triangle_bounds = triangles
+            .iter()
+            .map(|t| Aabb::from_points(vec![t.p0, t.p0 + t.e1, t.p0 + t.e2]).padded())
+            .collect::<Vec<_>>();
+
+        let bounds = triangle_bounds.iter().copied().reduce(Aabb::union);
+
+        Ok(Mesh {
+            bvh: Bvh::build(&triangle_bounds),
+            triangles,
+            bounds,
+            uv_mapper,
+            visible_to_camera: init.visible_to_camera,
+            smooth_shading: init.smooth_shading,
+        })
+    }
+}
+
+impl Triangle {
+    // Möller-Trumbore intersection. Returns the distance along the ray and the
+    // barycentric coordinates of the second and third vertex.
+    // Triangles are double sided, so that refracted rays can leave closed meshes.
+    fn intersect(&self, ray: &Ray) -> Option<(f64, f64, f64)> {
+        let p = ray.direction.cross(self.e2);
+        let det = self.e1.dot(p);
+
+        // Only exactly parallel rays are skipped here. A fixed epsilon would also skip every
+        // ray that hits a small triangle, because the determinant scales with its area.
+        // Nearly parallel rays end up with huge or NaN coordinates, which fail the checks below
+        if det == 0.0 {
+            return None;
+        }
+
+        let inv_det = 1.0 / det;
+        let s = ray.origin - self.p0;
+        let b1 = s.dot(p) * inv_det;
+
+        if !(0.0..=1.0).contains(&b1) {
+            return None;
+        }
+
+        let q = s.cross(self.e1);
+        let b2 = ray.direction.dot(q) * inv_det;
+
+        if b2 < 0.0 || b1 + b2 > 1.0 {
+            return None;
+        }
+
+        let t = self.e2.dot(q) * inv_det;
+
+        if t > 0.0 {
+            Some((t, b1, b2))
+        } else {
+            None
+        }
+    }
+}
+
+impl RayTarget for Mesh {
+    fn test_intersection(&self, ray: &Ray) -> Option<GeometryHitInfo> {
+        let (index, (t, b1, b2), _) = self.bvh.closest_hit(ray, |i| {
+            self.triangles[i].intersect(ray).map(|hit| (hit, hit.0 * hit.0))
+        })?;
+
+        let triangle = &self.triangles[index];
+        let b0 = 1.0 - b1 - b2;
+
+        let normal = match triangle.vertex_normals {
+            Some([n0, n1, n2]) if self.smooth_shading => {
+                let interpolated = n0 * b0 + n1 * b1 + n2 * b2;
+
+                if interpolated.is_zero() {
+                    triangle.face_normal
+                } else {
+                    interpolated.normalized()
+                }
+            }
+            _ => triangle.face_normal,
+        };
+
+        // Without texture coordinates, we fall back to the barycentric coordinates
+        let uv = match triangle.uvs {
+            Some([uv0, uv1, uv2]) => Vec2::new(
+                (uv0.u * b0 + uv1.u * b1 + uv2.u * b2).rem_euclid(1.0),
+                (uv0.v * b0 + uv1.v * b1 + uv2.v * b2).rem_euclid(1.0),
+            ),
+            None => Vec2::new(b1, b2),
+        };
+
+        Some(GeometryHitInfo {
+            position: ray.origin + ray.direction * t,
+            normal,
+            uv,
+        })
+    }
+
+    fn is_visible_to_camera(&self) -> bool {
+        self.visible_to_camera
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.bounds
+    }
+}
+
+impl HasUvMapper for Mesh {
+    fn get_uv_mapper(&self) -> &Arc<dyn UvMapper> {
+        &self.uv_mapper
+    }
+}
+
+// Wavefront OBJ parsing
+
+#[derive(Copy, Clone)]
+struct ObjVertex {
+    position: usize,
+    uv: Option<usize>,
+    normal: Option<usize>,
+}
+
+#[derive(Default)]
+struct ObjData {
+    positions: Vec<Vec3>,
+    normals: Vec<Vec3>,
+    uvs: Vec<Vec2>,
+
+    // Already triangulated
+    faces: Vec<[ObjVertex; 3]>,
+}
+
+impl ObjData {
+    fn load<P: AsRef<Path>>(path: P) -> Result<ObjData, io::Error> {
+        let content = std::fs::read_to_string(path)?;
+
+        let mut obj = ObjData::default();
+
+        for (line_index, line) in content.lines().enumerate() {
+            obj.parse_line(line).map_err(|msg| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("OBJ line {}: {}", line_index + 1, msg),
+                )
+            })?;
+        }
+
+        Ok(obj)
+    }
+
+    fn parse_line(&mut self, line: &str) -> Result<(), String> {
+        // Strip comments
+        let line = line.split('#').next().unwrap_or("");
+
+        let mut tokens = line.split_whitespace();
+
+        match tokens.next() {
+            Some("v") => {
+                let [x, y, z] = parse_floats(&mut tokens)?;
+                self.positions.push(Vec3::new(x, y, z));
+            }
+            Some("vn") => {
+                let [x, y, z] = parse_floats(&mut tokens)?;
+                self.normals.push(Vec3::new(x, y, z));
+            }
+            Some("vt") => {
+                // v is optional and defaults to 0
+                let [u] = parse_floats(&mut tokens)?;
+                let v = tokens.next().map_or(Ok(0.0), parse_float)?;
+                self.uvs.push(Vec2::new(u, v));
+            }
+            Some("f") => {
+                let vertices = tokens
+                    .map(|t| self.parse_face_vertex(t))
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                if vertices.len() < 3 {
+                    return Err("A face needs at least 3 vertices".to_owned());
+                }
+
+                // Fan triangulation for n-gons. This is only correct for convex polygons,
+                // but that's what pretty much every exporter writes anyway.
+                for i in 1..vertices.len() - 1 {
+                    self.faces.push([vertices[0], vertices[i], vertices[i + 1]]);
+                }
+            }
+            // Groups, materials, smoothing groups, lines, ... are not supported and silently skipped
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    // Parses vertex references of the forms v, v/vt, v//vn and v/vt/vn
+    fn parse_face_vertex(&self, token: &str) -> Result<ObjVertex, String> {
+        let mut parts = token.split('/');
+
+        let position = resolve_index(parts.next(), self.positions.len())?
+            .ok_or_else(|| format!("Face vertex \"{}\" has no position index", token))?;
+        let uv = resolve_index(parts.next(), self.uvs.len())?;
+        let normal = resolve_index(parts.next(), self.normals.len())?;
+
+        Ok(ObjVertex {
+            position,
+            uv,
+            normal,
+        })
+    }
+}
+
+fn parse_floats<'a, I, const N: usize>(tokens: &mut I) -> Result<[f64; N], String>
+where
+    I: Iterator<Item = &'a str>,
+{
+    let mut values = [0.0; N];
+
+    for value in values.iter_mut() {
+        let token = tokens.next().ok_or("Not enough coordinates")?;
+        *value = parse_float(token)?;
+    }
+
+    Ok(values)
+}
+
+fn parse_float(token: &str) -> Result<f64, String> {
+    token
+        .parse()
+        .map_err(|_| format!("Could not parse \"{}\" as a number", token))
+}
+
+// OBJ indices start at 1 and negative indices are relative to the end of the list
+fn resolve_index(token: Option<&str>, len: usize) -> Result<Option<usize>, String> {
+    let token = match token {
+        Some(t) if !t.is_empty() => t,
+        _ => return Ok(None),
+    };
+
+    let index = token
+        .parse::<i64>()
+        .map_err(|_| format!("Could not parse \"{}\" as an index", token))?;
+
+    let resolved = if index > 0 {
+        index - 1
+    } else {
+        len as i64 + index
+    };
+
+    if index == 0 || resolved < 0 || resolved >= len as i64 {
+        Err(format!("Index {} is out of range", index))
+    } else {
+        Ok(Some(resolved as usize))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(content: &str) -> Result<ObjData, String> {
+        let mut obj = ObjData::default();
+
+        for line in content.lines() {
+            obj.parse_line(line)?;
+        }
+
+        Ok(obj)
+    }
+
+    fn indices(vertex: ObjVertex) -> (usize, Option<usize>, Option<usize>) {
+        (vertex.position, vertex.uv, vertex.normal)
+    }
+
+    fn triangle(scale: f64) -> Triangle {
+        Triangle {
+            p0: Vec3::new(0.0, 0.0, 0.0),
+            e1: Vec3::new(scale, 0.0, 0.0),
+            e2: Vec3::new(0.0, scale, 0.0),
+            face_normal: Vec3Norm::new(0.0, 0.0, 1.0),
+            vertex_normals: None,
+            uvs: None,
+        }
+    }
+
+    #[test]
+    fn intersects_triangles_of_any_size() {
+        for &scale in &[1e-9, 1e-4, 1.0, 1e6] {
+            let triangle = triangle(scale);
+
+            let ray = Ray {
+                origin: Vec3::new(0.25 * scale, 0.5 * scale, -2.0 * scale),
+                direction: Vec3Norm::new(0.0, 0.0, 1.0),
+            };
+
+            let (t, b1, b2) = triangle.intersect(&ray).unwrap();
+            assert!((t / scale - 2.0).abs() < 1e-9, "{}: {}", scale, t);
+            assert!((b1 - 0.25).abs() < 1e-9 && (b2 - 0.5).abs() < 1e-9, "{}: {} {}", scale, b1, b2);
+
+            // Just outside of the hypotenuse
+            let miss = Ray {
+                origin: Vec3::new(0.5 * scale, 0.51 * scale, -2.0 * scale),
+                ..ray
+            };
+
+            assert!(triangle.intersect(&miss).is_none(), "{}", scale);
+        }
+    }
+
+    #[test]
+    fn parallel_rays_miss_triangles() {
+        let triangle = triangle(1.0);
+
+        for &z in &[0.0, 1e-12, 1.0] {
+            let ray = Ray {
+                origin: Vec3::new(-1.0, 0.25, z),
+                direction: Vec3Norm::new(1.0, 0.0, 0.0),
+            };
+
+            assert!(triangle.intersect(&ray).is_none(), "{}", z);
+        }
+
+        // Almost parallel, but still hitting the triangle far away from the origin
+        let ray = Ray {
+            origin: Vec3::new(0.25 - 1e6, 0.25, -1e-6),
+            direction: Vec3::new(1.0, 0.0, 1e-12).normalized(),
+        };
+
+        let (_, b1, b2) = triangle.intersect(&ray).unwrap();
+        assert!((0.0..=1.0).contains(&b1) && b2 >= 0.0 && b1 + b2 <= 1.0);
+    }
+
+    #[test]
+    fn parses_vertex_data() {
+        let obj = parse(
+            "# A comment\n\
+             v 1.0 2.0 3.0\n\
+             vn 0 1 0 # trailing comment\n\
+             vt 0.25 0.75\n\
+             vt 0.5\n\
+             g ignored group\n",
+        )
+        .unwrap();
+
+        assert_eq!(obj.positions.len(), 1);
+        assert_eq!(obj.positions[0].x, 1.0);
+        assert_eq!(obj.positions[0].z, 3.0);
+        assert_eq!(obj.normals.len(), 1);
+
+        // v defaults to 0 if it is missing
+        assert_eq!((obj.uvs[0].u, obj.uvs[0].v), (0.25, 0.75));
+        assert_eq!((obj.uvs[1].u, obj.uvs[1].v), (0.5, 0.0));
+    }
+
+    #[test]
+    fn parses_all_face_vertex_forms() {
+        let obj = parse(
+            "v 0 0 0\nv 1 0 0\nv 0 1 0\n\
+             vt 0 0\nvt 1 0\nvt 0 1\n\
+             vn 0 0 1\n\
+             f 1 2 3\n\
+             f 1/1 2/2 3/3\n\
+             f 1//1 2//1 3//1\n\
+             f 1/1/1 2/2/1 3/3/1\n",
+        )
+        .unwrap();
+
+        let faces = obj.faces.iter().map(|f| f.map(indices)).collect::<Vec<_>>();
+
+        assert_eq!(faces[0], [(0, None, None), (1, None, None), (2, None, None)]);
+        assert_eq!(faces[1], [(0, Some(0), None), (1, Some(1), None), (2, Some(2), None)]);
+        assert_eq!(faces[2], [(0, None, Some(0)), (1, None, Some(0)), (2, None, Some(0))]);
+        assert_eq!(faces[3], [(0, Some(0), Some(0)), (1, Some(1), Some(0)), (2, Some(2), Some(0))]);
+    }
+
+    #[test]
+    fn resolves_negative_indices() {
+        let obj = parse("v 0 0 0\nv 1 0 0\nv 0 1 0\nv 1 1 0\nf -3 -2 -1\n").unwrap();
+
+        assert_eq!(obj.faces[0].map(|v| v.position), [1, 2, 3]);
+    }
+
+    #[test]
+    fn triangulates_polygons() {
+        let obj = parse("v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nv 0 2 0\nf 1 2 3 4 5\n").unwrap();
+
+        let faces = obj.faces.iter().map(|f| f.map(|v| v.position)).collect::<Vec<_>>();
+
+        assert_eq!(faces, vec![[0, 1, 2], [0, 2, 3], [0, 3, 4]]);
+    }
+
+    #[test]
+    fn rejects_invalid_lines() {
+        assert!(parse("v 1.0 2.0\n").is_err());
+        assert!(parse("v 1.0 x 2.0\n").is_err());
+        assert!(parse("vt\n").is_err());
+        assert!(parse("vt 0.5 y\n").is_err());
+        assert!(parse("v 0 0 0\nv 1 0 0\nf 1 2\n").is_err());
+        assert!(parse("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 4\n").is_err());
+        assert!(parse("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 0 1 2\n").is_err());
+        assert!(parse("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1/1 2/1 3/1\n").is_err());
+    }
+
+    #[test]
+    fn rejects_non_positive_scales() {
+        for scale in [0.0, -1.0, f64::INFINITY] {
+            let mut diagnostics = Diagnostics::new();
+            MeshInit::default().with_scale(scale).validate(&mut diagnostics);
+
+            assert!(diagnostics.has_errors());
+        }
+
+        let mut diagnostics = Diagnostics::new();
+        MeshInit::default().with_scale(0.5).validate(&mut diagnostics);
+
+        assert!(diagnostics.is_empty());
+    }
+}
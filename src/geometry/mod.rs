@@ -2,8 +2,10 @@ mod sphere;
 mod infinite_plane;
 mod plane;
 mod cube;
+mod mesh;
 
 pub use sphere::{Sphere, SphereInit};
 pub use infinite_plane::{InifinitePlane, InfinitePlaneInit};
 pub use plane::{Plane, PlaneInit};
-pub use cube::{Cube, CubeInit};
\ No newline at end of file
+pub use cube::{Cube, CubeInit};
+pub use mesh::{Mesh, MeshInit};
\ No newline at end of file
@@ -1,4 +1,5 @@
 use super::InifinitePlane;
+use crate::bvh::Aabb;
 use crate::parser::{const_f64_one, const_true};
 use crate::ray_target::*;
 use crate::raytracing::*;
@@ -23,7 +24,7 @@ pub struct Plane {
     forwards: Vec3Norm,
 }
 
-#[derive(Default, Deserialize)]
+#[derive(Deserialize)]
 #[serde(default)]
 #[serde(deny_unknown_fields)]
 pub struct PlaneInit {
@@ -41,6 +42,42 @@ pub struct PlaneInit {
     pub visible_to_camera: bool,
 }
 
+impl PlaneInit {
+    /// Plane facing upwards. Width and height are HALF of the extents
+    pub fn new(origin: Vec3, width: f64, height: f64) -> PlaneInit {
+        PlaneInit {
+            origin,
+            width,
+            height,
+            ..PlaneInit::default()
+        }
+    }
+
+    pub fn with_rotation(self, rotation: Vec3) -> PlaneInit {
+        PlaneInit { rotation, ..self }
+    }
+
+    pub fn with_visible_to_camera(self, visible_to_camera: bool) -> PlaneInit {
+        PlaneInit {
+            visible_to_camera,
+            ..self
+        }
+    }
+}
+
+// Same defaults as in scene files
+impl Default for PlaneInit {
+    fn default() -> Self {
+        PlaneInit {
+            origin: Vec3::ZERO,
+            rotation: Vec3::ZERO,
+            width: 1.0,
+            height: 1.0,
+            visible_to_camera: true,
+        }
+    }
+}
+
 impl Plane {
     pub fn new(init: &PlaneInit, uv_mapper: Arc<dyn UvMapper>) -> Plane {
         let normal = Vec3Norm::UP.rotate(init.rotation);
@@ -95,6 +132,33 @@ impl RayTarget for Plane {
     fn is_visible_to_camera(&self) -> bool {
         self.visible_to_camera
     }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let right = self.right * self.width;
+        let forwards = self.forwards * self.height;
+
+        Some(Aabb::from_points(vec![
+            self.origin + right + forwards,
+            self.origin + right - forwards,
+            self.origin - right + forwards,
+            self.origin - right - forwards,
+        ]))
+    }
+
+    fn sample_surface(&self, u: f64, v: f64) -> Option<SurfaceSample> {
+        let position = self.origin
+            + self.right * (self.width * (2.0 * u - 1.0))
+            + self.forwards * (self.height * (2.0 * v - 1.0));
+
+        Some(SurfaceSample {
+            hit: GeometryHitInfo {
+                position,
+                normal: self.normal,
+                uv: Vec2::new(u, v),
+            },
+            area: 4.0 * self.width * self.height,
+        })
+    }
 }
 
 impl HasUvMapper for Plane {
@@ -2,6 +2,7 @@ use crate::vec::*;
 use crate::uv_mappers::*;
 use crate::raytracing::*;
 use crate::ray_target::*;
+use crate::bvh::Aabb;
 use crate::parser::{const_f64_one, const_true};
 use serde::Deserialize;
 use std::sync::Arc;
@@ -18,21 +19,54 @@ pub struct Sphere {
     visible_to_camera: bool
 }
 
-#[derive(Default, Deserialize)]
+#[derive(Deserialize)]
 #[serde(default)]
 #[serde(deny_unknown_fields)] 
 pub struct SphereInit {
 
-    origin: Vec3,
+    pub origin: Vec3,
 
     #[serde(default = "const_f64_one")]
-    radius: f64,
+    pub radius: f64,
 
-    rotation: Vec3,
+    /// Only affects how the uv mapper is applied
+    pub rotation: Vec3,
 
     #[serde(default = "const_true")]
     #[serde(rename = "visible-to-camera")]
-    visible_to_camera: bool
+    pub visible_to_camera: bool
+}
+
+impl SphereInit {
+
+    pub fn new(origin: Vec3, radius: f64) -> SphereInit {
+        SphereInit {
+            origin,
+            radius,
+            ..SphereInit::default()
+        }
+    }
+
+    pub fn with_rotation(self, rotation: Vec3) -> SphereInit {
+        SphereInit { rotation, ..self }
+    }
+
+    pub fn with_visible_to_camera(self, visible_to_camera: bool) -> SphereInit {
+        SphereInit { visible_to_camera, ..self }
+    }
+}
+
+// Same defaults as in scene files
+impl Default for SphereInit {
+
+    fn default() -> Self {
+        SphereInit {
+            origin: Vec3::ZERO,
+            radius: 1.0,
+            rotation: Vec3::ZERO,
+            visible_to_camera: true
+        }
+    }
 }
 
 impl Sphere {
@@ -58,6 +92,15 @@ impl RayTarget for Sphere where {
         self.visible_to_camera
     }
 
+    fn bounding_box(&self) -> Option<Aabb> {
+        let extent = Vec3::new(self.radius, self.radius, self.radius);
+
+        Some(Aabb {
+            min: self.origin - extent,
+            max: self.origin + extent
+        })
+    }
+
     fn test_intersection(&self, ray: &Ray) -> Option<GeometryHitInfo> {
         
         // Squared radius
@@ -100,6 +143,37 @@ impl RayTarget for Sphere where {
 
         let normal = ((hitpoint - self.origin) / self.radius).into_normalized_unsafe();
 
+        Option::Some(GeometryHitInfo {
+            position: hitpoint,
+            normal,
+            uv: self.uv_at(normal)
+        })
+    }
+
+    fn sample_surface(&self, u: f64, v: f64) -> Option<SurfaceSample> {
+
+        // Uniformly distributed point on the unit sphere
+        let y = 1.0 - 2.0 * u;
+        let r = (1.0 - y * y).max(0.0).sqrt();
+        let phi = 2.0 * std::f64::consts::PI * v;
+
+        let normal = Vec3Norm::new(r * phi.cos(), y, r * phi.sin());
+
+        Some(SurfaceSample {
+            hit: GeometryHitInfo {
+                position: self.origin + normal * self.radius,
+                normal,
+                uv: self.uv_at(normal)
+            },
+            area: 4.0 * std::f64::consts::PI * self.radius * self.radius
+        })
+    }
+}
+
+impl Sphere {
+
+    fn uv_at(&self, normal: Vec3Norm) -> Vec2 {
+
         let uv_x = normal
             .project_onto_plane_through_origin(self.up)
             .angle_to_on_plane(self.right, self.up, false)
@@ -111,11 +185,7 @@ impl RayTarget for Sphere where {
             .abs()
             / 180.0;
 
-        Option::Some(GeometryHitInfo {
-            position: hitpoint,
-            normal,
-            uv: Vec2::new(uv_x, uv_y)
-        })
+        Vec2::new(uv_x, uv_y)
     }
 }
 
@@ -0,0 +1,381 @@
+// Reading of Radiance RGBE (.hdr) images
+// Format description: http://paulbourke.net/dataformats/pic/
+
+use crate::color::RGBColor;
+use std::io;
+use std::path::Path;
+
+pub struct HdrImage {
+    pub width: usize,
+    pub height: usize,
+
+    /// Linear colors, row by row from the top left
+    pub pixels: Vec<RGBColor>,
+}
+
+pub fn read_hdr<P: AsRef<Path>>(path: P) -> Result<HdrImage, io::Error> {
+    let bytes = std::fs::read(path)?;
+
+    decode_hdr(&bytes).map_err(|msg| io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+fn decode_hdr(bytes: &[u8]) -> Result<HdrImage, String> {
+    let mut pos = 0;
+
+    let next_line = |pos: &mut usize| -> Result<String, String> {
+        let start = *pos;
+
+        while *pos < bytes.len() && bytes[*pos] != b'\n' {
+            *pos += 1;
+        }
+
+        if *pos >= bytes.len() {
+            return Err("Unexpected end of header".to_owned());
+        }
+
+        *pos += 1;
+
+        Ok(String::from_utf8_lossy(&bytes[start..*pos - 1]).into_owned())
+    };
+
+    let magic = next_line(&mut pos)?;
+
+    if !magic.starts_with("#?") {
+        return Err("Not a Radiance HDR file".to_owned());
+    }
+
+    // Header variables are terminated by an empty line
+    loop {
+        let line = next_line(&mut pos)?;
+
+        if line.is_empty() {
+            break;
+        }
+
+        if let Some(format) = line.strip_prefix("FORMAT=") {
+            if format != "32-bit_rle_rgbe" {
+                return Err(format!("Unsupported pixel format {}", format));
+            }
+        }
+    }
+
+    // We only support the standard orientation, which is what pretty much everybody writes
+    let resolution = next_line(&mut pos)?;
+    let parts = resolution.split_whitespace().collect::<Vec<_>>();
+
+    let (height, width) = match parts[..] {
+        ["-Y", h, "+X", w] => (
+            h.parse::<usize>().map_err(|_| "Invalid image height")?,
+            w.parse::<usize>().map_err(|_| "Invalid image width")?,
+        ),
+        _ => return Err(format!("Unsupported image orientation \"{}\"", resolution)),
+    };
+
+    if width == 0 || height == 0 {
+        return Err("Image must not be empty".to_owned());
+    }
+
+    let mut pixels = Vec::with_capacity(width * height);
+    let mut scanline = vec![[0u8; 4]; width];
+
+    for _ in 0..height {
+        pos = read_scanline(bytes, pos, &mut scanline)?;
+        pixels.extend(scanline.iter().map(|&rgbe| rgbe_to_color(rgbe)));
+    }
+
+    Ok(HdrImage {
+        width,
+        height,
+        pixels,
+    })
+}
+
+// Reads a single scanline starting at pos and returns the position after it
+fn read_scanline(bytes: &[u8], mut pos: usize, scanline: &mut [[u8; 4]]) -> Result<usize, String> {
+    let width = scanline.len();
+
+    let next = |pos: &mut usize| -> Result<u8, String> {
+        let b = *bytes.get(*pos).ok_or("Unexpected end of pixel data")?;
+        *pos += 1;
+        Ok(b)
+    };
+
+    let is_rle = (8..0x8000).contains(&width)
+        && bytes.get(pos) == Some(&2)
+        && bytes.get(pos + 1) == Some(&2)
+        && bytes.get(pos + 2).is_some_and(|&b| b & 0x80 == 0);
+
+    if !is_rle {
+        // Flat scanline (old run length encoding is not supported)
+        for pixel in scanline.iter_mut() {
+            for component in pixel.iter_mut() {
+                *component = next(&mut pos)?;
+            }
+        }
+
+        return Ok(pos);
+    }
+
+    let encoded_width = ((bytes[pos + 2] as usize) << 8) | bytes[pos + 3] as usize;
+    pos += 4;
+
+    if encoded_width != width {
+        return Err("Scanline width does not match image width".to_owned());
+    }
+
+    // Each of the four components is run length encoded separately
+    for component in 0..4 {
+        let mut x = 0;
+
+        while x < width {
+            let count = next(&mut pos)? as usize;
+
+            if count > 128 {
+                // Run of the same value
+                let count = count - 128;
+                let value = next(&mut pos)?;
+
+                if x + count > width {
+                    return Err("Run length exceeds scanline".to_owned());
+                }
+
+                for pixel in &mut scanline[x..x + count] {
+                    pixel[component] = value;
+                }
+
+                x += count;
+            } else {
+                // Literal values
+                if count == 0 || x + count > width {
+                    return Err("Invalid literal run in scanline".to_owned());
+                }
+
+                for pixel in &mut scanline[x..x + count] {
+                    pixel[component] = next(&mut pos)?;
+                }
+
+                x += count;
+            }
+        }
+    }
+
+    Ok(pos)
+}
+
+fn rgbe_to_color(rgbe: [u8; 4]) -> RGBColor {
+    if rgbe[3] == 0 {
+        return RGBColor::BLACK;
+    }
+
+    let f = 2f64.powi(rgbe[3] as i32 - (128 + 8));
+
+    RGBColor::new(
+        (rgbe[0] as f64 + 0.5) * f,
+        (rgbe[1] as f64 + 0.5) * f,
+        (rgbe[2] as f64 + 0.5) * f,
+    )
+}
+
+/// Writes linear colors, row by row from the top left. Negative values are clamped to 0.
+pub fn write_hdr<P: AsRef<Path>>(
+    path: P,
+    width: usize,
+    height: usize,
+    pixels: &[RGBColor],
+) -> Result<(), io::Error> {
+    std::fs::write(path, encode_hdr(width, height, pixels))
+}
+
+fn encode_hdr(width: usize, height: usize, pixels: &[RGBColor]) -> Vec<u8> {
+    assert!(width > 0 && height > 0 && pixels.len() == width * height);
+
+    let mut bytes = format!(
+        "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n",
+        height, width
+    )
+    .into_bytes();
+
+    for row in pixels.chunks(width) {
+        let scanline = row.iter().map(|&c| color_to_rgbe(c)).collect::<Vec<_>>();
+        write_scanline(&mut bytes, &scanline);
+    }
+
+    bytes
+}
+
+fn write_scanline(bytes: &mut Vec<u8>, scanline: &[[u8; 4]]) {
+    let width = scanline.len();
+
+    // Run length encoding is only allowed for these widths
+    if !(8..0x8000).contains(&width) {
+        bytes.extend(scanline.iter().flatten());
+        return;
+    }
+
+    bytes.extend_from_slice(&[2, 2, (width >> 8) as u8, (width & 0xff) as u8]);
+
+    for component in 0..4 {
+        let values = scanline.iter().map(|pixel| pixel[component]).collect::<Vec<_>>();
+        let mut x = 0;
+
+        while x < width {
+            let run = values[x..]
+                .iter()
+                .take(127)
+                .take_while(|&&v| v == values[x])
+                .count();
+
+            if run > 2 {
+                bytes.push(128 + run as u8);
+                bytes.push(values[x]);
+                x += run;
+            } else {
+                // Literals until the next run of at least 3 equal values
+                let start = x;
+
+                while x < width
+                    && x - start < 128
+                    && !(x + 2 < width && values[x] == values[x + 1] && values[x] == values[x + 2])
+                {
+                    x += 1;
+                }
+
+                bytes.push((x - start) as u8);
+                bytes.extend_from_slice(&values[start..x]);
+            }
+        }
+    }
+}
+
+fn color_to_rgbe(color: RGBColor) -> [u8; 4] {
+    let r = color.r.max(0.0);
+    let g = color.g.max(0.0);
+    let b = color.b.max(0.0);
+
+    let largest = r.max(g).max(b);
+
+    if largest < 1e-32 || !largest.is_finite() {
+        return [0, 0, 0, 0];
+    }
+
+    // largest = mantissa * 2^exponent with mantissa in [0.5, 1)
+    let mut exponent = largest.log2().floor() as i32 + 1;
+
+    if largest / 2f64.powi(exponent) >= 1.0 {
+        exponent += 1;
+    }
+
+    let exponent = exponent.clamp(-128, 127);
+    let scale = 256.0 / 2f64.powi(exponent);
+
+    [
+        (r * scale).min(255.0) as u8,
+        (g * scale).min(255.0) as u8,
+        (b * scale).min(255.0) as u8,
+        (exponent + 128) as u8,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip_scanline(scanline: &[[u8; 4]]) -> usize {
+        let mut bytes = Vec::new();
+        write_scanline(&mut bytes, scanline);
+
+        let mut decoded = vec![[0u8; 4]; scanline.len()];
+        assert_eq!(read_scanline(&bytes, 0, &mut decoded), Ok(bytes.len()));
+        assert_eq!(decoded, scanline);
+
+        bytes.len()
+    }
+
+    fn assert_close(a: RGBColor, b: RGBColor) {
+        // 8 bit mantissas relative to the largest component
+        let tolerance = a.r.max(a.g).max(a.b) / 128.0;
+
+        assert!((a.r - b.r).abs() <= tolerance, "{:?} != {:?}", a, b);
+        assert!((a.g - b.g).abs() <= tolerance, "{:?} != {:?}", a, b);
+        assert!((a.b - b.b).abs() <= tolerance, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn short_scanlines_are_flat() {
+        let scanline = [[1, 2, 3, 128], [4, 5, 6, 129], [4, 5, 6, 129]];
+        assert_eq!(round_trip_scanline(&scanline), 12);
+    }
+
+    #[test]
+    fn scanlines_with_runs_and_literals() {
+        let scanline = (0..300u32)
+            .map(|x| [(x / 50) as u8, (x * 7) as u8, if x % 3 == 0 { 1 } else { 2 }, 130])
+            .collect::<Vec<_>>();
+
+        round_trip_scanline(&scanline);
+
+        // A constant scanline compresses to a few runs per component
+        let flat = vec![[10, 20, 30, 128]; 1000];
+        assert!(round_trip_scanline(&flat) < 100);
+    }
+
+    #[test]
+    fn rejects_wrong_scanline_width() {
+        let mut bytes = Vec::new();
+        write_scanline(&mut bytes, &[[0, 0, 0, 0]; 16]);
+
+        let mut decoded = vec![[0u8; 4]; 17];
+        assert!(read_scanline(&bytes, 0, &mut decoded).is_err());
+    }
+
+    #[test]
+    fn rgbe_round_trip() {
+        let colors = [
+            RGBColor::new(1.0, 0.5, 0.25),
+            RGBColor::new(0.001, 0.002, 0.0005),
+            RGBColor::new(1000.0, 1.0, 0.0),
+            RGBColor::new(0.75, 0.75, 0.75),
+        ];
+
+        for &color in &colors {
+            assert_close(color, rgbe_to_color(color_to_rgbe(color)));
+        }
+    }
+
+    #[test]
+    fn rgbe_special_values() {
+        assert_eq!(color_to_rgbe(RGBColor::BLACK), [0, 0, 0, 0]);
+        assert_eq!(color_to_rgbe(RGBColor::new(-1.0, -2.0, 0.0)), [0, 0, 0, 0]);
+        assert_eq!(color_to_rgbe(RGBColor::new(f64::INFINITY, 0.0, 0.0)), [0, 0, 0, 0]);
+
+        let color = rgbe_to_color(color_to_rgbe(RGBColor::new(-1.0, 1.0, 0.0)));
+        assert!(color.r < 0.01 && color.b < 0.01);
+
+        // Mantissas are in [0.5, 1)
+        assert_eq!(color_to_rgbe(RGBColor::new(1.0, 0.0, 0.0)), [128, 0, 0, 129]);
+    }
+
+    #[test]
+    fn decodes_encoded_image() {
+        let (width, height) = (20, 3);
+        let pixels = (0..width * height)
+            .map(|i| RGBColor::new(i as f64 * 0.1, 1.0, if i % 2 == 0 { 0.0 } else { 5.0 }))
+            .collect::<Vec<_>>();
+
+        let image = decode_hdr(&encode_hdr(width, height, &pixels)).unwrap();
+
+        assert_eq!((image.width, image.height), (width, height));
+
+        for (&expected, &actual) in pixels.iter().zip(&image.pixels) {
+            assert_close(expected, actual);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_headers() {
+        assert!(decode_hdr(b"P6\n").is_err());
+        assert!(decode_hdr(b"#?RADIANCE\nFORMAT=32-bit_rle_xyze\n\n-Y 1 +X 1\n").is_err());
+        assert!(decode_hdr(b"#?RADIANCE\n\n+Y 1 +X 1\n").is_err());
+        assert!(decode_hdr(b"#?RADIANCE\n\n-Y 2 +X 1\n\x01\x02\x03\x80").is_err());
+    }
+}
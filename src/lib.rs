@@ -4,23 +4,38 @@ extern crate toml;
 extern crate rand;
 extern crate rayon;
 extern crate lodepng;
+extern crate flate2;
 
 mod parser;
+mod aov;
 mod camera;
 mod color;
+mod diagnostics;
+mod display;
+mod environment;
+mod exr;
+mod filter;
 mod geometry;
+mod light;
 mod material;
 mod output;
 mod post_processing;
+mod path_tracing;
 mod raytracing;
+mod sampler;
 mod scene;
+mod tiles;
 mod vec;
+mod hdr;
 mod render_params;
 mod ray_target;
+mod bvh;
 mod util;
 
 pub mod uv_mappers;
 pub mod prelude;
 
 pub use parser::{parse, Config};
-pub use raytracing::render;
\ No newline at end of file
+pub use diagnostics::{Diagnostic, Diagnostics, RaysError, RenderError, Severity, Source};
+pub use raytracing::{render, render_progressive, RenderProgress, RenderStats};
+pub use post_processing::post_process;
\ No newline at end of file
@@ -0,0 +1,360 @@
+use crate::color::RGBColor;
+use crate::diagnostics::Diagnostics;
+use crate::parser::const_f64_one;
+use crate::util;
+use crate::vec::*;
+use serde::Deserialize;
+
+#[derive(Debug, Copy, Clone)]
+pub enum Light {
+    Point(PointLight),
+    Directional(DirectionalLight),
+}
+
+/// Light that shines equally into all directions from a single point. Falls off with the squared distance.
+#[derive(Debug, Copy, Clone, Deserialize)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct PointLight {
+    pub position: Vec3,
+    pub color: RGBColor,
+
+    #[serde(default = "const_f64_one")]
+    pub intensity: f64,
+}
+
+/// Light that comes from infinitely far away, like the sun
+#[derive(Debug, Copy, Clone, Deserialize)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct DirectionalLight {
+    /// The direction that the light travels in (not the direction towards the light)
+    #[serde(default = "default_direction")]
+    pub direction: Vec3,
+    pub color: RGBColor,
+
+    #[serde(default = "const_f64_one")]
+    pub intensity: f64,
+}
+
+fn default_direction() -> Vec3 {
+    Vec3Norm::DOWN.into()
+}
+
+/// How a light illuminates a specific point in the scene
+pub struct LightSample {
+    /// Direction from the illuminated point towards the light
+    pub direction: Vec3Norm,
+
+    /// Distance to the light. Infinite for lights that are infinitely far away.
+    pub distance: f64,
+
+    /// Color and intensity of the light arriving at the point, before shadowing
+    pub color: RGBColor,
+}
+
+impl Light {
+    pub fn illuminate(&self, point: Vec3) -> LightSample {
+        match self {
+            Light::Point(light) => {
+                let to_light = light.position - point;
+                let distance = to_light.length();
+
+                // A point that is exactly at the light has no direction towards it and would
+                // receive infinite light, so it doesn't get any instead
+                if distance <= f64::EPSILON {
+                    return LightSample {
+                        direction: Vec3Norm::UP,
+                        distance: 0.0,
+                        color: RGBColor::BLACK,
+                    };
+                }
+
+                LightSample {
+                    direction: (to_light / distance).into_normalized_unsafe(),
+                    distance,
+                    color: light.color * (light.intensity / (distance * distance)),
+                }
+            }
+            Light::Directional(light) => LightSample {
+                direction: -light.direction.normalized(),
+                distance: f64::INFINITY,
+                color: light.color * light.intensity,
+            },
+        }
+    }
+
+    pub fn validate(&self, diagnostics: &mut Diagnostics) {
+        let (color, intensity) = match self {
+            Light::Point(light) => (light.color, light.intensity),
+            Light::Directional(light) => {
+                if light.direction.is_zero() {
+                    diagnostics.error("Direction of a directional light must not be zero");
+                }
+
+                (light.color, light.intensity)
+            }
+        };
+
+        if !util::is_in_range(intensity, 0.0, f64::INFINITY) {
+            diagnostics.error("Light intensity must be 0 or positive");
+        }
+
+        color.validate(diagnostics);
+    }
+}
+
+/// Probability density with respect to solid angle of a point that was picked uniformly on the
+/// surface of an area light, as seen from a point at the given distance. cos_light is the cosine
+/// between the light's normal and the direction towards the illuminated point
+pub fn area_light_pdf(area: f64, distance: f64, cos_light: f64) -> f64 {
+    distance * distance / (cos_light * area)
+}
+
+impl PointLight {
+    pub fn new(position: Vec3, color: RGBColor, intensity: f64) -> PointLight {
+        PointLight {
+            position,
+            color,
+            intensity,
+        }
+    }
+}
+
+impl DirectionalLight {
+    /// The direction is the one that the light travels in
+    pub fn new(direction: Vec3, color: RGBColor, intensity: f64) -> DirectionalLight {
+        DirectionalLight {
+            direction,
+            color,
+            intensity,
+        }
+    }
+}
+
+impl Default for PointLight {
+    fn default() -> Self {
+        PointLight {
+            position: Vec3::ZERO,
+            color: RGBColor::WHITE,
+            intensity: 1.0,
+        }
+    }
+}
+
+impl Default for DirectionalLight {
+    fn default() -> Self {
+        DirectionalLight {
+            direction: default_direction(),
+            color: RGBColor::WHITE,
+            intensity: 1.0,
+        }
+    }
+}
+
+impl From<PointLight> for Light {
+    fn from(light: PointLight) -> Light {
+        Light::Point(light)
+    }
+}
+
+impl From<DirectionalLight> for Light {
+    fn from(light: DirectionalLight) -> Light {
+        Light::Directional(light)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::{Plane, PlaneInit, Sphere, SphereInit};
+    use crate::material::{Emission, Material, Opacity};
+    use crate::ray_target::RayTarget;
+    use crate::raytracing::{self, HitInfo, Ray, RaytraceParameters};
+    use crate::render_params::RenderParams;
+    use crate::scene::Scene;
+    use crate::uv_mappers::StaticUvMapper;
+    use rand::prelude::*;
+    use std::f64::consts::PI;
+    use std::sync::Arc;
+
+    fn uv_mapper(material: Material) -> Arc<StaticUvMapper> {
+        Arc::new(StaticUvMapper(material))
+    }
+
+    fn sphere(origin: Vec3, radius: f64) -> Sphere {
+        Sphere::new(&SphereInit::new(origin, radius), uv_mapper(Material::default()))
+    }
+
+    fn assert_close(a: f64, b: f64, tolerance: f64) {
+        assert!((a - b).abs() <= tolerance, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn sphere_samples_cover_the_surface_uniformly() {
+        let origin = Vec3::new(1.0, 2.0, 3.0);
+        let sphere = sphere(origin, 2.0);
+        let mut rng = SmallRng::seed_from_u64(0);
+
+        let count = 20000;
+        let mut sum = Vec3::ZERO;
+        let mut upper = 0;
+
+        for _ in 0..count {
+            let sample = sphere.sample_surface(rng.gen(), rng.gen()).unwrap();
+            let offset = sample.hit.position - origin;
+
+            assert_close(sample.area, 16.0 * PI, 1e-9);
+            assert_close(offset.length(), 2.0, 1e-9);
+            assert_close(offset.normalized().dot(sample.hit.normal), 1.0, 1e-9);
+
+            sum += offset;
+            upper += (offset.y > 0.0) as usize;
+        }
+
+        assert!((sum / count as f64).length() < 0.05, "{:?}", sum / count as f64);
+        assert_close(upper as f64 / count as f64, 0.5, 0.02);
+    }
+
+    #[test]
+    fn plane_samples_cover_the_surface_uniformly() {
+        let origin = Vec3::new(0.0, 1.0, 0.0);
+        let plane = Plane::new(&PlaneInit::new(origin, 2.0, 0.5), uv_mapper(Material::default()));
+        let mut rng = SmallRng::seed_from_u64(0);
+
+        let count = 20000;
+        let mut sum = Vec3::ZERO;
+
+        for _ in 0..count {
+            let sample = plane.sample_surface(rng.gen(), rng.gen()).unwrap();
+            let offset = sample.hit.position - origin;
+
+            assert_close(sample.area, 4.0, 1e-12);
+            assert_close(offset.dot(sample.hit.normal), 0.0, 1e-9);
+            assert!(offset.x.abs().max(offset.z.abs()) <= 2.0 + 1e-9);
+
+            sum += offset;
+        }
+
+        assert!((sum / count as f64).length() < 0.02, "{:?}", sum / count as f64);
+    }
+
+    #[test]
+    fn area_light_pdf_integrates_to_the_solid_angle() {
+        // Averaging 1 / pdf over the visible samples estimates the solid angle of the sphere,
+        // which is the cap 2 pi (1 - cos) for a sphere of radius r at distance d
+        let (radius, distance) = (1.0, 3.0);
+        let sphere = sphere(Vec3::new(0.0, 0.0, distance), radius);
+        let mut rng = SmallRng::seed_from_u64(1);
+
+        let count = 100_000;
+        let mut sum = 0.0;
+
+        for _ in 0..count {
+            let sample = sphere.sample_surface(rng.gen(), rng.gen()).unwrap();
+            let to_light = sample.hit.position;
+            let cos_light = -sample.hit.normal.dot(to_light.normalized());
+
+            if cos_light > 0.0 {
+                sum += 1.0 / area_light_pdf(sample.area, to_light.length(), cos_light);
+            }
+        }
+
+        let expected = 2.0 * PI * (1.0 - (1.0 - (radius / distance).powi(2)).sqrt());
+        assert_close(sum / count as f64, expected, 0.01 * expected);
+
+        // A small patch that faces the point covers roughly area / distance² steradians
+        assert_close(area_light_pdf(0.01, 5.0, 1.0), 2500.0, 1e-9);
+        assert_close(area_light_pdf(0.01, 5.0, 0.5), 5000.0, 1e-9);
+    }
+
+    #[test]
+    fn shadows_are_tinted_by_transparent_occluders() {
+        let glass = Material::pure(RGBColor::new(1.0, 0.5, 0.25)).with_opacity(Opacity::new(0.5, 0.5, 1.0));
+
+        let mut scene = Scene::new();
+        scene.add(Sphere::new(&SphereInit::new(Vec3::new(0.0, 0.0, 5.0), 1.0), uv_mapper(glass)));
+        scene.add(Sphere::new(&SphereInit::new(Vec3::new(0.0, 0.0, -5.0), 1.0), uv_mapper(Material::default())));
+
+        let render_params = RenderParams::default();
+        let params = RaytraceParameters {
+            scene: &scene,
+            render_params: &render_params,
+        };
+
+        let transmittance = |direction: Vec3Norm, distance: f64| {
+            raytracing::shadow_transmittance(&params, Vec3::ZERO, direction, distance)
+        };
+
+        let forwards = Vec3Norm::new(0.0, 0.0, 1.0);
+        let backwards = Vec3Norm::new(0.0, 0.0, -1.0);
+
+        // Entering and leaving the glass sphere halve the light and tint it twice
+        let tinted = transmittance(forwards, 10.0);
+        assert_close(tinted.r, 0.25, 1e-9);
+        assert_close(tinted.g, 0.0625, 1e-9);
+        assert_close(tinted.b, 0.25 * 0.0625, 1e-9);
+
+        // Lights in front of the occluder aren't shadowed, opaque ones block everything
+        assert_close(transmittance(forwards, 3.0).g, 1.0, 0.0);
+        assert_close(transmittance(backwards, 10.0).g, 0.0, 0.0);
+        assert_close(transmittance(Vec3Norm::UP, f64::INFINITY).g, 1.0, 0.0);
+    }
+
+    #[test]
+    fn point_lights_dont_light_their_own_position() {
+        let light = Light::from(PointLight::new(Vec3::new(1.0, 2.0, 3.0), RGBColor::WHITE, 5.0));
+
+        let sample = light.illuminate(Vec3::new(1.0, 2.0, 3.0));
+        assert_eq!(sample.distance, 0.0);
+        assert_eq!((sample.color.r, sample.color.g, sample.color.b), (0.0, 0.0, 0.0));
+        assert_close(Vec3::from(sample.direction).length(), 1.0, 1e-12);
+
+        let sample = light.illuminate(Vec3::new(1.0, 0.0, 3.0));
+        assert_close(sample.color.g, 5.0 / 4.0, 1e-12);
+    }
+
+    #[test]
+    fn points_on_area_lights_get_finite_light() {
+        let lamp = Material::default().with_emission(Emission::new(RGBColor::WHITE, 10.0));
+
+        let mut scene = Scene::new();
+        scene.add(Sphere::new(&SphereInit::new(Vec3::ZERO, 1.0), uv_mapper(lamp)));
+        scene.add_light(PointLight::new(Vec3::new(0.0, 1.0, 0.0), RGBColor::WHITE, 1.0));
+
+        let mut render_params = RenderParams::default();
+        render_params.quality.bias = 0.0;
+
+        let params = RaytraceParameters {
+            scene: &scene,
+            render_params: &render_params,
+        };
+
+        // Shading points on the emissive sphere. Without a bias, the first one is exactly at the point light
+        let ray = Ray {
+            origin: Vec3::new(0.0, 5.0, 0.0),
+            direction: Vec3Norm::DOWN,
+        };
+        let mut rng = SmallRng::seed_from_u64(2);
+
+        for &(u, v) in &[(0.0, 0.0), (0.0, 0.5), (0.3, 0.7)] {
+            let hit = sphere(Vec3::ZERO, 1.0).sample_surface(u, v).unwrap().hit;
+            let mat = Material::default();
+
+            let hit_info = HitInfo {
+                mat: &mat,
+                hit: &hit,
+                ray: &ray,
+                bounces: 0,
+                intensity: 1.0,
+                transparent_background: false,
+            };
+
+            let (diffuse, specular) = raytracing::direct_lighting(&params, &mut rng, &hit_info);
+
+            for value in &[diffuse.r, diffuse.g, diffuse.b, specular.r, specular.g, specular.b] {
+                assert!(value.is_finite(), "{:?} at ({}, {})", diffuse, u, v);
+            }
+        }
+    }
+}
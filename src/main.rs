@@ -2,7 +2,10 @@ extern crate rays;
 
 use clap::{App, Arg};
 use rays::prelude::*;
-use std::time::Instant;
+use rays::{RaysError, RenderError, Source};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 fn main() {
     const ARG_CAMERA: &str = "camera";
@@ -13,13 +16,24 @@ fn main() {
     const ARG_HEIGHT: &str = "height";
     const ARG_SCENE: &str = "SCENE";
     const ARG_OUTPUT: &str = "OUTPUT";
+    const ARG_EXR_PIXEL_TYPE: &str = "exr-pixel-type";
+    const ARG_EXR_COMPRESSION: &str = "exr-compression";
+    const ARG_EXPOSURE: &str = "exposure";
+    const ARG_TONE_MAP: &str = "tone-map";
+    const ARG_SRGB: &str = "srgb";
+    const ARG_DITHER: &str = "dither";
+    const ARG_TRANSPARENT: &str = "transparent";
+    const ARG_THREADS: &str = "threads";
+    const ARG_SEED: &str = "seed";
+    const ARG_TIME_LIMIT: &str = "time-limit";
+    const ARG_SNAPSHOT_INTERVAL: &str = "snapshot-interval";
 
     const QUALITY_LEVELS: &[&str] = &["sketch", "low", "medium", "high", "ultra"];
 
     let cla = App::new("rays")
         .version(env!("CARGO_PKG_VERSION"))
         .author("Markus W. <markuswebel@gmail.com>")
-        .about("Renders a scene configuration file into a PNG file")
+        .about("Renders a scene configuration file into a PNG, PPM, HDR, PFM or EXR file")
         .arg(Arg::with_name(ARG_CAMERA)
             .short('c')
             .long(ARG_CAMERA)
@@ -55,42 +69,182 @@ fn main() {
             .long(ARG_HEIGHT)
             .takes_value(true)
             .help("Height of the output picture. If only width is supplied, this value is calculated from the camera aspect ratio"))
+        .arg(Arg::with_name(ARG_EXR_PIXEL_TYPE)
+            .long(ARG_EXR_PIXEL_TYPE)
+            .takes_value(true)
+            .possible_values(["half", "float"])
+            .default_value("half")
+            .help("Precision of the color channels when writing an EXR file"))
+        .arg(Arg::with_name(ARG_EXR_COMPRESSION)
+            .long(ARG_EXR_COMPRESSION)
+            .takes_value(true)
+            .possible_values(["none", "rle", "zip"])
+            .default_value("zip")
+            .help("Lossless compression method when writing an EXR file"))
+        .arg(Arg::with_name(ARG_EXPOSURE)
+            .long(ARG_EXPOSURE)
+            .takes_value(true)
+            .allow_hyphen_values(true)
+            .help("Exposure correction in stops for PNG and PPM output. Overrides display.exposure"))
+        .arg(Arg::with_name(ARG_TONE_MAP)
+            .long(ARG_TONE_MAP)
+            .takes_value(true)
+            .possible_values(["none", "reinhard", "filmic", "aces"])
+            .help("Tone mapping operator for PNG and PPM output. Overrides display.tone_map"))
+        .arg(Arg::with_name(ARG_SRGB)
+            .long(ARG_SRGB)
+            .help("Encode PNG and PPM output with the sRGB transfer function. Overrides display.srgb"))
+        .arg(Arg::with_name(ARG_DITHER)
+            .long(ARG_DITHER)
+            .takes_value(true)
+            .possible_values(["none", "ordered", "blue-noise"])
+            .help("Dithering that is applied when rounding to 8 bits. Overrides display.dither"))
+        .arg(Arg::with_name(ARG_TRANSPARENT)
+            .long(ARG_TRANSPARENT)
+            .help("Render the background transparent. PNG and EXR output get an alpha channel. \
+                Overrides transparent-background"))
+        .arg(Arg::with_name(ARG_THREADS)
+            .long(ARG_THREADS)
+            .takes_value(true)
+            .help("Number of render threads. Defaults to the number of logical CPU cores"))
+        .arg(Arg::with_name(ARG_SEED)
+            .long(ARG_SEED)
+            .takes_value(true)
+            .help("Start value for all random numbers. Overrides seed"))
+        .arg(Arg::with_name(ARG_TIME_LIMIT)
+            .long(ARG_TIME_LIMIT)
+            .takes_value(true)
+            .help("Render progressively and stop after this many seconds. Overrides progressive.time_limit"))
+        .arg(Arg::with_name(ARG_SNAPSHOT_INTERVAL)
+            .long(ARG_SNAPSHOT_INTERVAL)
+            .takes_value(true)
+            .help("Render progressively and write the current image to the output file every \
+                this many seconds. Overrides progressive.snapshot_interval"))
         .arg(Arg::with_name(ARG_SCENE)
             .required(true)
             .help("A scene configuration file in the TOML format"))
         .arg(Arg::with_name(ARG_OUTPUT)
-            .help("Path the the PNG output file"))
+            .help("Path the the output file. The format is chosen based on the extension \
+            (.png, .ppm, .hdr, .pfm or .exr). PNG and PPM files go through the display transform"))
         .get_matches();
 
-    let config = rays::parse(cla.value_of(ARG_SCENE).unwrap())
-        .expect("Error parsing config. Please fix and try again.");
+    if let Some(threads) = cla.value_of(ARG_THREADS) {
+        let threads = threads
+            .parse::<usize>()
+            .expect("Could not parse thread count as a positive integer");
+
+        if threads == 0 {
+            panic!("Thread count must be at least 1");
+        }
+
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+            .expect("Could not create the render threads");
+    }
+
+    let config = match rays::parse(cla.value_of(ARG_SCENE).unwrap()) {
+        Ok(config) => config,
+        Err(RaysError::Scene {
+            sources,
+            diagnostics,
+        }) => exit_with_errors(&diagnostics, &sources),
+        Err(err) => {
+            eprintln!("error: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    for warning in &config.warnings {
+        eprintln!("{}", format_diagnostic(warning, &config.sources));
+    }
 
     let camera = extract_camera(cla.value_of(ARG_CAMERA), config.camera_config);
 
-    let render_params = extract_render_params(
+    let mut render_params = extract_render_params(
         cla.value_of(ARG_RENDERPARAMS),
         cla.value_of(ARG_QUALITY_HINT),
         cla.value_of(ARG_QUALITY_OVERRIDE),
         config.render_params_config,
     );
 
-    let (width, height) = extract_rt_dimensions(
-        cla.value_of(ARG_WIDTH),
-        cla.value_of(ARG_HEIGHT),
-        camera.viewport.aspect(),
-    );
+    if let Some(exposure) = cla.value_of(ARG_EXPOSURE) {
+        render_params.display.exposure = exposure
+            .parse()
+            .expect("Could not parse exposure as a number");
+    }
 
-    let mut render_target = RenderTarget::new(width, height);
+    if let Some(tone_map) = cla.value_of(ARG_TONE_MAP) {
+        render_params.display.tone_map = match tone_map {
+            "reinhard" => ToneMap::Reinhard,
+            "filmic" => ToneMap::Filmic,
+            "aces" => ToneMap::Aces,
+            _ => ToneMap::None,
+        };
+    }
 
-    let before = Instant::now();
+    if cla.is_present(ARG_SRGB) {
+        render_params.display.srgb = true;
+    }
 
-    rays::render(&config.scene, &camera, &mut render_target, &render_params);
+    if let Some(dither) = cla.value_of(ARG_DITHER) {
+        render_params.display.dither = match dither {
+            "ordered" => Dither::Ordered,
+            "blue-noise" => Dither::BlueNoise,
+            _ => Dither::None,
+        };
+    }
 
-    let elapsed = before.elapsed();
-    println!(
-        "Finished in {}.{} s",
-        elapsed.as_secs(),
-        elapsed.subsec_millis()
+    if let Some(seed) = cla.value_of(ARG_SEED) {
+        render_params.seed = seed
+            .parse()
+            .expect("Could not parse seed as a positive integer");
+    }
+
+    if cla.is_present(ARG_TRANSPARENT) {
+        render_params.transparent_background = true;
+    }
+
+    if let Some(time_limit) = cla.value_of(ARG_TIME_LIMIT) {
+        render_params.progressive.enabled = true;
+        render_params.progressive.time_limit = time_limit
+            .parse()
+            .expect("Could not parse time limit as a number of seconds");
+    }
+
+    if let Some(interval) = cla.value_of(ARG_SNAPSHOT_INTERVAL) {
+        render_params.progressive.enabled = true;
+        render_params.progressive.snapshot_interval = interval
+            .parse()
+            .expect("Could not parse snapshot interval as a number of seconds");
+    }
+
+    // The scene file was already checked while parsing, but the quality presets and the
+    // command line can still produce invalid settings. Warnings that the scene file caused
+    // were reported above, so only the new ones are shown
+    let mut diagnostics = Diagnostics::new();
+    render_params.validate(&mut diagnostics);
+    render_params.validate_for_camera(&camera, &mut diagnostics);
+
+    let (errors, warnings): (Vec<_>, Vec<_>) = diagnostics
+        .into_vec()
+        .into_iter()
+        .partition(|d| d.severity == Severity::Error);
+
+    for warning in &warnings {
+        if !config.warnings.iter().any(|w| w.message == warning.message) {
+            eprintln!("{}", format_diagnostic(warning, &[]));
+        }
+    }
+
+    if !errors.is_empty() {
+        exit_with_errors(&errors, &[]);
+    }
+
+    let (width, height) = extract_rt_dimensions(
+        cla.value_of(ARG_WIDTH),
+        cla.value_of(ARG_HEIGHT),
+        camera.aspect(),
     );
 
     let output_path = if let Some(path) = cla.value_of(ARG_OUTPUT) {
@@ -105,10 +259,237 @@ fn main() {
             + ".png"
     };
 
-    render_target.save_as_png(&output_path).expect(&format!(
-        "Could not write to output file ({})",
-        &output_path
-    ));
+    let extension = std::path::Path::new(&output_path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or("")
+        .to_lowercase();
+
+    let save = |target: &RenderTarget, path: &str, display: &DisplayParameters| {
+        let result = match extension.as_str() {
+            "png" => target.save_as_png(path, display),
+            "ppm" => target.save_as_ppm(path, display),
+            "hdr" => target.save_as_hdr(path),
+            "pfm" => target.save_as_pfm(path),
+            "exr" => {
+                let pixel_type = match cla.value_of(ARG_EXR_PIXEL_TYPE) {
+                    Some("float") => ExrPixelType::Float,
+                    _ => ExrPixelType::Half,
+                };
+
+                let compression = match cla.value_of(ARG_EXR_COMPRESSION) {
+                    Some("none") => ExrCompression::None,
+                    Some("rle") => ExrCompression::Rle,
+                    _ => ExrCompression::Zip,
+                };
+
+                target.save_as_exr(path, pixel_type, compression)
+            }
+            _ => panic!(
+                "Unsupported output format \"{}\". Please use .png, .ppm, .hdr, .pfm or .exr",
+                extension
+            ),
+        };
+
+        result.unwrap_or_else(|_| panic!("Could not write to output file ({})", path));
+    };
+
+    let mut render_target = match RenderTarget::new(width, height) {
+        Ok(target) => target,
+        Err(RenderError::Invalid(diagnostics)) => exit_with_errors(&diagnostics, &[]),
+    };
+
+    // The first Ctrl-C ends a progressive render after the current tiles, so the image
+    // is still saved. A second one quits immediately
+    let stop = Arc::new(AtomicBool::new(false));
+
+    if render_params.progressive.enabled {
+        let stop = Arc::clone(&stop);
+
+        ctrlc::set_handler(move || {
+            if stop.swap(true, Ordering::Relaxed) {
+                std::process::exit(130);
+            }
+
+            println!("Stopping... Press Ctrl-C again to quit without saving");
+        })
+        .expect("Could not install the Ctrl-C handler");
+    }
+
+    let post_effects = &config.post_effects;
+    let snapshot_interval = Duration::from_secs_f64(render_params.progressive.snapshot_interval);
+    let mut last_snapshot = Instant::now();
+
+    let before = Instant::now();
+
+    let result = rays::render_progressive(
+        &config.scene,
+        &camera,
+        &mut render_target,
+        &render_params,
+        &stop,
+        |target, progress| {
+            if !render_params.progressive.enabled {
+                return;
+            }
+
+            if progress.end_of_pass {
+                println!(
+                    "Pass {}: {} samples per pixel after {:.1} s",
+                    progress.pass,
+                    progress.samples,
+                    progress.elapsed.as_secs_f64()
+                );
+            }
+
+            if !snapshot_interval.is_zero() && last_snapshot.elapsed() >= snapshot_interval {
+                let mut snapshot = target.clone();
+                rays::post_process(&mut snapshot, post_effects)
+                    .expect("Post effects were checked while parsing");
+                save(&snapshot, &output_path, &render_params.display);
+
+                last_snapshot = Instant::now();
+            }
+        },
+    );
+
+
+    // Warnings about the scene and settings were already shown
+    if let Err(RenderError::Invalid(diagnostics)) = result {
+        exit_with_errors(&diagnostics, &[]);
+    }
+
+    if let Err(RenderError::Invalid(diagnostics)) =
+        rays::post_process(&mut render_target, post_effects)
+    {
+        exit_with_errors(&diagnostics, &[]);
+    }
+
+    let elapsed = before.elapsed();
+    println!(
+        "Finished in {}.{} s",
+        elapsed.as_secs(),
+        elapsed.subsec_millis()
+    );
+
+    if render_params.transparent_background && extension != "png" && extension != "exr" {
+        println!(
+            "Warning: .{} files can't store an alpha channel, so the background will be black",
+            extension
+        );
+    }
+
+    save(&render_target, &output_path, &render_params.display);
+
+    // EXR files contain the AOVs as layers, all other formats get one file per AOV
+    if extension != "exr" {
+        let is_8_bit = extension == "png" || extension == "ppm";
+
+        for aov in render_target.aovs() {
+            let aov_target = render_target.aov_as_render_target(aov, is_8_bit).unwrap();
+
+            // Data AOVs must not be tone mapped
+            let display = if aov.is_radiance() {
+                render_params.display
+            } else {
+                DisplayParameters::default()
+            };
+
+            save(&aov_target, &aov_output_path(&output_path, aov), &display);
+        }
+    }
+}
+
+// Prints all diagnostics and ends the program
+fn exit_with_errors(diagnostics: &[Diagnostic], sources: &[Source]) -> ! {
+    for diagnostic in diagnostics {
+        eprintln!("{}", format_diagnostic(diagnostic, sources));
+    }
+
+    let errors = diagnostics
+        .iter()
+        .filter(|d| d.severity == Severity::Error)
+        .count();
+
+    eprintln!(
+        "error: Aborting because of {} error{}",
+        errors,
+        if errors == 1 { "" } else { "s" }
+    );
+
+    std::process::exit(1);
+}
+
+// Formats a problem like rustc does: With the line of the scene file that it belongs to and
+// the problematic part underlined. Only the first line of longer spans is shown
+fn format_diagnostic(diagnostic: &Diagnostic, sources: &[Source]) -> String {
+    let severity = match diagnostic.severity {
+        Severity::Error => "error",
+        Severity::Warning => "warning",
+    };
+
+    let mut out = format!("{}: {}\n", severity, diagnostic.message);
+
+    let (source, span) = match (sources.get(diagnostic.file), &diagnostic.span) {
+        (Some(source), Some(span)) => (source, span),
+        _ => return out,
+    };
+
+    let content = &source.content;
+    let start = floor_char_boundary(content, span.start);
+
+    let line_start = content[..start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = content[start..]
+        .find('\n')
+        .map_or(content.len(), |i| start + i);
+    let end = floor_char_boundary(content, span.end.clamp(start, line_end));
+
+    let line_number = content[..start].matches('\n').count() + 1;
+    let column = content[line_start..start].chars().count() + 1;
+    let line = content[line_start..line_end].trim_end_matches('\r');
+
+    let gutter = " ".repeat(line_number.to_string().len());
+    let underline = "^".repeat(content[start..end].chars().count().max(1));
+
+    out += &format!(
+        "{}--> {}:{}:{}\n",
+        gutter,
+        source.path.display(),
+        line_number,
+        column
+    );
+    out += &format!("{} |\n", gutter);
+    out += &format!("{} | {}\n", line_number, line);
+    out += &format!("{} | {}{}\n", gutter, " ".repeat(column - 1), underline);
+
+    out
+}
+
+fn floor_char_boundary(s: &str, index: usize) -> usize {
+    let mut index = index.min(s.len());
+
+    while !s.is_char_boundary(index) {
+        index -= 1;
+    }
+
+    index
+}
+
+// image.png -> image.depth.png
+fn aov_output_path(output_path: &str, aov: Aov) -> String {
+    let path = std::path::Path::new(output_path);
+
+    let file_name = match (path.file_stem(), path.extension()) {
+        (Some(stem), Some(extension)) => format!(
+            "{}.{}.{}",
+            stem.to_string_lossy(),
+            aov.name(),
+            extension.to_string_lossy()
+        ),
+        _ => format!("{}.{}", output_path, aov.name()),
+    };
+
+    path.with_file_name(file_name).to_string_lossy().into_owned()
 }
 
 fn extract_rt_dimensions(
@@ -157,13 +538,12 @@ fn extract_rt_dimensions(
 
 fn extract_camera(cla_cam: Option<&str>, cam_cfg: CameraConfig) -> Camera {
     if let Some(cam_name) = cla_cam {
-        if let CameraConfig::Multiple(cam_map) = cam_cfg {
+        if let CameraConfig::Multiple(mut cam_map) = cam_cfg {
             cam_map
-                .get(cam_name)
+                .remove(cam_name)
                 .expect(
                     "Provided camera name did not correspond to any camera name in scene config",
                 )
-                .clone()
         } else {
             panic!("Not allowed to provide camera name when there are less than 2 cameras in the scene config")
         }
@@ -222,3 +602,71 @@ fn get_quality_from_cla(quality_name: &str) -> RenderParams {
         _ => unreachable!(), // Unreachable because clap should catch illegal variants early
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(content: &str) -> Source {
+        Source {
+            path: "scene.toml".into(),
+            content: content.to_owned(),
+        }
+    }
+
+    fn diagnostic(span: Option<std::ops::Range<usize>>) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            message: "Material \"nope\" not found".to_owned(),
+            span,
+            file: 0,
+        }
+    }
+
+    #[test]
+    fn diagnostics_show_the_line_they_point_at() {
+        let content = "[[material]]\nname = \"a\"\nextends = \"nope\"\n";
+        let start = content.find("\"nope\"").unwrap();
+
+        assert_eq!(
+            format_diagnostic(&diagnostic(Some(start..start + 6)), &[source(content)]),
+            "error: Material \"nope\" not found\n \
+             --> scene.toml:3:11\n  \
+             |\n\
+             3 | extends = \"nope\"\n  \
+             |           ^^^^^^\n"
+        );
+    }
+
+    #[test]
+    fn diagnostics_count_columns_in_characters() {
+        // Spans that cover several lines are only underlined up to the end of the first one
+        let content = "# Ünïcode\r\ncolor = [1.0,\n  2.0]";
+        let start = content.find('[').unwrap();
+
+        let formatted = format_diagnostic(&diagnostic(Some(start..content.len())), &[source(content)]);
+        let lines = formatted.lines().collect::<Vec<_>>();
+
+        assert_eq!(lines[1], " --> scene.toml:2:9");
+        assert_eq!(lines[3], "2 | color = [1.0,");
+        assert_eq!(lines[4], "  |         ^^^^^");
+
+        let formatted = format_diagnostic(&diagnostic(Some(2..4)), &[source(content)]);
+        assert_eq!(formatted.lines().nth(3), Some("1 | # Ünïcode"));
+        assert_eq!(formatted.lines().nth(4), Some("  |   ^"));
+    }
+
+    #[test]
+    fn diagnostics_without_span_are_a_single_line() {
+        assert_eq!(
+            format_diagnostic(&diagnostic(None), &[source("")]),
+            "error: Material \"nope\" not found\n"
+        );
+
+        // Render settings from the command line have no source at all
+        assert_eq!(
+            format_diagnostic(&diagnostic(Some(0..3)), &[]),
+            "error: Material \"nope\" not found\n"
+        );
+    }
+}
@@ -1,5 +1,7 @@
 use crate::color::*;
+use crate::diagnostics::Diagnostics;
 use crate::util;
+use crate::util::generate_optional_variant;
 use serde::Deserialize;
 
 // TODO: Supply useful default values for all these things
@@ -12,18 +14,33 @@ pub struct Material {
     pub opacity: Opacity,
     pub reflection: Reflection,
     pub refraction: Refraction,
+    pub specular: Specular,
+    pub emission: Emission,
 }
 
-#[derive(Debug, Copy, Clone, Deserialize)]
-#[serde(deny_unknown_fields)] 
+/// Material where every value is optional, so that it can extend another material.
+/// Nested tables like reflection are merged value by value
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct MaterialOverride {
+    pub color: Option<RGBColor>,
+    pub opacity: override_structs::Opacity,
+    pub reflection: override_structs::Reflection,
+    pub refraction: override_structs::Refraction,
+    pub specular: override_structs::Specular,
+    pub emission: override_structs::Emission,
+}
+
+generate_optional_variant!(
+#[derive(Copy)]
 pub struct Opacity {
     pub center: f64,
     pub edges: f64,
     pub power: f64
 }
 
-#[derive(Debug, Copy, Clone, Deserialize)]
-#[serde(deny_unknown_fields)] 
+#[derive(Copy)]
 pub struct Reflection {
     pub center: f64,
     pub edges: f64,
@@ -32,14 +49,29 @@ pub struct Reflection {
     pub color: Option<RGBColor>
 }
 
-#[derive(Debug, Copy, Clone, Deserialize)]
-#[serde(deny_unknown_fields)] 
+#[derive(Copy)]
 pub struct Refraction {
     pub ior: f64,
     pub max_angle: f64,
     pub color: Option<RGBColor>
 }
 
+/// Light that is emitted by the material itself. Emissive spheres and
+/// planes also act as area lights that illuminate other objects.
+#[derive(Copy)]
+pub struct Emission {
+    pub color: RGBColor,
+    pub strength: f64
+}
+
+/// Highlights caused by light sources. Only visible if the scene contains lights.
+#[derive(Copy)]
+pub struct Specular {
+    pub strength: f64,
+    pub exponent: f64
+}
+);
+
 impl Material {
 
     pub fn new(color: RGBColor, opacity: Opacity, reflection: Reflection, refraction: Refraction) -> Material {
@@ -47,7 +79,9 @@ impl Material {
             color,
             opacity,
             reflection,
-            refraction
+            refraction,
+            specular: Specular::default(),
+            emission: Emission::default()
         }
     }
 
@@ -56,7 +90,9 @@ impl Material {
             color,
             opacity: Opacity::default(),
             reflection,
-            refraction: Refraction::default()
+            refraction: Refraction::default(),
+            specular: Specular::default(),
+            emission: Emission::default()
         }
     }
 
@@ -65,32 +101,155 @@ impl Material {
             color,
             opacity: Opacity::default(),
             reflection: Reflection::default(),
-            refraction: Refraction::default()
+            refraction: Refraction::default(),
+            specular: Specular::default(),
+            emission: Emission::default()
         }
     }
 
-    pub fn validate(&self) -> bool {
-        
-        let mut success = true;
+    /// Opaque material that glows in the supplied color
+    pub fn emissive(color: RGBColor, strength: f64) -> Material {
+        Material {
+            emission: Emission::new(color, strength),
+            ..Material::pure(color)
+        }
+    }
+
+    pub fn with_opacity(self, opacity: Opacity) -> Material {
+        Material { opacity, ..self }
+    }
+
+    pub fn with_reflection(self, reflection: Reflection) -> Material {
+        Material { reflection, ..self }
+    }
+
+    pub fn with_refraction(self, refraction: Refraction) -> Material {
+        Material { refraction, ..self }
+    }
+
+    pub fn with_specular(self, specular: Specular) -> Material {
+        Material { specular, ..self }
+    }
+
+    pub fn with_emission(self, emission: Emission) -> Material {
+        Material { emission, ..self }
+    }
+
+    /// Radiance emitted by the material
+    pub fn emitted(&self) -> RGBColor {
+        self.emission.color * self.emission.strength
+    }
+
+    pub fn is_emissive(&self) -> bool {
+        self.emission.strength > 0.0
+    }
+
+    pub fn validate(&self, diagnostics: &mut Diagnostics) {
         
-        success = success && self.color.validate();
+        self.color.validate(diagnostics);
 
         if  !util::is_in_range(self.opacity.center, 0.0, 1.0) ||
             !util::is_in_range(self.opacity.edges, 0.0, 1.0) {
-            println!("Warning: Opacity out of usual range 0-1. This can be desired, but might look really weird.");
+            diagnostics.warning("Opacity out of usual range 0-1. This can be desired, but might look really weird.");
+        }
+
+        if !util::is_in_range(self.opacity.power, 0.0, f64::INFINITY) {
+            diagnostics.error("Opacity edge effect power must be 0 or positive");
+        }
+
+        if !util::is_in_range(self.reflection.power, 0.0, f64::INFINITY) {
+            diagnostics.error("Reflectivity edge effect power must be 0 or positive");
+        }
+
+        if !util::is_in_range(self.specular.strength, 0.0, f64::INFINITY) ||
+            !util::is_in_range(self.specular.exponent, 0.0, f64::INFINITY) {
+            diagnostics.error("Specular strength and exponent must be 0 or positive");
         }
 
-        if !util::is_in_range(self.opacity.power, 0.0, std::f64::INFINITY) {
-            println!("Error: Opacity edge effect power must be 0 or positive");
-            success = false;
+        if !util::is_in_range(self.emission.strength, 0.0, f64::INFINITY) {
+            diagnostics.error("Emission strength must be 0 or positive");
         }
 
-        if !util::is_in_range(self.reflection.power, 0.0, std::f64::INFINITY) {
-            println!("Error: Reflectivity edge effect power must be 0 or positive");
-            success = false;
+        self.emission.color.validate(diagnostics);
+    }
+}
+
+impl Material {
+
+    /// Replaces every value that is set in the override
+    pub fn override_with(mut self, or: &MaterialOverride) -> Material {
+        if let Some(color) = or.color {
+            self.color = color;
         }
 
-        success
+        self.opacity.override_with(&or.opacity);
+        self.reflection.override_with(&or.reflection);
+        self.refraction.override_with(&or.refraction);
+        self.specular.override_with(&or.specular);
+        self.emission.override_with(&or.emission);
+
+        self
+    }
+}
+
+impl MaterialOverride {
+
+    /// Values that are not set here are taken from the parent
+    pub fn inherit_from(&mut self, parent: &MaterialOverride) {
+        self.color = self.color.or(parent.color);
+
+        self.opacity.inherit_from(&parent.opacity);
+        self.reflection.inherit_from(&parent.reflection);
+        self.refraction.inherit_from(&parent.refraction);
+        self.specular.inherit_from(&parent.specular);
+        self.emission.inherit_from(&parent.emission);
+    }
+
+    /// Reports tables that only contain some of their values. This is checked after everything
+    /// that the material extends has been merged in, only the parents can fill in the rest
+    pub fn validate(&self, diagnostics: &mut Diagnostics) {
+        let tables = [
+            ("opacity", vec![
+                ("center", self.opacity.center.is_some()),
+                ("edges", self.opacity.edges.is_some()),
+                ("power", self.opacity.power.is_some()),
+            ]),
+            ("reflection", vec![
+                ("center", self.reflection.center.is_some()),
+                ("edges", self.reflection.edges.is_some()),
+                ("power", self.reflection.power.is_some()),
+                ("max_angle", self.reflection.max_angle.is_some()),
+            ]),
+            ("refraction", vec![
+                ("ior", self.refraction.ior.is_some()),
+                ("max_angle", self.refraction.max_angle.is_some()),
+            ]),
+            ("specular", vec![
+                ("strength", self.specular.strength.is_some()),
+                ("exponent", self.specular.exponent.is_some()),
+            ]),
+            ("emission", vec![
+                ("color", self.emission.color.is_some()),
+                ("strength", self.emission.strength.is_some()),
+            ]),
+        ];
+
+        // A table that is missing completely takes its defaults
+        for (table, values) in &tables {
+            let missing = values
+                .iter()
+                .filter(|(_, is_set)| !is_set)
+                .map(|(name, _)| *name)
+                .collect::<Vec<_>>();
+
+            if !missing.is_empty() && missing.len() < values.len() {
+                diagnostics.error(format!(
+                    "Material {} is missing {}. Only a parent material can fill in the rest of a table",
+                    table,
+                    missing.join(", ")
+                ));
+            }
+        }
     }
 }
 
@@ -149,3 +308,37 @@ impl Default for Refraction {
         }
     }
 }
+
+impl Specular {
+
+    pub fn new(strength: f64, exponent: f64) -> Specular {
+        Specular { strength, exponent }
+    }
+}
+
+impl Default for Specular {
+
+    fn default() -> Self {
+        Specular {
+            strength: 0.0,
+            exponent: 50.0
+        }
+    }
+}
+
+impl Emission {
+
+    pub fn new(color: RGBColor, strength: f64) -> Emission {
+        Emission { color, strength }
+    }
+}
+
+impl Default for Emission {
+
+    fn default() -> Self {
+        Emission {
+            color: RGBColor::WHITE,
+            strength: 0.0
+        }
+    }
+}
@@ -1,22 +1,31 @@
-// TODO: Support other formats and alpha
-
+use super::aov::Aov;
 use super::color::*;
+use super::diagnostics::{Diagnostics, RenderError};
+use super::exr::{self, ExrCompression, ExrPixelType};
+use super::hdr;
+use super::render_params::DisplayParameters;
 use std::path::Path;
 
 use std::fs;
 use std::io;
 
+#[derive(Clone)]
 pub struct RenderTarget {
     pub width: usize,
     pub height: usize,
     data: Vec<RGBColor>,
+
+    // Coverage of every pixel, only present for renders with a transparent background.
+    // Colors are premultiplied by it
+    alpha: Option<Vec<f64>>,
+
+    // Extra buffers that were requested in the render params
+    aovs: Vec<(Aov, Vec<RGBColor>)>,
 }
 
 impl RenderTarget {
     // Creates a new rendertarget that is cleared to black
-    pub fn new(width: usize, height: usize) -> RenderTarget {
-        assert!(width > 0 && height > 0);
-
+    pub fn new(width: usize, height: usize) -> Result<RenderTarget, RenderError> {
         Self::with_clear_color(
             width,
             height,
@@ -28,21 +37,111 @@ impl RenderTarget {
         )
     }
 
-    pub fn with_clear_color(width: usize, height: usize, clear_color: &RGBColor) -> RenderTarget {
-        assert!(width > 0 && height > 0);
+    pub fn with_clear_color(
+        width: usize,
+        height: usize,
+        clear_color: &RGBColor,
+    ) -> Result<RenderTarget, RenderError> {
+        if width == 0 || height == 0 {
+            let mut diagnostics = Diagnostics::new();
+            diagnostics.error(format!("The image must be at least 1x1 pixels, not {}x{}", width, height));
 
-        RenderTarget {
+            return Err(RenderError::Invalid(diagnostics.into_vec()));
+        }
+
+        Ok(RenderTarget {
             width,
             height,
             data: vec![*clear_color; width * height],
-        }
+            alpha: None,
+            aovs: vec![],
+        })
     }
 
     pub fn set_pixel(&mut self, x: usize, y: usize, color: RGBColor) {
         self.data[x + y * self.width] = color;
     }
 
-    pub fn save_as_ppm<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+    pub fn pixels(&self) -> &[RGBColor] {
+        &self.data
+    }
+
+    pub fn pixels_mut(&mut self) -> &mut [RGBColor] {
+        &mut self.data
+    }
+
+    /// Adds an alpha channel that is cleared to fully opaque. Does nothing if it
+    /// already exists
+    pub fn add_alpha(&mut self) {
+        if self.alpha.is_none() {
+            self.alpha = Some(vec![1.0; self.width * self.height]);
+        }
+    }
+
+    pub fn alpha(&self) -> Option<&[f64]> {
+        self.alpha.as_deref()
+    }
+
+    /// Panics if the alpha channel wasn't added before
+    pub fn set_alpha(&mut self, x: usize, y: usize, alpha: f64) {
+        let width = self.width;
+        let buffer = self
+            .alpha
+            .as_mut()
+            .expect("Alpha channel was not added to the render target");
+
+        buffer[x + y * width] = alpha;
+    }
+
+    /// Adds a buffer for the AOV, cleared to its background value. Does nothing if
+    /// the buffer already exists
+    pub fn add_aov(&mut self, aov: Aov) {
+        if !self.aovs.iter().any(|(existing, _)| *existing == aov) {
+            self.aovs
+                .push((aov, vec![aov.background(); self.width * self.height]));
+        }
+    }
+
+    pub fn aovs(&self) -> impl Iterator<Item = Aov> + '_ {
+        self.aovs.iter().map(|(aov, _)| *aov)
+    }
+
+    /// Panics if the buffer wasn't added before
+    pub fn set_aov(&mut self, aov: Aov, x: usize, y: usize, value: RGBColor) {
+        let width = self.width;
+        let (_, buffer) = self
+            .aovs
+            .iter_mut()
+            .find(|(existing, _)| *existing == aov)
+            .expect("AOV buffer was not added to the render target");
+
+        buffer[x + y * width] = value;
+    }
+
+    /// Copies an AOV buffer into a separate render target, so it can be saved like any other
+    /// image. If visualize is set, the values are mapped into the 0-1 range for 8 bit formats.
+    pub fn aov_as_render_target(&self, aov: Aov, visualize: bool) -> Option<RenderTarget> {
+        self.aovs
+            .iter()
+            .find(|(existing, _)| *existing == aov)
+            .map(|(_, buffer)| RenderTarget {
+                width: self.width,
+                height: self.height,
+                data: if visualize {
+                    aov.visualize(buffer)
+                } else {
+                    buffer.clone()
+                },
+                alpha: None,
+                aovs: vec![],
+            })
+    }
+
+    pub fn save_as_ppm<P: AsRef<Path>>(
+        &self,
+        path: P,
+        display: &DisplayParameters,
+    ) -> std::io::Result<()> {
         let mut bytes = vec![
             80u8, 54, // Magic number
             32, // space
@@ -55,31 +154,151 @@ impl RenderTarget {
             50, 53, 53, // Max color value. 255 (ASCII) for normal pictures
             10, // newline
         ]);
-        bytes.extend(self.data.iter().flat_map(|&col| {
-            let bytes: [u8; 3] = col.into();
-            bytes.iter().map(ToOwned::to_owned).collect::<Vec<_>>()
-        }));
+        bytes.extend(
+            self.data
+                .iter()
+                .enumerate()
+                .flat_map(|(i, &col)| display.to_bytes(col, i % self.width, i / self.width)),
+        );
 
         fs::write(path, bytes)?;
 
         Ok(())
     }
 
-    pub fn save_as_png<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
-        // Convert our float colour to a byte color format
-        fn c(x: f64) -> u8 {
-            (x * 255.0) as u8
+    /// Writes 32 bit RGBA if the render target has an alpha channel, 24 bit RGB otherwise
+    pub fn save_as_png<P: AsRef<Path>>(
+        &self,
+        path: P,
+        display: &DisplayParameters,
+    ) -> std::io::Result<()> {
+        if let Some(alpha) = &self.alpha {
+            // PNG stores straight alpha, so the premultiplied colors have to be divided first
+            let data = self
+                .data
+                .iter()
+                .zip(alpha)
+                .enumerate()
+                .map(|(i, (&pix, &a))| {
+                    let a = a.clamp(0.0, 1.0);
+                    let pix = if a > 0.0 { pix / a } else { RGBColor::BLACK };
+                    let [r, g, b] = display.to_bytes(pix, i % self.width, i / self.width);
+                    lodepng::RGBA::new(r, g, b, (a * 255.0).round() as u8)
+                })
+                .collect::<Vec<_>>();
+
+            lodepng::encode32_file(path, &data, self.width, self.height)
+                .map_err(io::Error::other)?;
+
+            return Ok(());
         }
 
+        // Convert our float colour to a byte color format
         let data = self
             .data
             .iter()
-            .map(|pix| lodepng::RGB::new(c(pix.r), c(pix.g), c(pix.b)))
+            .enumerate()
+            .map(|(i, &pix)| {
+                let [r, g, b] = display.to_bytes(pix, i % self.width, i / self.width);
+                lodepng::RGB::new(r, g, b)
+            })
             .collect::<Vec<_>>();
 
         lodepng::encode24_file(path, &data, self.width, self.height)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            .map_err(io::Error::other)?;
 
         Ok(())
     }
+
+    /// Radiance RGBE file. Keeps colors above 1, but negative values are lost
+    pub fn save_as_hdr<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        hdr::write_hdr(path, self.width, self.height, &self.data)
+    }
+
+    /// Portable float map with 32 bit floats per channel
+    pub fn save_as_pfm<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        // A negative scale means little endian
+        let mut bytes = format!("PF\n{} {}\n-1.0\n", self.width, self.height).into_bytes();
+
+        // Rows are stored from bottom to top
+        for row in self.data.chunks(self.width).rev() {
+            for pix in row {
+                for &value in &[pix.r, pix.g, pix.b] {
+                    bytes.extend_from_slice(&(value as f32).to_le_bytes());
+                }
+            }
+        }
+
+        fs::write(path, bytes)
+    }
+
+    /// Stores the alpha channel as "A" and AOVs as additional layers in the same file. Depths and object ids
+    /// always use 32 bit floats, because half floats are too imprecise for them
+    pub fn save_as_exr<P: AsRef<Path>>(
+        &self,
+        path: P,
+        pixel_type: ExrPixelType,
+        compression: ExrCompression,
+    ) -> std::io::Result<()> {
+        fn component(color: &RGBColor, index: usize) -> f32 {
+            (match index {
+                0 => color.r,
+                1 => color.g,
+                _ => color.b,
+            }) as f32
+        }
+
+        let mut channels = ["R", "G", "B"]
+            .iter()
+            .enumerate()
+            .map(|(index, &name)| exr::ExrChannel {
+                name: name.to_owned(),
+                pixel_type,
+                values: self.data.iter().map(|c| component(c, index)).collect(),
+            })
+            .collect::<Vec<_>>();
+
+        if let Some(alpha) = &self.alpha {
+            channels.push(exr::ExrChannel {
+                name: "A".to_owned(),
+                pixel_type,
+                values: alpha.iter().map(|&a| a as f32).collect(),
+            });
+        }
+
+        for (aov, buffer) in &self.aovs {
+            let pixel_type = match aov {
+                Aov::Depth | Aov::ObjectId => ExrPixelType::Float,
+                _ => pixel_type,
+            };
+
+            for (index, channel) in aov.channels().iter().enumerate() {
+                channels.push(exr::ExrChannel {
+                    name: format!("{}.{}", aov.name(), channel),
+                    pixel_type,
+                    values: buffer.iter().map(|c| component(c, index)).collect(),
+                });
+            }
+        }
+
+        exr::write_exr(path, self.width, self.height, channels, compression)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_render_targets_are_rejected() {
+        for &(width, height) in &[(0, 0), (0, 10), (10, 0)] {
+            match RenderTarget::new(width, height) {
+                Err(RenderError::Invalid(diagnostics)) => assert_eq!(diagnostics.len(), 1),
+                Ok(_) => panic!("{}x{} was accepted", width, height),
+            }
+        }
+
+        let target = RenderTarget::new(3, 2).unwrap();
+        assert_eq!(target.pixels().len(), 6);
+    }
 }
@@ -1,17 +1,30 @@
+use crate::camera::{ApertureImage, ApertureKind, CameraOverride};
+use crate::diagnostics::{Diagnostic, Diagnostics, RaysError, Severity, Source};
+use crate::material::MaterialOverride;
 use crate::prelude::*;
-use serde::Deserialize;
-use std::collections::HashMap;
+use serde::de::{self, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use toml::Spanned;
 
 // TODO: This whole module is pretty awful, but I'm not sure what to do about it
 
+// Materials, cameras and render params can extend another entry of the same kind by name.
+// Everything that they don't set themselves is inherited from there
+
 #[derive(Deserialize)]
 #[serde(deny_unknown_fields)]
 struct NamedMaterial {
-    name: String,
+    name: Spanned<String>,
+
+    #[serde(default)]
+    extends: Option<Spanned<String>>,
 
     #[serde(flatten)]
-    material: Material,
+    material: MaterialOverride,
 }
 
 #[derive(Default, Deserialize)]
@@ -20,8 +33,10 @@ struct NamedMaterial {
 struct NamedCamera {
     name: String,
 
+    extends: Option<Spanned<String>>,
+
     #[serde(flatten)]
-    camera: Camera,
+    camera: CameraOverride,
 }
 
 #[derive(Default, Deserialize)]
@@ -30,27 +45,29 @@ struct NamedCamera {
 struct NamedRenderParams {
     name: String,
 
+    extends: Option<Spanned<String>>,
+
     #[serde(flatten)]
-    render_params: crate::render_params::RenderParamsOverride,
+    render_params: RenderParamsOverride,
 }
 
 #[derive(Deserialize)]
 #[serde(deny_unknown_fields)]
 struct UvmCheckerboardInit {
-    name: String,
-    even: String,
-    odd: String,
+    name: Spanned<String>,
+    even: Spanned<String>,
+    odd: Spanned<String>,
 }
 
 #[derive(Deserialize)]
 #[serde(deny_unknown_fields)]
 struct UvmTextureInit {
-    name: String,
+    name: Spanned<String>,
 
     #[serde(default)]
-    base: String,
+    base: Option<Spanned<String>>,
 
-    path: String,
+    path: Spanned<String>,
 
     #[serde(default)]
     sampling: SamplingMethod,
@@ -61,44 +78,112 @@ struct UvmTextureInit {
 #[serde(deny_unknown_fields)]
 struct NamedGeometryInit<T> {
     #[serde(rename = "uv-mapper")]
-    uv_mapper: String,
+    uv_mapper: Option<Spanned<String>>,
 
     #[serde(flatten)]
     init: T,
 }
 
-// Main config struct that is parsed
+// Another scene file whose contents are added to the scene. Either just the path
+// or a table like { path = "metals.toml", namespace = "metals" }
+struct IncludeInit {
+    path: String,
+    namespace: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct IncludeTable {
+    path: String,
+
+    #[serde(default)]
+    namespace: String,
+}
+
+impl<'de> Deserialize<'de> for IncludeInit {
+    fn deserialize<D>(deserializer: D) -> Result<IncludeInit, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct IncludeVisitor;
+
+        impl<'de> Visitor<'de> for IncludeVisitor {
+            type Value = IncludeInit;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a path or a table with path and namespace")
+            }
+
+            fn visit_str<E: de::Error>(self, path: &str) -> Result<IncludeInit, E> {
+                Ok(IncludeInit {
+                    path: path.to_owned(),
+                    namespace: String::new(),
+                })
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<IncludeInit, A::Error> {
+                let table = IncludeTable::deserialize(de::value::MapAccessDeserializer::new(map))?;
+
+                Ok(IncludeInit {
+                    path: table.path,
+                    namespace: table.namespace,
+                })
+            }
+        }
+
+        deserializer.deserialize_any(IncludeVisitor)
+    }
+}
+
+// Main config struct that is parsed. Everything is spanned, so problems can be reported
+// at the right place in the file
 
 #[derive(Default, Deserialize)]
 #[serde(default)]
 #[serde(deny_unknown_fields)]
 struct RawConfig {
+    include: Vec<Spanned<IncludeInit>>,
+
     #[serde(rename = "material")]
-    materials: Vec<NamedMaterial>,
+    materials: Vec<Spanned<NamedMaterial>>,
 
     #[serde(rename = "uvm-checkerboard")]
-    uvm_checkerboards: Vec<UvmCheckerboardInit>,
+    uvm_checkerboards: Vec<Spanned<UvmCheckerboardInit>>,
 
     #[serde(rename = "uvm-texture")]
-    uvm_textures: Vec<UvmTextureInit>,
+    uvm_textures: Vec<Spanned<UvmTextureInit>>,
 
     #[serde(rename = "obj-sphere")]
-    spheres: Vec<NamedGeometryInit<SphereInit>>,
+    spheres: Vec<Spanned<NamedGeometryInit<SphereInit>>>,
 
     #[serde(rename = "obj-infinite-plane")]
-    infinite_planes: Vec<NamedGeometryInit<InfinitePlaneInit>>,
+    infinite_planes: Vec<Spanned<NamedGeometryInit<InfinitePlaneInit>>>,
 
     #[serde(rename = "obj-plane")]
-    planes: Vec<NamedGeometryInit<PlaneInit>>,
+    planes: Vec<Spanned<NamedGeometryInit<PlaneInit>>>,
 
     #[serde(rename = "obj-cube")]
-    cubes: Vec<NamedGeometryInit<CubeInit>>,
+    cubes: Vec<Spanned<NamedGeometryInit<CubeInit>>>,
+
+    #[serde(rename = "obj-mesh")]
+    meshes: Vec<Spanned<NamedGeometryInit<MeshInit>>>,
+
+    environment: Option<Spanned<EnvironmentInit>>,
+
+    #[serde(rename = "light-point")]
+    point_lights: Vec<Spanned<PointLight>>,
+
+    #[serde(rename = "light-directional")]
+    directional_lights: Vec<Spanned<DirectionalLight>>,
 
     #[serde(rename = "camera")]
-    cameras: Vec<NamedCamera>,
+    cameras: Vec<Spanned<NamedCamera>>,
 
     #[serde(rename = "render-params")]
-    render_params: Vec<NamedRenderParams>,
+    render_params: Vec<Spanned<NamedRenderParams>>,
+
+    #[serde(rename = "post")]
+    post_effects: Vec<Spanned<PostEffect>>,
 }
 
 // Useful defaults
@@ -117,6 +202,15 @@ pub struct Config {
     pub scene: Scene,
     pub camera_config: CameraConfig,
     pub render_params_config: RenderParamsConfig,
+
+    /// Applied to the finished render in this order
+    pub post_effects: Vec<PostEffect>,
+
+    /// The scene file and all files that it includes, to show where the warnings point at
+    pub sources: Vec<Source>,
+
+    /// Problems in the scene that don't prevent it from being rendered
+    pub warnings: Vec<Diagnostic>,
 }
 
 pub enum CameraConfig {
@@ -125,219 +219,1251 @@ pub enum CameraConfig {
 }
 
 pub enum RenderParamsConfig {
-    Single(RenderParamsOverride),
+    Single(Box<RenderParamsOverride>),
     Multiple(HashMap<String, RenderParamsOverride>),
 }
 
-// TODO: Get rid of code duplication all over this module
-pub fn parse<P: AsRef<std::path::Path>>(path: P) -> Result<Config, Box<dyn std::error::Error>> {
-    let content = std::fs::read_to_string(path)?;
+// A scene file and everything that was deserialized from it
+struct SceneFile {
+    // Index into the sources
+    file: usize,
 
-    let config: RawConfig = toml::from_str(&content)?;
+    // Prefix of all names that the file defines, like "metals::". Empty if there is none
+    namespace: String,
 
-    let mut into_uvm_map: HashMap<&str, &dyn IntoUvMapper> = HashMap::new();
+    config: RawConfig,
+}
 
-    // Check if all materials and uv mapeprs combined have unique keys
+/// Loads a scene file and all files that it includes. Instead of stopping at the first problem,
+/// the whole scene is checked and all errors are returned together
+pub fn parse<P: AsRef<Path>>(path: P) -> Result<Config, RaysError> {
+    let path = path.as_ref();
 
-    let all_unique = config
-        .materials
-        .iter()
-        .all(|x| into_uvm_map.insert(x.name(), x).is_none())
-        && config
-            .uvm_checkerboards
-            .iter()
-            .all(|x| into_uvm_map.insert(x.name(), x).is_none())
-        && config
-            .uvm_textures
-            .iter()
-            .all(|x| into_uvm_map.insert(x.name(), x).is_none());
+    let content = std::fs::read_to_string(path).map_err(|error| RaysError::Io {
+        path: path.to_owned(),
+        error,
+    })?;
+
+    let source = Source {
+        path: path.to_owned(),
+        content,
+    };
 
-    if !all_unique {
-        return Err("The set of names of all materials and uv mappers 
-            combined must not contain duplicates"
-            .into());
+    let mut diagnostics = Diagnostics::new();
+    let mut sources = vec![];
+    let mut files = vec![];
+
+    load(
+        source,
+        String::new(),
+        &mut vec![],
+        &mut HashSet::new(),
+        &mut sources,
+        &mut files,
+        &mut diagnostics,
+    );
+
+    // Everything that was loaded is still checked. But names can only be missing for certain
+    // if all files were loaded, otherwise they are probably defined in one of the broken ones
+    let complete = !diagnostics.has_errors();
+
+    let mut to_uvm_map: HashMap<String, (&SceneFile, &dyn ToUvMapper)> = HashMap::new();
+
+    // Check if all materials and uv mappers combined have unique keys, across all files
+
+    let to_uvms = entries(&files, |c| &c.materials)
+        .map(|(file, x)| (file, x.get_ref() as &dyn ToUvMapper))
+        .chain(entries(&files, |c| &c.uvm_checkerboards).map(|(file, x)| (file, x.get_ref() as &dyn ToUvMapper)))
+        .chain(entries(&files, |c| &c.uvm_textures).map(|(file, x)| (file, x.get_ref() as &dyn ToUvMapper)));
+
+    for (file, to_uvm) in to_uvms {
+        let name = to_uvm.name();
+        let full_name = format!("{}{}", file.namespace, name.get_ref());
+
+        if to_uvm_map.insert(full_name.clone(), (file, to_uvm)).is_some() {
+            diagnostics.in_file(file.file, |d| {
+                d.error_at(
+                    name.span(),
+                    format!(
+                        "There is more than one material or uv mapper with the name \"{}\"",
+                        full_name
+                    ),
+                )
+            });
+        }
     }
 
+    // Materials are complete after everything that they extend has been merged into them
+    let materials = entries(&files, |c| &c.materials).collect::<Vec<_>>();
+
+    let material_overrides = resolve_extends(
+        &materials
+            .iter()
+            .map(|(file, mat)| Extendable {
+                file,
+                name: format!("{}{}", file.namespace, mat.get_ref().name.get_ref()),
+                extends: &mat.get_ref().extends,
+                value: &mat.get_ref().material,
+            })
+            .collect::<Vec<_>>(),
+        "Material",
+        complete,
+        &mut diagnostics,
+    );
+
     // put all materials (but not uv mappers!) into a map
-    let mut mat_map = HashMap::new();
-    for mat in &config.materials {
-        mat_map.insert(mat.name(), mat.material);
+    let mut materials_map = HashMap::new();
+
+    for ((file, mat), or) in materials.iter().zip(&material_overrides) {
+        let material = Material::default().override_with(or);
+
+        diagnostics.in_file(file.file, |d| {
+            d.in_span(mat.span(), |d| {
+                or.validate(d);
+                material.validate(d);
+            })
+        });
+
+        materials_map.insert(
+            format!("{}{}", file.namespace, mat.get_ref().name.get_ref()),
+            material,
+        );
     }
 
+    let materials = Materials {
+        map: materials_map,
+        complete,
+    };
+
     // generate all uv mappers out of material and uv mapper descriptions
-    // and put them into a map
+    // and put them into a map. Broken uv mappers are remembered, so
+    // objects that use them don't cause more errors
     let mut uv_mapper_map = HashMap::new();
 
-    for (key, into_uvm) in into_uvm_map {
-        let uvm = into_uvm
-            .into_uv_mapper(&mat_map)
-            .map_err(|err| format!("{}: {}", key, err))?;
+    for (key, (file, to_uvm)) in to_uvm_map {
+        let uvm = diagnostics.in_file(file.file, |d| {
+            to_uvm.to_uv_mapper(&materials, &file.namespace, d)
+        });
 
         uv_mapper_map.insert(key, uvm);
     }
 
-    // Construct all geometry and associate it with uv mappers
+    let uv_mappers = UvMappers {
+        map: uv_mapper_map,
+        complete,
+    };
+
+    // Construct all geometry and associate it with uv mappers. The objects of included files
+    // come first, just like their contents had been pasted at the top of the file
     let mut scene = Scene::new();
+    let mut has_environment = false;
 
-    // TODO: Again: REALLY, REALLY get rid of this code duplication. It makes me depressed
+    for file in &files {
+        let config = &file.config;
+        let namespace = &file.namespace[..];
 
-    // Let's start with all the spheres
-    for init in config.spheres {
-        let uvm = str_to_uv_mapper(&init.uv_mapper, &uv_mapper_map)?;
-        scene.add(Sphere::new(&init.init, uvm));
-    }
+        diagnostics.in_file(file.file, |diagnostics| {
+            // TODO: Again: REALLY, REALLY get rid of this code duplication. It makes me depressed
 
-    // And now let's do the infinite planes
-    for init in config.infinite_planes {
-        let uvm = str_to_uv_mapper(&init.uv_mapper, &uv_mapper_map)?;
-        scene.add(InifinitePlane::new(&init.init, uvm));
-    }
+            // Let's start with all the spheres
+            for init in &config.spheres {
+                if let Some(uvm) = uv_mappers.get(&init.get_ref().uv_mapper, namespace, diagnostics) {
+                    scene.add(Sphere::new(&init.get_ref().init, uvm));
+                }
+            }
 
-    // Continuing with the finite planes...
-    for init in config.planes {
-        let uvm = str_to_uv_mapper(&init.uv_mapper, &uv_mapper_map)?;
-        scene.add(Plane::new(&init.init, uvm));
-    }
+            // And now let's do the infinite planes
+            for init in &config.infinite_planes {
+                if let Some(uvm) = uv_mappers.get(&init.get_ref().uv_mapper, namespace, diagnostics) {
+                    scene.add(InifinitePlane::new(&init.get_ref().init, uvm));
+                }
+            }
+
+            // Continuing with the finite planes...
+            for init in &config.planes {
+                if let Some(uvm) = uv_mappers.get(&init.get_ref().uv_mapper, namespace, diagnostics) {
+                    scene.add(Plane::new(&init.get_ref().init, uvm));
+                }
+            }
+
+            // Then cubes
+            for init in &config.cubes {
+                if let Some(uvm) = uv_mappers.get(&init.get_ref().uv_mapper, namespace, diagnostics) {
+                    scene.add(Cube::new(&init.get_ref().init, uvm));
+                }
+            }
+
+            // And finally meshes, which need to be loaded from disk
+            for init in &config.meshes {
+                diagnostics.in_span(init.span(), |d| init.get_ref().init.validate(d));
+
+                if let Some(uvm) = uv_mappers.get(&init.get_ref().uv_mapper, namespace, diagnostics) {
+                    match Mesh::from_obj_file(&init.get_ref().init, uvm) {
+                        Ok(mesh) => scene.add(mesh),
+                        Err(err) => diagnostics
+                            .error_at(init.span(), format!("Could not load mesh: {}", err)),
+                    }
+                }
+            }
 
-    // And finally cubes
-    for init in config.cubes {
-        let uvm = str_to_uv_mapper(&init.uv_mapper, &uv_mapper_map)?;
-        scene.add(Cube::new(&init.init, uvm));
+            // Lights don't need any uv mappers, so we can just add them
+
+            let lights = config
+                .point_lights
+                .iter()
+                .map(|light| (light.span(), Light::from(*light.get_ref())))
+                .chain(
+                    config
+                        .directional_lights
+                        .iter()
+                        .map(|light| (light.span(), Light::from(*light.get_ref()))),
+                );
+
+            for (span, light) in lights {
+                diagnostics.in_span(span, |d| light.validate(d));
+                scene.add_light(light);
+            }
+
+            if let Some(init) = &config.environment {
+                if has_environment {
+                    diagnostics.error_at(init.span(), "There is more than one environment");
+                } else {
+                    has_environment = true;
+
+                    match Environment::load(init.get_ref()) {
+                        Ok(environment) => {
+                            diagnostics.in_span(init.span(), |d| environment.validate(d));
+                            scene.set_environment(environment);
+                        }
+                        Err(err) => diagnostics.error_at(
+                            init.span(),
+                            format!("Could not load environment: {}", err),
+                        ),
+                    }
+                }
+            }
+        });
     }
 
     // Now we handle the cameras
 
-    let camera_config = if config.cameras.is_empty() {
+    let cameras = entries(&files, |c| &c.cameras).collect::<Vec<_>>();
+
+    let camera_overrides = resolve_extends(
+        &cameras
+            .iter()
+            .map(|(file, named_cam)| Extendable {
+                file,
+                name: format!("{}{}", file.namespace, named_cam.get_ref().name),
+                extends: &named_cam.get_ref().extends,
+                value: &named_cam.get_ref().camera,
+            })
+            .collect::<Vec<_>>(),
+        "Camera",
+        complete,
+        &mut diagnostics,
+    );
+
+    let resolved_cameras = cameras
+        .iter()
+        .zip(&camera_overrides)
+        .map(|((file, named_cam), or)| {
+            let mut camera = Camera::default();
+            camera.override_with(or);
+
+            // Image apertures are loaded here, so that problems with the file point at the camera
+            let shape = &or.aperture_shape;
+
+            if let (Some(ApertureKind::Image), Some(path)) = (shape.kind, &shape.path) {
+                match ApertureImage::load(path) {
+                    Ok(image) => camera.aperture_shape = ApertureShape::Image { image },
+                    Err(err) => diagnostics.in_file(file.file, |d| {
+                        d.error_at(
+                            named_cam.span(),
+                            format!("Could not load aperture image {}: {}", path, err),
+                        )
+                    }),
+                }
+            }
+
+            camera
+        })
+        .collect::<Vec<_>>();
+
+    let cameras = cameras.into_iter().zip(resolved_cameras).collect::<Vec<_>>();
+
+    for (((file, named_cam), camera), or) in cameras.iter().zip(&camera_overrides) {
+        diagnostics.in_file(file.file, |d| {
+            d.in_span(named_cam.span(), |d| {
+                if camera.rotation.is_some() && camera.target.is_some() {
+                    d.error("Camera must not specify both rotation and target");
+                }
+
+                or.aperture_shape.validate(d);
+                camera.validate(d);
+            })
+        });
+    }
+
+    let camera_config = if cameras.is_empty() {
         CameraConfig::Single(Camera::default())
-    } else if config.cameras.len() == 1 {
-        CameraConfig::Single(config.cameras[0].camera)
+    } else if cameras.len() == 1 {
+        CameraConfig::Single(cameras.into_iter().next().unwrap().1)
     } else {
         // If we have multiple cameras, we need to make sure that
         // their keys are unique
         let mut cam_map = HashMap::new();
 
-        for named_cam in config.cameras {
-            if !named_cam.name.is_empty()
-                && cam_map.insert(named_cam.name, named_cam.camera).is_some()
-            {
-                return Err("Multiple cameras must have unique, non-empty name keys".into());
+        for ((file, named_cam), camera) in cameras {
+            let name = &named_cam.get_ref().name;
+            let full_name = format!("{}{}", file.namespace, name);
+
+            if name.is_empty() || cam_map.insert(full_name, camera).is_some() {
+                diagnostics.in_file(file.file, |d| {
+                    d.error_at(named_cam.span(), "Multiple cameras must have unique, non-empty name keys")
+                });
             }
         }
 
         CameraConfig::Multiple(cam_map)
     };
 
-    // And finally the render parameters
+    // And finally the render parameters. They are checked on top of the defaults here,
+    // because the quality preset that they are combined with isn't known yet
+
+    let render_params = entries(&files, |c| &c.render_params).collect::<Vec<_>>();
 
-    let render_params_config = if config.render_params.is_empty() {
-        RenderParamsConfig::Single(RenderParamsOverride::default()) // Default override doesn't override anything
-    } else if config.render_params.len() == 1 {
-        RenderParamsConfig::Single(config.render_params[0].render_params)
+    let render_params_overrides = resolve_extends(
+        &render_params
+            .iter()
+            .map(|(file, named_rp)| Extendable {
+                file,
+                name: format!("{}{}", file.namespace, named_rp.get_ref().name),
+                extends: &named_rp.get_ref().extends,
+                value: &named_rp.get_ref().render_params,
+            })
+            .collect::<Vec<_>>(),
+        "Render params",
+        complete,
+        &mut diagnostics,
+    );
+
+    let render_params = render_params
+        .into_iter()
+        .zip(render_params_overrides)
+        .collect::<Vec<_>>();
+
+    for ((file, named_rp), or) in &render_params {
+        let render_params = RenderParams::default().override_with(or);
+
+        diagnostics.in_file(file.file, |d| {
+            d.in_span(named_rp.span(), |d| render_params.validate(d))
+        });
+    }
+
+    let render_params_config = if render_params.is_empty() {
+        RenderParamsConfig::Single(Box::default()) // Default override doesn't override anything
+    } else if render_params.len() == 1 {
+        RenderParamsConfig::Single(Box::new(render_params.into_iter().next().unwrap().1))
     } else {
         // Again, we need to make sure that all RenderParams
         // have unique keys
         let mut rp_map = HashMap::new();
 
-        for named_rp in config.render_params {
-            if !named_rp.name.is_empty()
-                && rp_map
-                    .insert(named_rp.name, named_rp.render_params)
-                    .is_some()
-            {
-                return Err(
-                    "Multiple render-params structs must have unique, non-empty name keys".into(),
-                );
+        for ((file, named_rp), or) in render_params {
+            let name = &named_rp.get_ref().name;
+            let full_name = format!("{}{}", file.namespace, name);
+
+            if name.is_empty() || rp_map.insert(full_name, or).is_some() {
+                diagnostics.in_file(file.file, |d| {
+                    d.error_at(
+                        named_rp.span(),
+                        "Multiple render-params structs must have unique, non-empty name keys",
+                    )
+                });
             }
         }
 
         RenderParamsConfig::Multiple(rp_map)
     };
 
+    for (file, effect) in entries(&files, |c| &c.post_effects) {
+        diagnostics.in_file(file.file, |d| {
+            d.in_span(effect.span(), |d| effect.get_ref().validate(d))
+        });
+    }
+
+    let post_effects = entries(&files, |c| &c.post_effects)
+        .map(|(_, effect)| effect.get_ref().clone())
+        .collect();
+
+    let diagnostics = in_file_order(diagnostics);
+
+    if diagnostics.iter().any(|d| d.severity == Severity::Error) {
+        return Err(RaysError::Scene {
+            sources,
+            diagnostics,
+        });
+    }
+
     Ok(Config {
         scene,
         camera_config,
         render_params_config,
+        post_effects,
+        sources,
+        warnings: diagnostics,
     })
 }
 
-fn str_to_uv_mapper(
-    key: &str,
-    uv_mapper_map: &HashMap<&str, Arc<dyn UvMapper>>,
-) -> Result<Arc<dyn UvMapper>, Box<dyn std::error::Error>> {
-    if key.is_empty() {
-        Ok(Arc::new(StaticUvMapper(Material::default())))
-    } else {
-        Ok(Arc::clone(uv_mapper_map.get(key).ok_or(format!(
-            "UV mapper or material \"{}\" not found",
-            key
-        ))?))
+// Deserializes a scene file and, recursively, all files that it includes. Included files are
+// added to the list before the file that includes them. The chain contains the canonical paths
+// and source indices of the files that are currently being loaded, to detect include cycles.
+// Files that were already loaded into the same namespace are skipped, so a library that is
+// included by several files only defines its names once
+fn load(
+    source: Source,
+    namespace: String,
+    chain: &mut Vec<(PathBuf, usize)>,
+    loaded: &mut HashSet<(PathBuf, String)>,
+    sources: &mut Vec<Source>,
+    files: &mut Vec<SceneFile>,
+    diagnostics: &mut Diagnostics,
+) {
+    let file = sources.len();
+    let dir = source.path.parent().map(Path::to_owned).unwrap_or_default();
+    let result = toml::from_str::<RawConfig>(&source.content);
+
+    let canonical = canonical_path(&source.path);
+
+    chain.push((canonical.clone(), file));
+    loaded.insert((canonical, namespace.clone()));
+    sources.push(source);
+
+    let mut config = match result {
+        Ok(config) => config,
+        Err(err) => {
+            // Messages of syntax errors sometimes continue on a second line
+            let message = err.message().trim().replace('\n', ": ");
+
+            diagnostics.in_file(file, |d| match err.span() {
+                Some(span) => d.error_at(span, message),
+                None => d.error(message),
+            });
+
+            chain.pop();
+            return;
+        }
+    };
+
+    // Asset paths are relative to the file that contains them, just like includes
+    for mesh in &mut config.meshes {
+        resolve_path(&dir, &mut mesh.get_mut().init.path);
+    }
+
+    for texture in &mut config.uvm_textures {
+        resolve_path(&dir, texture.get_mut().path.get_mut());
     }
+
+    if let Some(environment) = &mut config.environment {
+        resolve_path(&dir, &mut environment.get_mut().path);
+    }
+
+    for camera in &mut config.cameras {
+        if let Some(path) = &mut camera.get_mut().camera.aperture_shape.path {
+            resolve_path(&dir, path);
+        }
+    }
+
+    for include in std::mem::take(&mut config.include) {
+        let span = include.span();
+        let include = include.into_inner();
+
+        // Paths are relative to the file that contains the include
+        let path = dir.join(&include.path);
+        let canonical = canonical_path(&path);
+
+        if let Some(start) = chain.iter().position(|(p, _)| *p == canonical) {
+            let cycle = chain[start..]
+                .iter()
+                .map(|&(_, index)| sources[index].path.display().to_string())
+                .chain(std::iter::once(path.display().to_string()))
+                .collect::<Vec<_>>()
+                .join(" -> ");
+
+            diagnostics.in_file(file, |d| {
+                d.error_at(span, format!("Include cycle: {}", cycle))
+            });
+
+            continue;
+        }
+
+        let include_namespace = if include.namespace.is_empty() {
+            namespace.clone()
+        } else {
+            format!("{}{}::", namespace, include.namespace)
+        };
+
+        if loaded.contains(&(canonical, include_namespace.clone())) {
+            continue;
+        }
+
+        match std::fs::read_to_string(&path) {
+            Ok(content) => {
+                let source = Source { path, content };
+
+                load(source, include_namespace, chain, loaded, sources, files, diagnostics);
+            }
+            Err(err) => diagnostics.in_file(file, |d| {
+                d.error_at(span, format!("Could not read {}: {}", path.display(), err))
+            }),
+        }
+    }
+
+    files.push(SceneFile {
+        file,
+        namespace,
+        config,
+    });
+
+    chain.pop();
 }
 
-trait IntoUvMapper {
-    fn name(&self) -> &str;
-    fn into_uv_mapper(
-        &self,
-        mat_map: &HashMap<&str, Material>,
-    ) -> Result<Arc<dyn UvMapper>, Box<dyn std::error::Error>>;
+// Report everything in the order of the files
+fn in_file_order(diagnostics: Diagnostics) -> Vec<Diagnostic> {
+    let mut diagnostics = diagnostics.into_vec();
+    diagnostics.sort_by_key(|d| (d.file, d.span.as_ref().map_or(usize::MAX, |span| span.start)));
+    diagnostics
 }
 
-impl IntoUvMapper for NamedMaterial {
-    fn name(&self) -> &str {
-        &self.name
+fn resolve_path(dir: &Path, path: &mut String) {
+    *path = dir.join(&*path).to_string_lossy().into_owned();
+}
+
+// Files that can't be canonicalized can't be read either, which is reported anyway
+fn canonical_path(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| path.to_owned())
+}
+
+// Every entry of one kind from all files, together with the file it comes from
+fn entries<'a, T: 'a>(
+    files: &'a [SceneFile],
+    get: fn(&RawConfig) -> &Vec<Spanned<T>>,
+) -> impl Iterator<Item = (&'a SceneFile, &'a Spanned<T>)> {
+    files
+        .iter()
+        .flat_map(move |file| get(&file.config).iter().map(move |entry| (file, entry)))
+}
+
+// Names are looked up in the namespace of the file that uses them first, and then in
+// the enclosing ones. In a file that was included as "metals", "gold" is looked up as
+// "metals::gold" and then as "gold"
+fn lookup<'m, T>(map: &'m HashMap<String, T>, namespace: &str, name: &str) -> Option<&'m T> {
+    let mut namespace = namespace;
+
+    loop {
+        if let Some(value) = map.get(&format!("{}{}", namespace, name)) {
+            return Some(value);
+        }
+
+        if namespace.is_empty() {
+            return None;
+        }
+
+        // "a::b::" -> "a::"
+        let parent = &namespace[..namespace.len() - 2];
+        namespace = parent.rfind("::").map_or("", |i| &namespace[..i + 2]);
+    }
+}
+
+// Entry of a scene file that can extend another entry of the same kind
+struct Extendable<'a, T> {
+    file: &'a SceneFile,
+
+    // Including the namespace. Empty for entries without a name, which can't be extended
+    name: String,
+
+    extends: &'a Option<Spanned<String>>,
+    value: &'a T,
+}
+
+trait Inherit: Clone {
+    fn inherit_from(&mut self, parent: &Self);
+}
+
+impl Inherit for MaterialOverride {
+    fn inherit_from(&mut self, parent: &Self) {
+        MaterialOverride::inherit_from(self, parent);
+    }
+}
+
+impl Inherit for CameraOverride {
+    fn inherit_from(&mut self, parent: &Self) {
+        CameraOverride::inherit_from(self, parent);
+    }
+}
+
+impl Inherit for RenderParamsOverride {
+    fn inherit_from(&mut self, parent: &Self) {
+        RenderParamsOverride::inherit_from(self, parent);
+    }
+}
+
+// Follows the extends chains of all entries of one kind. The result for each entry contains
+// its own values and everything that it inherits. If a chain is broken, the entry only
+// keeps the values up to that point
+fn resolve_extends<T: Inherit>(
+    entries: &[Extendable<T>],
+    kind: &str,
+    complete: bool,
+    diagnostics: &mut Diagnostics,
+) -> Vec<T> {
+    let mut names = HashMap::new();
+
+    // Later definitions replace earlier ones, just like in the maps that the entries end up in.
+    // Duplicate names are reported there
+    for (index, entry) in entries.iter().enumerate() {
+        if !entry.name.is_empty() {
+            names.insert(entry.name.clone(), index);
+        }
+    }
+
+    let mut resolved = vec![None; entries.len()];
+
+    for index in 0..entries.len() {
+        resolve_entry(index, entries, &names, kind, complete, &mut vec![], &mut resolved, diagnostics);
+    }
+
+    resolved.into_iter().map(Option::unwrap).collect()
+}
+
+// The chain contains the entries that are currently being resolved, to detect cycles
+#[allow(clippy::too_many_arguments)]
+fn resolve_entry<T: Inherit>(
+    index: usize,
+    entries: &[Extendable<T>],
+    names: &HashMap<String, usize>,
+    kind: &str,
+    complete: bool,
+    chain: &mut Vec<usize>,
+    resolved: &mut Vec<Option<T>>,
+    diagnostics: &mut Diagnostics,
+) -> T {
+    if let Some(value) = &resolved[index] {
+        return value.clone();
+    }
+
+    let entry = &entries[index];
+    let mut value = entry.value.clone();
+
+    if let Some(extends) = entry.extends {
+        chain.push(index);
+
+        match lookup(names, &entry.file.namespace, extends.get_ref()) {
+            None if !complete => {}
+            None => diagnostics.in_file(entry.file.file, |d| {
+                d.error_at(
+                    extends.span(),
+                    format!("{} \"{}\" not found", kind, extends.get_ref()),
+                )
+            }),
+            Some(&parent) => match chain.iter().position(|&i| i == parent) {
+                Some(start) => {
+                    let cycle = chain[start..]
+                        .iter()
+                        .chain(std::iter::once(&parent))
+                        .map(|&i| &entries[i].name[..])
+                        .collect::<Vec<_>>()
+                        .join(" -> ");
+
+                    diagnostics.in_file(entry.file.file, |d| {
+                        d.error_at(extends.span(), format!("Inheritance cycle: {}", cycle))
+                    });
+                }
+                None => {
+                    let parent = resolve_entry(parent, entries, names, kind, complete, chain, resolved, diagnostics);
+                    value.inherit_from(&parent);
+                }
+            },
+        }
+
+        chain.pop();
     }
 
-    fn into_uv_mapper(
+    resolved[index] = Some(value.clone());
+
+    value
+}
+
+// All uv mappers of the scene by their full name. Uv mappers that could not be created
+// are None, they already caused an error
+struct UvMappers {
+    map: HashMap<String, Option<Arc<dyn UvMapper>>>,
+
+    // False if some files couldn't be loaded, so missing names aren't reported
+    complete: bool,
+}
+
+impl UvMappers {
+    fn get(
         &self,
-        _mat_map: &HashMap<&str, Material>,
-    ) -> Result<Arc<dyn UvMapper>, Box<dyn std::error::Error>> {
-        Ok(Arc::new(StaticUvMapper(self.material)))
+        key: &Option<Spanned<String>>,
+        namespace: &str,
+        diagnostics: &mut Diagnostics,
+    ) -> Option<Arc<dyn UvMapper>> {
+        let key = match key {
+            Some(key) if !key.get_ref().is_empty() => key,
+            _ => return Some(Arc::new(StaticUvMapper(Material::default()))),
+        };
+
+        match lookup(&self.map, namespace, key.get_ref()) {
+            Some(uvm) => uvm.clone(),
+            None => {
+                if self.complete {
+                    diagnostics.error_at(
+                        key.span(),
+                        format!("UV mapper or material \"{}\" not found", key.get_ref()),
+                    );
+                }
+
+                None
+            }
+        }
     }
 }
 
-impl IntoUvMapper for UvmCheckerboardInit {
-    fn name(&self) -> &str {
+// All materials of the scene by their full name, with everything that they extend
+struct Materials {
+    map: HashMap<String, Material>,
+
+    // False if some files couldn't be loaded, so missing names aren't reported
+    complete: bool,
+}
+
+impl Materials {
+    // Looks up a material that is referenced by a uv mapper
+    fn get(
+        &self,
+        key: &Spanned<String>,
+        namespace: &str,
+        diagnostics: &mut Diagnostics,
+    ) -> Option<Material> {
+        let material = lookup(&self.map, namespace, key.get_ref()).copied();
+
+        if material.is_none() && self.complete {
+            diagnostics.error_at(
+                key.span(),
+                format!("Material \"{}\" not found", key.get_ref()),
+            );
+        }
+
+        material
+    }
+}
+
+trait ToUvMapper {
+    fn name(&self) -> &Spanned<String>;
+
+    /// Reports all problems and returns None if the uv mapper can't be created.
+    /// Materials are looked up in the namespace of the file that defines the uv mapper
+    fn to_uv_mapper(
+        &self,
+        materials: &Materials,
+        namespace: &str,
+        diagnostics: &mut Diagnostics,
+    ) -> Option<Arc<dyn UvMapper>>;
+}
+
+impl ToUvMapper for NamedMaterial {
+    fn name(&self) -> &Spanned<String> {
         &self.name
     }
 
-    fn into_uv_mapper(
+    fn to_uv_mapper(
         &self,
-        mat_map: &HashMap<&str, Material>,
-    ) -> Result<Arc<dyn UvMapper>, Box<dyn std::error::Error>> {
-        let even_mat = mat_map
-            .get(&self.even[..])
-            .ok_or("Material for key \"even\" not found")?
-            .clone();
+        materials: &Materials,
+        namespace: &str,
+        _diagnostics: &mut Diagnostics,
+    ) -> Option<Arc<dyn UvMapper>> {
+        // The material itself, with everything that it extends
+        let material = materials.map[&format!("{}{}", namespace, self.name.get_ref())];
+
+        Some(Arc::new(StaticUvMapper(material)))
+    }
+}
 
-        let odd_mat = mat_map
-            .get(&self.odd[..])
-            .ok_or("Material for key \"odd\" not found")?
-            .clone();
+impl ToUvMapper for UvmCheckerboardInit {
+    fn name(&self) -> &Spanned<String> {
+        &self.name
+    }
 
-        Ok(Arc::new(CheckerboardUvMapper(even_mat, odd_mat)))
+    fn to_uv_mapper(
+        &self,
+        materials: &Materials,
+        namespace: &str,
+        diagnostics: &mut Diagnostics,
+    ) -> Option<Arc<dyn UvMapper>> {
+        let even_mat = materials.get(&self.even, namespace, diagnostics);
+        let odd_mat = materials.get(&self.odd, namespace, diagnostics);
+
+        Some(Arc::new(CheckerboardUvMapper(even_mat?, odd_mat?)))
     }
 }
 
-impl IntoUvMapper for UvmTextureInit {
-    fn name(&self) -> &str {
+impl ToUvMapper for UvmTextureInit {
+    fn name(&self) -> &Spanned<String> {
         &self.name
     }
 
-    fn into_uv_mapper(
+    fn to_uv_mapper(
         &self,
-        mat_map: &HashMap<&str, Material>,
-    ) -> Result<Arc<dyn UvMapper>, Box<dyn std::error::Error>> {
-        let base_mat = if self.base.is_empty() {
-            Material::default()
-        } else {
-            mat_map
-                .get(&self.base[..])
-                .ok_or("Material for key \"base_mat\" not found")?
-                .clone()
+        materials: &Materials,
+        namespace: &str,
+        diagnostics: &mut Diagnostics,
+    ) -> Option<Arc<dyn UvMapper>> {
+        let base_mat = match &self.base {
+            Some(base) if !base.get_ref().is_empty() => {
+                materials.get(base, namespace, diagnostics)?
+            }
+            _ => Material::default(),
         };
 
-        let uvm = TextureUvMapper::from_png_24(&self.path, base_mat, self.sampling)?;
+        match TextureUvMapper::from_png_24(self.path.get_ref(), base_mat, self.sampling) {
+            Ok(uvm) => Some(Arc::new(uvm)),
+            Err(err) => {
+                diagnostics.error_at(
+                    self.path.span(),
+                    format!("Could not load texture: {}", err),
+                );
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Directory with scene files that is removed again at the end of the test
+    struct SceneDir(PathBuf);
+
+    impl SceneDir {
+        fn new(name: &str) -> SceneDir {
+            let dir = std::env::temp_dir().join(format!("rays-{}-{}", std::process::id(), name));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+
+            SceneDir(dir)
+        }
+
+        fn file(&self, name: &str, content: &str) -> PathBuf {
+            let path = self.0.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, content).unwrap();
+
+            path
+        }
+    }
+
+    impl Drop for SceneDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn errors(result: Result<Config, RaysError>) -> Vec<String> {
+        match result {
+            Ok(_) => vec![],
+            Err(RaysError::Scene { diagnostics, .. }) => diagnostics
+                .into_iter()
+                .filter(|d| d.severity == Severity::Error)
+                .map(|d| d.message)
+                .collect(),
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    #[test]
+    fn extends_resolves_chains() {
+        let dir = SceneDir::new("extends-chain");
+        let scene = dir.file(
+            "scene.toml",
+            r#"
+            [[camera]]
+            name = "grandchild"
+            extends = "child"
+            focus_distance = 5.0
+
+            [[camera]]
+            name = "child"
+            extends = "base"
+            aperture = 1.0
+
+            [[camera]]
+            name = "base"
+            aperture = 2.0
+            focus_distance = 10.0
+            fov_h = 60.0
+            "#,
+        );
+
+        let config = parse(scene).unwrap();
+
+        let cameras = match config.camera_config {
+            CameraConfig::Multiple(cameras) => cameras,
+            CameraConfig::Single(_) => panic!("Expected named cameras"),
+        };
+
+        let camera = &cameras["grandchild"];
+        assert_eq!(camera.aperture, 1.0);
+        assert_eq!(camera.focus_distance, 5.0);
+        assert_eq!(camera.fov_h, 60.0);
+
+        assert_eq!(cameras["child"].focus_distance, 10.0);
+    }
+
+    #[test]
+    fn extends_detects_cycles() {
+        let dir = SceneDir::new("extends-cycle");
+        let scene = dir.file(
+            "scene.toml",
+            r#"
+            [[material]]
+            name = "a"
+            extends = "b"
+
+            [[material]]
+            name = "b"
+            extends = "c"
+
+            [[material]]
+            name = "c"
+            extends = "a"
+
+            [[material]]
+            name = "self"
+            extends = "self"
+
+            [[render-params]]
+            name = "fast"
+            extends = "fast"
+            "#,
+        );
+
+        assert_eq!(
+            errors(parse(scene)),
+            vec![
+                "Inheritance cycle: a -> b -> c -> a",
+                "Inheritance cycle: self -> self",
+                "Inheritance cycle: fast -> fast",
+            ]
+        );
+    }
+
+    #[test]
+    fn extends_reports_missing_parents() {
+        let dir = SceneDir::new("extends-missing");
+        let scene = dir.file(
+            "scene.toml",
+            r#"
+            [[material]]
+            name = "a"
+            extends = "nope"
+
+            [[camera]]
+            name = "main"
+            extends = "other"
+            "#,
+        );
+
+        assert_eq!(
+            errors(parse(scene)),
+            vec!["Material \"nope\" not found", "Camera \"other\" not found"]
+        );
+    }
+
+    #[test]
+    fn partial_tables_need_a_parent() {
+        let dir = SceneDir::new("extends-partial");
+        let scene = dir.file(
+            "scene.toml",
+            r#"
+            [[material]]
+            name = "base"
+            opacity = { center = 0.5, edges = 1.0, power = 2.0 }
+
+            [[material]]
+            name = "child"
+            extends = "base"
+            opacity = { center = 0.2 }
+
+            [[material]]
+            name = "orphan"
+            reflection = { center = 0.5, max_angle = 2.0 }
+
+            [[material]]
+            name = "wrong-parent"
+            extends = "base"
+            specular = { strength = 1.0 }
+            "#,
+        );
+
+        assert_eq!(
+            errors(parse(scene)),
+            vec![
+                "Material reflection is missing edges, power. Only a parent material can fill in the rest of a table",
+                "Material specular is missing exponent. Only a parent material can fill in the rest of a table",
+            ]
+        );
+    }
+
+    #[test]
+    fn redefined_parents_are_the_last_definition() {
+        let dir = SceneDir::new("extends-redefined");
+        let scene = dir.file(
+            "scene.toml",
+            r#"
+            [[material]]
+            name = "base"
+            specular = { strength = -1.0, exponent = 10.0 }
+
+            [[material]]
+            name = "child"
+            extends = "base"
+
+            [[material]]
+            name = "base"
+            color = [0.5, 0.5, 0.5]
+            "#,
+        );
+
+        // The child extends the second base, which is also the one in the material map.
+        // Otherwise it would inherit the broken specular values of the first one
+        assert_eq!(
+            errors(parse(scene)),
+            vec![
+                "Specular strength and exponent must be 0 or positive",
+                "There is more than one material or uv mapper with the name \"base\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn broken_includes_dont_hide_other_errors() {
+        let dir = SceneDir::new("include-broken");
+        let scene = dir.file(
+            "scene.toml",
+            r#"
+            include = ["lib/broken.toml", "lib/missing.toml", "lib/good.toml"]
+
+            [[material]]
+            name = "glowing"
+            emission = { color = [1.0, 1.0, 1.0], strength = -1.0 }
+
+            [[obj-sphere]]
+            uv-mapper = "defined-in-broken"
+            "#,
+        );
+        dir.file("lib/broken.toml", "[[material]]\nname = \"defined-in-broken\"\ncolor = [1.0, 1.0\n");
+        dir.file(
+            "lib/good.toml",
+            r#"
+            [[material]]
+            name = "dark"
+            specular = { strength = -2.0, exponent = 10.0 }
+            "#,
+        );
+
+        let messages = errors(parse(&scene));
+
+        // Sorted by file. The sphere's material is probably in the broken file, so it isn't reported
+        assert_eq!(messages.len(), 4, "{:?}", messages);
+        assert!(messages[0].starts_with("Could not read"));
+        assert_eq!(messages[1], "Emission strength must be 0 or positive");
+        assert!(messages[2].contains("array"), "{}", messages[2]);
+        assert_eq!(messages[3], "Specular strength and exponent must be 0 or positive");
+    }
+
+    #[test]
+    fn diagnostics_point_at_their_entry() {
+        let dir = SceneDir::new("diagnostic-spans");
+        let content = "[[material]]\nname = \"a\"\nextends = \"nope\"\n";
+        let scene = dir.file("scene.toml", content);
+
+        let diagnostics = match parse(&scene) {
+            Err(RaysError::Scene { diagnostics, .. }) => diagnostics,
+            _ => panic!("Expected an error"),
+        };
+
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].file, 0);
+        assert_eq!(&content[diagnostics[0].span.clone().unwrap()], "\"nope\"");
+    }
+
+    #[test]
+    fn includes_detect_cycles() {
+        let dir = SceneDir::new("include-cycle");
+        let scene = dir.file("scene.toml", r#"include = ["lib/a.toml", "lib/self.toml"]"#);
+        dir.file("lib/a.toml", r#"include = ["b.toml"]"#);
+        dir.file("lib/b.toml", r#"include = ["../scene.toml"]"#);
+        dir.file("lib/self.toml", r#"include = ["self.toml"]"#);
+
+        let path = |name: &str| dir.0.join(name).display().to_string();
+
+        assert_eq!(
+            errors(parse(&scene)),
+            vec![
+                format!(
+                    "Include cycle: {} -> {} -> {} -> {}",
+                    path("scene.toml"),
+                    path("lib/a.toml"),
+                    path("lib/b.toml"),
+                    path("lib/../scene.toml")
+                ),
+                format!(
+                    "Include cycle: {} -> {}",
+                    path("lib/self.toml"),
+                    path("lib/self.toml")
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn includes_load_shared_files_once() {
+        let dir = SceneDir::new("include-diamond");
+        let scene = dir.file(
+            "scene.toml",
+            r#"
+            include = [
+                "lib/metals.toml",
+                "lib/common.toml",
+                { path = "lib/metals.toml", namespace = "m" },
+            ]
+
+            [[obj-sphere]]
+            uv-mapper = "white"
+
+            [[obj-sphere]]
+            uv-mapper = "m::white"
+            "#,
+        );
+        dir.file("lib/common.toml", r#"include = ["metals.toml"]"#);
+        dir.file(
+            "lib/metals.toml",
+            r#"
+            [[material]]
+            name = "white"
+            color = [1.0, 1.0, 1.0]
+            "#,
+        );
+
+        let config = parse(scene).unwrap();
+
+        // The second include of metals.toml is skipped, the one with a namespace is not
+        assert_eq!(config.sources.len(), 4);
+    }
+
+    #[test]
+    fn includes_report_missing_files() {
+        let dir = SceneDir::new("include-missing");
+        let scene = dir.file("scene.toml", r#"include = ["missing.toml"]"#);
+
+        let errors = errors(parse(scene));
+
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("Could not read"), "{}", errors[0]);
+    }
+
+    #[test]
+    fn aperture_images_are_loaded_after_parsing() {
+        let dir = SceneDir::new("aperture-missing");
+        let scene = dir.file(
+            "scene.toml",
+            r#"
+            [[camera]]
+            aperture = 0.5
+            aperture_shape = { type = "image", path = "missing.png" }
+            "#,
+        );
+
+        let errors = errors(parse(scene));
+
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("Could not load aperture image"), "{}", errors[0]);
+    }
+
+    #[test]
+    fn aperture_images_are_relative_to_their_file() {
+        let dir = SceneDir::new("aperture-include");
+        let scene = dir.file("scene.toml", r#"include = ["lenses/camera.toml"]"#);
+        dir.file(
+            "lenses/camera.toml",
+            r#"
+            [[camera]]
+            aperture = 0.5
+            aperture_shape = { type = "image", path = "bokeh.png" }
+            "#,
+        );
+
+        lodepng::encode24_file(dir.0.join("lenses/bokeh.png"), &[255u8; 4 * 3], 2, 2).unwrap();
+
+        let config = parse(scene).unwrap();
+
+        match config.camera_config {
+            CameraConfig::Single(camera) => {
+                assert!(matches!(camera.aperture_shape, ApertureShape::Image { .. }))
+            }
+            CameraConfig::Multiple(_) => panic!("Expected a single camera"),
+        }
+    }
+
+    #[test]
+    fn broken_images_are_reported() {
+        let dir = SceneDir::new("broken-images");
+        let scene = dir.file(
+            "scene.toml",
+            r#"
+            [[uvm-texture]]
+            name = "wood"
+            path = "wood.png"
+
+            [environment]
+            path = "sky.hdr"
+
+            [[camera]]
+            "#,
+        );
+
+        // Cut off in the middle of the image data
+        lodepng::encode24_file(dir.0.join("wood.png"), &[128u8; 16 * 16 * 3], 16, 16).unwrap();
+        let png = std::fs::read(dir.0.join("wood.png")).unwrap();
+        std::fs::write(dir.0.join("wood.png"), &png[..png.len() / 2]).unwrap();
+
+        dir.file("sky.hdr", "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 0 +X 0\n");
+
+        let messages = errors(parse(&scene));
+        assert!(messages.iter().any(|e| e.starts_with("Could not load texture")), "{:?}", messages);
+        assert!(messages.iter().any(|e| e.starts_with("Could not load environment")), "{:?}", messages);
+
+        // Environments can be truncated PNG files as well
+        std::fs::write(dir.0.join("sky.png"), &png[..png.len() / 2]).unwrap();
+        let scene = dir.file("scene.toml", "[environment]\npath = \"sky.png\"\n\n[[camera]]\n");
+
+        let messages = errors(parse(&scene));
+        assert!(messages.iter().any(|e| e.starts_with("Could not load environment")), "{:?}", messages);
+    }
+
+    #[test]
+    fn images_need_matching_pixels() {
+        let pixels = vec![RGBColor::new(0.5, 0.5, 0.5); 6];
+
+        assert!(TextureUvMapper::new(pixels.clone(), 3, 2, Material::default(), SamplingMethod::POINT).is_ok());
+        assert!(TextureUvMapper::new(pixels.clone(), 2, 2, Material::default(), SamplingMethod::POINT).is_err());
+        assert!(TextureUvMapper::new(vec![], 0, 0, Material::default(), SamplingMethod::POINT).is_err());
 
-        Ok(Arc::new(uvm))
+        assert!(Environment::new(3, 2, pixels.clone(), Vec3::ZERO, 1.0).is_ok());
+        assert!(Environment::new(6, 0, pixels, Vec3::ZERO, 1.0).is_err());
     }
 }
@@ -0,0 +1,193 @@
+use rand::prelude::*;
+
+use super::color::*;
+use super::raytracing::*;
+use super::sampler::{self, Sampler};
+use super::scene::*;
+use super::vec::*;
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Lobe {
+    Diffuse,
+    Reflection,
+    Refraction,
+}
+
+/// Follows a single random path from the camera through the scene and returns the light
+/// that travels back along it. Instead of splitting into several rays, every hit picks one
+/// way to continue (diffuse, reflection or refraction) with a probability equal to its weight.
+/// Everything that arrives after a reflection or refraction at the first hit is also added
+/// to the contributions.
+pub fn trace_path(
+    params: &RaytraceParameters,
+    sampler: &mut Sampler,
+    mut ray: Ray,
+    contributions: Option<&mut PrimaryContributions>,
+) -> RGBColor {
+    let render_params = params.render_params;
+    let scene = params.scene;
+
+    let mut output = RGBColor::BLACK;
+
+    // Fraction of the light at the current hit that makes it back to the camera
+    let mut throughput = RGBColor::WHITE;
+
+    // Area lights and the environment are already sampled at every diffuse hit, so they
+    // must not be counted a second time when a diffuse bounce happens to hit them
+    let mut after_diffuse_bounce = false;
+
+    // Paths that leave the camera and are only refracted show the transparent background
+    let mut transparent_background = render_params.transparent_background;
+
+    let mut bounces = 0;
+
+    // Way the path continued at the first hit, and the light gathered up to that point
+    let mut first_lobe = None;
+
+    loop {
+        let (obj, hit) = match get_closest_hit(params, &ray, bounces) {
+            Some(closest) => closest,
+            None if transparent_background => break,
+            None => {
+                match scene.environment() {
+                    Some(environment) if !after_diffuse_bounce => {
+                        output += throughput * environment.radiance(ray.direction)
+                    }
+                    Some(_) => {}
+                    None => output += throughput * render_params.sky_color,
+                }
+
+                break;
+            }
+        };
+
+        let mat = obj.get_uv_mapper().get_material_at(&hit);
+
+        let steepness = calc_steepness(ray.direction, hit.normal);
+        let alpha = calc_scaled_alpha(&mat, steepness);
+        let reflection = calc_scaled_reflection(&mat, steepness);
+
+        let diffuse_weight = alpha * (1.0 - reflection);
+        let reflection_weight = alpha * reflection;
+
+        if mat.is_emissive() && !(after_diffuse_bounce && Scene::is_area_light(obj)) {
+            output += throughput * mat.emitted() * alpha;
+        }
+
+        let hit_info = HitInfo {
+            mat: &mat,
+            hit: &hit,
+            ray: &ray,
+            bounces,
+            intensity: 1.0,
+            transparent_background,
+        };
+
+        // Direct light is estimated at every hit, no matter which way the path continues
+        if scene.has_lights() {
+            let (diffuse, specular) = direct_lighting(params, sampler, &hit_info);
+
+            output += throughput * (mat.color * diffuse * diffuse_weight + specular * alpha);
+        }
+
+        if bounces >= render_params.quality.max_bounces {
+            break;
+        }
+
+        // Drawn before the lobe is chosen, so every bounce uses up the same dimensions
+        // of the sampler
+        let point = sampler.next_2d();
+        let choice = sampler.gen::<f64>();
+
+        let (next_ray, tint, lobe) = if choice < diffuse_weight {
+            // Lambertian surface: cosine weighted directions cancel out the cosine term
+            let normal = if ray.direction.dot(hit.normal) > 0.0 {
+                -hit.normal
+            } else {
+                hit.normal
+            };
+
+            let next_ray = Ray {
+                origin: hit.position + normal * render_params.quality.bias,
+                direction: sampler::cosine_hemisphere(point, normal),
+            };
+
+            (next_ray, mat.color, Lobe::Diffuse)
+        } else if choice < diffuse_weight + reflection_weight {
+            let center = get_reflected_ray(params, &hit_info);
+
+            let direction = match sample_cone(sampler, point, mat.reflection.max_angle, hit.normal, center.direction) {
+                Some(direction) => direction,
+                None => break,
+            };
+
+            let next_ray = Ray {
+                origin: center.origin,
+                direction,
+            };
+
+            (next_ray, mat.reflection.color.unwrap_or(mat.color), Lobe::Reflection)
+        } else {
+            let (center, cutoff_normal) = get_refracted_ray(params, &hit_info);
+
+            let direction = match sample_cone(sampler, point, mat.refraction.max_angle, cutoff_normal, center.direction) {
+                Some(direction) => direction,
+                None => break,
+            };
+
+            let next_ray = Ray {
+                origin: center.origin,
+                direction,
+            };
+
+            (next_ray, mat.refraction.color.unwrap_or(mat.color), Lobe::Refraction)
+        };
+
+        if bounces == 0 {
+            first_lobe = Some((lobe, output));
+        }
+
+        throughput = throughput * tint;
+        after_diffuse_bounce = lobe == Lobe::Diffuse;
+        transparent_background = transparent_background && lobe == Lobe::Refraction;
+        bounces += 1;
+        ray = next_ray;
+
+        // Russian roulette: Dark paths are terminated randomly and the surviving ones are
+        // brightened accordingly, which keeps the result unbiased
+        if bounces >= render_params.path.min_bounces {
+            let survival = throughput.r.max(throughput.g).max(throughput.b).min(0.95);
+
+            if sampler.gen::<f64>() >= survival {
+                break;
+            }
+
+            throughput = throughput * (1.0 / survival);
+        }
+    }
+
+    if let (Some(contributions), Some((lobe, output_at_first_hit))) = (contributions, first_lobe) {
+        match lobe {
+            Lobe::Diffuse => {}
+            Lobe::Reflection => contributions.reflection = output - output_at_first_hit,
+            Lobe::Refraction => contributions.refraction = output - output_at_first_hit,
+        }
+    }
+
+    output
+}
+
+// Picks a single direction inside of a cone, like the whitted integrator does for glossy surfaces
+fn sample_cone(
+    sampler: &mut Sampler,
+    point: [f64; 2],
+    max_angle: f64,
+    cutoff_normal: Vec3Norm,
+    cone_direction: Vec3Norm,
+) -> Option<Vec3Norm> {
+    if max_angle == 0.0 {
+        Some(cone_direction)
+    } else {
+        resample_cone(sampler, point, max_angle, cutoff_normal, cone_direction)
+    }
+}
@@ -0,0 +1,731 @@
+// Image effects that are applied to the finished render, before it is saved
+
+use crate::color::RGBColor;
+use crate::diagnostics::{Diagnostics, RenderError};
+use crate::output::RenderTarget;
+use crate::util;
+use serde::Deserialize;
+
+/// A single step of the post processing pipeline. Effects are applied in the order they
+/// appear in the config, on the linear colors of the render.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum PostEffect {
+    Bloom(Bloom),
+    Vignette(Vignette),
+    Grade(ColorGrade),
+    Sharpen(Sharpen),
+    Grain(FilmGrain),
+}
+
+/// Glow around overbright parts of the image, like the light scattering inside of a real lens
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct Bloom {
+    /// Only the brightness above this value starts to glow
+    pub threshold: f64,
+
+    /// How much of the glow is added to the image
+    pub intensity: f64,
+
+    /// Size of the glow as a fraction of the image width, so it looks the same at any resolution
+    pub radius: f64,
+}
+
+/// Darkens the image towards the corners
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct Vignette {
+    /// Range: 0-1
+    /// How dark the corners become
+    pub strength: f64,
+
+    /// Distance from the center where the darkening starts. 0 is the center, 1 is a corner
+    pub radius: f64,
+
+    /// Distance over which the darkening fades in
+    pub softness: f64,
+}
+
+/// Lift, gamma and gain per color channel, followed by a saturation adjustment
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct ColorGrade {
+    /// Raises the shadows while keeping white in place
+    pub lift: RGBColor,
+
+    /// Bends the midtones. Values above 1 brighten them
+    pub gamma: RGBColor,
+
+    /// Multiplies the whole range
+    pub gain: RGBColor,
+
+    /// 0 is grayscale, 1 leaves the colors unchanged
+    pub saturation: f64,
+}
+
+/// Unsharp mask: Increases the contrast of small details
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct Sharpen {
+    pub strength: f64,
+
+    /// Unit: Pixels
+    /// Size of the details that are sharpened
+    pub radius: f64,
+}
+
+/// Noise that mimics the grain of photographic film. The same seed always results in
+/// the same pattern, so stills can be reproduced exactly.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct FilmGrain {
+    /// Standard deviation of the noise, relative to the brightness of the pixel
+    pub strength: f64,
+
+    /// Unit: Pixels
+    /// Size of a single grain
+    pub size: f64,
+
+    pub seed: u64,
+
+    /// Uses independent noise for every color channel instead of the same for all three
+    pub colored: bool,
+}
+
+/// Applies all effects in order. Fails without changing the image if one of them has
+/// invalid settings.
+pub fn post_process(
+    render_target: &mut RenderTarget,
+    effects: &[PostEffect],
+) -> Result<(), RenderError> {
+    let mut diagnostics = Diagnostics::new();
+
+    for effect in effects {
+        effect.validate(&mut diagnostics);
+    }
+
+    if diagnostics.has_errors() {
+        return Err(RenderError::Invalid(diagnostics.into_vec()));
+    }
+
+    for effect in effects {
+        match effect {
+            PostEffect::Bloom(bloom) => bloom.apply(render_target),
+            PostEffect::Vignette(vignette) => vignette.apply(render_target),
+            PostEffect::Grade(grade) => grade.apply(render_target),
+            PostEffect::Sharpen(sharpen) => sharpen.apply(render_target),
+            PostEffect::Grain(grain) => grain.apply(render_target),
+        }
+    }
+
+    Ok(())
+}
+
+impl PostEffect {
+    pub fn validate(&self, diagnostics: &mut Diagnostics) {
+        match self {
+            PostEffect::Bloom(bloom) => {
+                if !util::is_in_range(bloom.threshold, 0.0, f64::INFINITY) {
+                    diagnostics.error("Bloom threshold must be 0 or positive");
+                }
+
+                if !util::is_in_range(bloom.intensity, 0.0, f64::INFINITY) {
+                    diagnostics.error("Bloom intensity must be 0 or positive");
+                }
+
+                if !util::is_in_range(bloom.radius, 0.0, 1.0) {
+                    diagnostics.error("Bloom radius must be in range 0-1");
+                }
+            }
+            PostEffect::Vignette(vignette) => {
+                if !util::is_in_range(vignette.strength, 0.0, 1.0) {
+                    diagnostics.error("Vignette strength must be in range 0-1");
+                }
+
+                if !util::is_in_range(vignette.radius, 0.0, f64::INFINITY) {
+                    diagnostics.error("Vignette radius must be 0 or positive");
+                }
+
+                if !util::is_in_range(vignette.softness, 0.0, f64::INFINITY) {
+                    diagnostics.error("Vignette softness must be 0 or positive");
+                }
+            }
+            PostEffect::Grade(grade) => {
+                let gamma = grade.gamma;
+
+                if ![gamma.r, gamma.g, gamma.b]
+                    .iter()
+                    .all(|&g| util::is_in_range_exclusive(g, 0.0, f64::INFINITY))
+                {
+                    diagnostics.error("Grade gamma must be positive in all channels");
+                }
+
+                if !util::is_in_range(grade.saturation, 0.0, f64::INFINITY) {
+                    diagnostics.error("Grade saturation must be 0 or positive");
+                }
+            }
+            PostEffect::Sharpen(sharpen) => {
+                if !util::is_in_range(sharpen.strength, 0.0, f64::INFINITY) {
+                    diagnostics.error("Sharpen strength must be 0 or positive");
+                }
+
+                if !util::is_in_range(sharpen.radius, 0.0, f64::INFINITY) {
+                    diagnostics.error("Sharpen radius must be 0 or positive");
+                }
+            }
+            PostEffect::Grain(grain) => {
+                if !util::is_in_range(grain.strength, 0.0, f64::INFINITY) {
+                    diagnostics.error("Grain strength must be 0 or positive");
+                }
+
+                if !util::is_in_range_exclusive(grain.size, 0.0, f64::INFINITY) {
+                    diagnostics.error("Grain size must be positive");
+                }
+            }
+        }
+    }
+}
+
+impl Bloom {
+    fn apply(&self, render_target: &mut RenderTarget) {
+        let (width, height) = (render_target.width, render_target.height);
+
+        // Scale colors down, so that only the luminance above the threshold remains
+        let bright = render_target
+            .pixels()
+            .iter()
+            .map(|&color| {
+                let lum = color.luminance();
+
+                if lum > self.threshold {
+                    color * ((lum - self.threshold) / lum)
+                } else {
+                    RGBColor::BLACK
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let glow = gaussian_blur(&bright, width, height, self.radius * width as f64);
+
+        for (pixel, glow) in render_target.pixels_mut().iter_mut().zip(glow) {
+            *pixel += glow * self.intensity;
+        }
+    }
+}
+
+impl Vignette {
+    fn apply(&self, render_target: &mut RenderTarget) {
+        let (width, height) = (render_target.width, render_target.height);
+        let half_diagonal = ((width * width + height * height) as f64).sqrt() / 2.0;
+
+        for (i, pixel) in render_target.pixels_mut().iter_mut().enumerate() {
+            let dx = (i % width) as f64 + 0.5 - width as f64 / 2.0;
+            let dy = (i / width) as f64 + 0.5 - height as f64 / 2.0;
+            let distance = (dx * dx + dy * dy).sqrt() / half_diagonal;
+
+            let t = smoothstep(self.radius, self.radius + self.softness, distance);
+
+            *pixel = *pixel * (1.0 - self.strength * t);
+        }
+    }
+}
+
+impl ColorGrade {
+    fn apply(&self, render_target: &mut RenderTarget) {
+        let grade = |x: f64, lift: f64, gamma: f64, gain: f64| {
+            let v = gain * (x + lift * (1.0 - x));
+
+            if v > 0.0 {
+                v.powf(1.0 / gamma)
+            } else {
+                v
+            }
+        };
+
+        for pixel in render_target.pixels_mut() {
+            let graded = RGBColor::new(
+                grade(pixel.r, self.lift.r, self.gamma.r, self.gain.r),
+                grade(pixel.g, self.lift.g, self.gamma.g, self.gain.g),
+                grade(pixel.b, self.lift.b, self.gamma.b, self.gain.b),
+            );
+
+            let lum = graded.luminance();
+            let gray = RGBColor::new(lum, lum, lum);
+
+            *pixel = gray + (graded - gray) * self.saturation;
+        }
+    }
+}
+
+impl Sharpen {
+    fn apply(&self, render_target: &mut RenderTarget) {
+        let (width, height) = (render_target.width, render_target.height);
+        let blurred = gaussian_blur(render_target.pixels(), width, height, self.radius);
+
+        for (pixel, blurred) in render_target.pixels_mut().iter_mut().zip(blurred) {
+            *pixel = *pixel + (*pixel - blurred) * self.strength;
+        }
+    }
+}
+
+impl FilmGrain {
+    fn apply(&self, render_target: &mut RenderTarget) {
+        let width = render_target.width;
+
+        for (i, pixel) in render_target.pixels_mut().iter_mut().enumerate() {
+            let x = (i % width) as f64 + 0.5;
+            let y = (i / width) as f64 + 0.5;
+
+            let noise = |channel: u64| 1.0 + self.strength * self.noise(x, y, channel);
+
+            *pixel = if self.colored {
+                RGBColor::new(pixel.r * noise(0), pixel.g * noise(1), pixel.b * noise(2))
+            } else {
+                *pixel * noise(0)
+            };
+        }
+    }
+
+    // Value noise with a standard deviation of roughly 1. A grid of random values with a
+    // spacing of size pixels is interpolated bilinearly
+    fn noise(&self, x: f64, y: f64, channel: u64) -> f64 {
+        let (gx, gy) = (x / self.size - 0.5, y / self.size - 0.5);
+        let (x0, y0) = (gx.floor(), gy.floor());
+        let (tx, ty) = (gx - x0, gy - y0);
+
+        let value = |ix: f64, iy: f64| {
+            let hash = util::hash(self.seed, &[ix as i64 as u64, iy as i64 as u64, channel]);
+
+            // Uniform distribution in -sqrt(3)..sqrt(3) has a standard deviation of 1
+            ((hash >> 11) as f64 / (1u64 << 53) as f64 * 2.0 - 1.0) * 3f64.sqrt()
+        };
+
+        let top = value(x0, y0) * (1.0 - tx) + value(x0 + 1.0, y0) * tx;
+        let bottom = value(x0, y0 + 1.0) * (1.0 - tx) + value(x0 + 1.0, y0 + 1.0) * tx;
+
+        top * (1.0 - ty) + bottom * ty
+    }
+}
+
+impl Default for Bloom {
+    fn default() -> Self {
+        Bloom {
+            threshold: 1.0,
+            intensity: 0.3,
+            radius: 0.02,
+        }
+    }
+}
+
+impl Default for Vignette {
+    fn default() -> Self {
+        Vignette {
+            strength: 0.5,
+            radius: 0.5,
+            softness: 0.5,
+        }
+    }
+}
+
+impl Default for ColorGrade {
+    fn default() -> Self {
+        ColorGrade {
+            lift: RGBColor::BLACK,
+            gamma: RGBColor::WHITE,
+            gain: RGBColor::WHITE,
+            saturation: 1.0,
+        }
+    }
+}
+
+impl Default for Sharpen {
+    fn default() -> Self {
+        Sharpen {
+            strength: 0.5,
+            radius: 1.0,
+        }
+    }
+}
+
+impl Default for FilmGrain {
+    fn default() -> Self {
+        FilmGrain {
+            strength: 0.05,
+            size: 1.0,
+            seed: 0,
+            colored: false,
+        }
+    }
+}
+
+// Smooth transition from 0 at edge0 to 1 at edge1. Without a range in between, it's a hard step
+fn smoothstep(edge0: f64, edge1: f64, x: f64) -> f64 {
+    if edge1 <= edge0 {
+        return if x < edge0 { 0.0 } else { 1.0 };
+    }
+
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+
+    t * t * (3.0 - 2.0 * t)
+}
+
+// Approximates a gaussian blur with three successive box blurs, which takes the same time
+// for any radius. Pixels outside of the image repeat the closest edge pixel.
+fn gaussian_blur(src: &[RGBColor], width: usize, height: usize, sigma: f64) -> Vec<RGBColor> {
+    if sigma < 0.5 {
+        return src.to_vec();
+    }
+
+    let radii = box_radii_for_gaussian(sigma);
+
+    let mut data = src.to_vec();
+
+    for &radius in &radii {
+        data = box_blur_rows(&data, width, radius);
+    }
+
+    data = transpose(&data, width, height);
+
+    for &radius in &radii {
+        data = box_blur_rows(&data, height, radius);
+    }
+
+    transpose(&data, height, width)
+}
+
+// Box sizes whose combined variance matches the gaussian (Kovesi, "Fast Almost-Gaussian Filtering")
+fn box_radii_for_gaussian(sigma: f64) -> [usize; 3] {
+    const PASSES: f64 = 3.0;
+
+    let ideal_width = (12.0 * sigma * sigma / PASSES + 1.0).sqrt();
+    let mut lower = ideal_width.floor() as i64;
+
+    if lower % 2 == 0 {
+        lower -= 1;
+    }
+
+    let upper = lower + 2;
+    let lower_f = lower as f64;
+
+    // Number of passes that use the smaller box
+    let lower_count = ((12.0 * sigma * sigma
+        - PASSES * lower_f * lower_f
+        - 4.0 * PASSES * lower_f
+        - 3.0 * PASSES)
+        / (-4.0 * lower_f - 4.0))
+        .round() as usize;
+
+    let mut radii = [0; 3];
+
+    for (i, radius) in radii.iter_mut().enumerate() {
+        let width = if i < lower_count { lower } else { upper };
+        *radius = ((width - 1) / 2).max(0) as usize;
+    }
+
+    radii
+}
+
+fn box_blur_rows(src: &[RGBColor], width: usize, radius: usize) -> Vec<RGBColor> {
+    let scale = 1.0 / (2 * radius + 1) as f64;
+    let radius = radius as isize;
+
+    let mut out = Vec::with_capacity(src.len());
+
+    for row in src.chunks(width) {
+        let at = |x: isize| row[x.clamp(0, width as isize - 1) as usize];
+
+        let mut sum = RGBColor::BLACK;
+
+        for x in -radius..=radius {
+            sum += at(x);
+        }
+
+        for x in 0..width as isize {
+            out.push(sum * scale);
+            sum += at(x + radius + 1) - at(x - radius);
+        }
+    }
+
+    out
+}
+
+fn transpose(src: &[RGBColor], width: usize, height: usize) -> Vec<RGBColor> {
+    let mut out = Vec::with_capacity(src.len());
+
+    for x in 0..width {
+        for y in 0..height {
+            out.push(src[y * width + x]);
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gray(value: f64) -> RGBColor {
+        RGBColor::new(value, value, value)
+    }
+
+    fn image(width: usize, height: usize, color: RGBColor) -> RenderTarget {
+        RenderTarget::with_clear_color(width, height, &color).unwrap()
+    }
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{} != {}", a, b);
+    }
+
+    fn sum(pixels: &[RGBColor]) -> f64 {
+        pixels.iter().map(|p| p.r).sum()
+    }
+
+    #[test]
+    fn bloom_only_spreads_overbright_parts() {
+        let mut target = image(41, 41, gray(0.5));
+        let bloom = Bloom {
+            threshold: 1.0,
+            intensity: 0.5,
+            radius: 0.05,
+        };
+
+        bloom.apply(&mut target);
+        assert!(target.pixels().iter().all(|p| p.r == 0.5));
+
+        let mut target = image(41, 41, RGBColor::BLACK);
+        target.set_pixel(20, 20, gray(10.0));
+        bloom.apply(&mut target);
+
+        // The glow contains the brightness above the threshold, scaled by the intensity
+        assert_close(sum(target.pixels()), 10.0 + 9.0 * 0.5);
+        assert!(target.pixels()[20 * 41 + 23].r > 0.0);
+        assert!(target.pixels()[20 * 41 + 21].r > target.pixels()[20 * 41 + 23].r);
+    }
+
+    #[test]
+    fn vignette_darkens_towards_the_corners() {
+        let mut target = image(101, 101, RGBColor::WHITE);
+
+        Vignette {
+            strength: 0.8,
+            radius: 0.3,
+            softness: 0.5,
+        }
+        .apply(&mut target);
+
+        let at = |x: usize, y: usize| target.pixels()[y * 101 + x].r;
+
+        // Inside of the radius, nothing changes. Beyond radius + softness, the full strength applies
+        assert_eq!(at(50, 50), 1.0);
+        assert_close(at(0, 0), 0.2);
+
+        let diagonal = (0..=50).map(|i| at(50 + i, 50 + i)).collect::<Vec<_>>();
+        assert!(diagonal.windows(2).all(|w| w[1] <= w[0]));
+
+        // Smooth transition
+        assert!(at(80, 80) > 0.2 && at(80, 80) < 1.0);
+    }
+
+    #[test]
+    fn grade_lift_gamma_gain() {
+        let grade = |color: RGBColor, grade: ColorGrade| {
+            let mut target = image(1, 1, color);
+            grade.apply(&mut target);
+            target.pixels()[0]
+        };
+
+        let color = RGBColor::new(0.25, 0.5, 1.0);
+        let unchanged = grade(color, ColorGrade::default());
+        assert_eq!((unchanged.r, unchanged.g, unchanged.b), (0.25, 0.5, 1.0));
+
+        // Lift raises black and keeps white in place
+        let lift = ColorGrade {
+            lift: gray(0.2),
+            ..ColorGrade::default()
+        };
+
+        assert_close(grade(RGBColor::BLACK, lift.clone()).r, 0.2);
+        assert_close(grade(RGBColor::WHITE, lift).r, 1.0);
+
+        let gamma = grade(gray(0.25), ColorGrade {
+            gamma: gray(2.0),
+            ..ColorGrade::default()
+        });
+        assert_close(gamma.r, 0.5);
+
+        let gain = grade(color, ColorGrade {
+            gain: RGBColor::new(2.0, 1.0, 0.5),
+            ..ColorGrade::default()
+        });
+        assert_close(gain.r, 0.5);
+        assert_close(gain.g, 0.5);
+        assert_close(gain.b, 0.5);
+    }
+
+    #[test]
+    fn grade_saturation_keeps_luminance() {
+        let color = RGBColor::new(0.8, 0.3, 0.1);
+
+        for &saturation in &[0.0, 0.5, 2.0] {
+            let mut target = image(1, 1, color);
+
+            ColorGrade {
+                saturation,
+                ..ColorGrade::default()
+            }
+            .apply(&mut target);
+
+            let graded = target.pixels()[0];
+            assert_close(graded.luminance(), color.luminance());
+
+            if saturation == 0.0 {
+                assert_close(graded.r, graded.g);
+                assert_close(graded.g, graded.b);
+            }
+        }
+    }
+
+    #[test]
+    fn sharpen_increases_edge_contrast() {
+        let mut flat = image(20, 20, gray(0.5));
+        let sharpen = Sharpen {
+            strength: 1.0,
+            radius: 2.0,
+        };
+
+        sharpen.apply(&mut flat);
+        assert!(flat.pixels().iter().all(|p| (p.r - 0.5).abs() < 1e-12));
+
+        // Dark left half, bright right half
+        let mut edge = image(20, 20, gray(0.2));
+
+        for y in 0..20 {
+            for x in 10..20 {
+                edge.set_pixel(x, y, gray(0.8));
+            }
+        }
+
+        sharpen.apply(&mut edge);
+
+        let row = &edge.pixels()[200..220];
+        assert!(row[9].r < 0.2 && row[10].r > 0.8);
+        assert_close(row[0].r, 0.2);
+        assert_close(row[19].r, 0.8);
+    }
+
+    #[test]
+    fn gaussian_blur_keeps_brightness_and_spreads_like_sigma() {
+        let (width, height) = (61, 61);
+
+        let constant = vec![gray(0.3); width * height];
+        let blurred = gaussian_blur(&constant, width, height, 3.0);
+        assert!(blurred.iter().all(|p| (p.r - 0.3).abs() < 1e-12));
+
+        let mut impulse = vec![RGBColor::BLACK; width * height];
+        impulse[30 * width + 30] = RGBColor::WHITE;
+
+        // Small sigmas can only be approximated roughly with boxes of whole pixels
+        for &sigma in &[2.5, 4.0, 6.0] {
+            let blurred = gaussian_blur(&impulse, width, height, sigma);
+            assert_close(sum(&blurred), 1.0);
+
+            // Variance along one axis
+            let variance = blurred
+                .iter()
+                .enumerate()
+                .map(|(i, p)| {
+                    let dx = (i % width) as f64 - 30.0;
+                    dx * dx * p.r
+                })
+                .sum::<f64>();
+
+            assert!((variance.sqrt() - sigma).abs() < 0.1 * sigma, "{} != {}", variance.sqrt(), sigma);
+        }
+
+        // Too small to have an effect
+        assert_eq!(sum(&gaussian_blur(&impulse, width, height, 0.3)), 1.0);
+        assert_eq!(gaussian_blur(&impulse, width, height, 0.3)[30 * width + 30].r, 1.0);
+    }
+
+    #[test]
+    fn box_radii_match_the_gaussian_variance() {
+        for &sigma in &[0.8, 1.0, 2.0, 3.7, 10.0, 25.0] {
+            let radii = box_radii_for_gaussian(sigma);
+
+            // A box of width w has a variance of (w² - 1) / 12
+            let variance = radii
+                .iter()
+                .map(|&r| {
+                    let width = (2 * r + 1) as f64;
+                    (width * width - 1.0) / 12.0
+                })
+                .sum::<f64>();
+
+            assert!((variance.sqrt() - sigma).abs() < 0.2 * sigma, "sigma {}: {:?}", sigma, radii);
+            assert!(radii.windows(2).all(|w| w[0] <= w[1] && w[1] - w[0] <= 1));
+        }
+    }
+
+    #[test]
+    fn grain_is_reproducible() {
+        let grain = |seed: u64, colored: bool| {
+            let mut target = image(32, 32, gray(0.5));
+
+            FilmGrain {
+                strength: 0.2,
+                size: 2.0,
+                seed,
+                colored,
+            }
+            .apply(&mut target);
+
+            target.pixels().iter().map(|p| (p.r, p.g, p.b)).collect::<Vec<_>>()
+        };
+
+        assert_eq!(grain(7, false), grain(7, false));
+        assert_eq!(grain(7, true), grain(7, true));
+        assert_ne!(grain(7, false), grain(8, false));
+
+        // Monochrome grain changes all channels alike, colored grain doesn't
+        assert!(grain(7, false).iter().all(|&(r, g, b)| r == g && g == b));
+        assert!(grain(7, true).iter().any(|&(r, g, _)| r != g));
+
+        // The average brightness stays roughly the same
+        let mean = grain(7, false).iter().map(|p| p.0).sum::<f64>() / (32.0 * 32.0);
+        assert!((mean - 0.5).abs() < 0.02, "{}", mean);
+    }
+
+    #[test]
+    fn invalid_effects_leave_the_image_alone() {
+        let mut target = image(4, 4, gray(0.5));
+
+        let effects = [
+            PostEffect::Vignette(Vignette::default()),
+            PostEffect::Grain(FilmGrain {
+                size: 0.0,
+                ..FilmGrain::default()
+            }),
+        ];
+
+        assert!(post_process(&mut target, &effects).is_err());
+        assert!(target.pixels().iter().all(|p| p.r == 0.5));
+    }
+
+    #[test]
+    fn smoothstep_edges() {
+        assert_eq!(smoothstep(0.2, 0.6, 0.1), 0.0);
+        assert_close(smoothstep(0.2, 0.6, 0.4), 0.5);
+        assert_eq!(smoothstep(0.2, 0.6, 0.7), 1.0);
+
+        // Empty range
+        assert_eq!(smoothstep(0.5, 0.5, 0.4), 0.0);
+        assert_eq!(smoothstep(0.5, 0.5, 0.5), 1.0);
+    }
+}
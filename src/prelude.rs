@@ -1,12 +1,22 @@
 // Things that you need so often that you don't want to write rays:: before it all the time
 
-pub use crate::material::{Material, Reflection, Refraction, Opacity};
+pub use crate::material::{Material, Reflection, Refraction, Opacity, Specular, Emission};
 pub use crate::color::RGBColor;
 pub use crate::uv_mappers::*;
-pub use crate::vec::{Vec3, Vec3Norm};
+pub use crate::vec::{Vec2, Vec3, Vec3Norm, Vec3View};
 pub use crate::geometry::*;
 pub use crate::scene::Scene;
-pub use crate::camera::{Camera, ViewPort};
+pub use crate::environment::{Environment, EnvironmentInit};
+pub use crate::light::{Light, PointLight, DirectionalLight};
+pub use crate::camera::{Camera, ViewPort, ApertureShape, ApertureImage, Projection};
 pub use crate::output::RenderTarget;
+pub use crate::exr::{ExrPixelType, ExrCompression};
 pub use crate::render_params::*;
-pub use crate::parser::{CameraConfig, RenderParamsConfig};
\ No newline at end of file
+pub use crate::filter::PixelFilter;
+pub use crate::display::{ToneMap, Dither};
+pub use crate::aov::Aov;
+pub use crate::tiles::TileOrder;
+pub use crate::sampler::{Sampler, SamplerType};
+pub use crate::post_processing::{PostEffect, Bloom, Vignette, ColorGrade, Sharpen, FilmGrain};
+pub use crate::parser::{CameraConfig, RenderParamsConfig};
+pub use crate::diagnostics::{Diagnostic, Diagnostics, Severity};
\ No newline at end of file
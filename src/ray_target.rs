@@ -1,3 +1,4 @@
+use crate::bvh::Aabb;
 use crate::raytracing::*;
 use crate::vec::*;
 
@@ -6,6 +7,15 @@ pub trait RayTarget {
     fn test_intersection(&self, ray: &Ray) -> Option<GeometryHitInfo>;
 
     fn is_visible_to_camera(&self) -> bool;
+
+    /// Should return None if the geometry is unbounded (e.g. an infinite plane)
+    fn bounding_box(&self) -> Option<Aabb>;
+
+    /// Maps two uniformly distributed numbers in 0-1 to a uniformly distributed point on the surface.
+    /// Geometry that returns Some here can be used as an area light if its material is emissive.
+    fn sample_surface(&self, _u: f64, _v: f64) -> Option<SurfaceSample> {
+        None
+    }
 }
 
 pub struct GeometryHitInfo {
@@ -14,4 +24,13 @@ pub struct GeometryHitInfo {
     pub normal: Vec3Norm,
     pub uv: Vec2
 
+}
+
+pub struct SurfaceSample {
+
+    pub hit: GeometryHitInfo,
+
+    /// Total surface area of the sampled geometry
+    pub area: f64
+
 }
\ No newline at end of file
@@ -1,192 +1,763 @@
 use rand::prelude::*;
 use rayon::prelude::*;
 
+use super::aov::Aov;
 use super::camera::*;
 use super::color::*;
+use super::diagnostics::{Diagnostic, Diagnostics, RenderError};
+use super::filter::{FilterSample, PixelFilter};
+use super::light;
 use super::material::*;
 use super::output::*;
+use super::path_tracing;
 use super::ray_target::*;
 use super::render_params::*;
 use super::scene::*;
 use super::vec::*;
 
+use super::sampler::{self, Sampler};
+use super::tiles;
+use super::util;
+
 use std::cmp;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
+#[derive(Copy, Clone)]
 pub struct Ray {
     pub origin: Vec3,
     pub direction: Vec3Norm,
 }
 
 // Convenience structs so we don't need to pass around so much stuff
-struct RaytraceParameters<'a> {
-    scene: &'a Scene,
-    render_params: &'a RenderParams,
+pub struct RaytraceParameters<'a> {
+    pub scene: &'a Scene,
+    pub render_params: &'a RenderParams,
 }
 
-struct HitInfo<'a> {
-    mat: &'a Material,
-    hit: &'a GeometryHitInfo,
-    ray: &'a Ray,
-    bounces: u32,
-    intensity: f64,
+pub struct HitInfo<'a> {
+    pub mat: &'a Material,
+    pub hit: &'a GeometryHitInfo,
+    pub ray: &'a Ray,
+    pub bounces: u32,
+    pub intensity: f64,
+
+    /// True if the ray was only refracted since it left the camera. If such a ray doesn't
+    /// hit anything, it shows the transparent background instead of the sky
+    pub transparent_background: bool,
+}
+
+/// Light that arrives at the first hit of a camera ray through reflection and refraction.
+/// Filled in by the integrators for the corresponding AOVs
+pub struct PrimaryContributions {
+    pub reflection: RGBColor,
+    pub refraction: RGBColor,
+}
+
+impl Default for PrimaryContributions {
+    fn default() -> Self {
+        PrimaryContributions {
+            reflection: RGBColor::BLACK,
+            refraction: RGBColor::BLACK,
+        }
+    }
+}
+
+// Final values of a single pixel, before they are copied into the render target
+struct RenderedPixel {
+    color: RGBColor,
+    alpha: f64,
+    aovs: Vec<RGBColor>,
+}
+
+/// State of a progressive render, handed to the callback after every pass
+pub struct RenderProgress {
+    /// Number of finished passes
+    pub pass: u32,
+
+    /// Largest number of samples that any pixel has so far
+    pub samples: u32,
+
+    pub elapsed: Duration,
+
+    /// False if the callback happens in the middle of a pass, because the snapshot
+    /// interval was up before the pass was done
+    pub end_of_pass: bool,
 }
 
+/// Summary of a finished render
+#[derive(Clone, Debug)]
+pub struct RenderStats {
+    pub elapsed: Duration,
+
+    /// Number of passes of a progressive render, 1 otherwise
+    pub passes: u32,
+
+    /// Camera samples of all pixels together
+    pub samples: u64,
+
+    /// True if a progressive render ended because of the time limit or the stop flag
+    /// instead of reaching its sample target
+    pub stopped: bool,
+
+    /// Problems with the settings that didn't prevent rendering
+    pub warnings: Vec<Diagnostic>,
+}
+
+/// Renders the scene into the render target. Fails without touching the render target if
+/// the render params, the camera or the scene contain errors. Progressive renders need a
+/// sample or time limit here, because nothing else could stop them
 pub fn render(
     scene: &Scene,
     camera: &Camera,
     render_target: &mut RenderTarget,
     render_params: &RenderParams,
-) {
-    if !render_params.validate() {
-        panic!("Invalid RenderParameters. Aborting.")
-    }
+) -> Result<RenderStats, RenderError> {
+    if render_params.is_unlimited() {
+        let mut diagnostics = Diagnostics::new();
+        diagnostics.error("Progressive renders without a sample or time limit need render_progressive and a stop flag");
 
-    if !camera.validate() {
-        panic!("Invalid Camera settings. Aborting.");
+        return Err(RenderError::Invalid(diagnostics.into_vec()));
     }
 
-    if !scene.validate() {
-        panic!("Scene contains illegal materials or colors. Aborting.")
+    render_progressive(
+        scene,
+        camera,
+        render_target,
+        render_params,
+        &AtomicBool::new(false),
+        |_, _| {},
+    )
+}
+
+/// Like render, but with progressive.enabled, the image is refined over several passes that
+/// all add their samples to the render target. After every pass, on_pass is called with the
+/// current image, and with progressive.snapshot_interval also in the middle of passes that
+/// take longer than the interval. Rendering ends when the sample target or the time limit is reached or
+/// once stop is set. A pass that is cut short still leaves a valid image, its unfinished
+/// tiles just keep the samples of the previous passes
+pub fn render_progressive<F>(
+    scene: &Scene,
+    camera: &Camera,
+    render_target: &mut RenderTarget,
+    render_params: &RenderParams,
+    stop: &AtomicBool,
+    mut on_pass: F,
+) -> Result<RenderStats, RenderError>
+where
+    F: FnMut(&RenderTarget, &RenderProgress),
+{
+    let mut diagnostics = Diagnostics::new();
+
+    render_params.validate(&mut diagnostics);
+    render_params.validate_for_camera(camera, &mut diagnostics);
+    camera.validate(&mut diagnostics);
+    scene.validate(&mut diagnostics);
+
+    if diagnostics.has_errors() {
+        return Err(RenderError::Invalid(diagnostics.into_vec()));
     }
 
+    let start = Instant::now();
+
     let raytrace_params = RaytraceParameters {
         scene,
         render_params,
     };
 
-    // Reusable stuff to avoid casting so much
-    let w = render_target.width as f64;
-    let h = render_target.height as f64;
+    let rt_width = render_target.width;
+    let rt_height = render_target.height;
 
-    // Distances between 2 pixels
-    let x_step = camera.viewport.width / w;
-    let x_start = (x_step - camera.viewport.width) / 2.0;
+    let sample_count = render_params.samples_per_pixel(camera);
+    let rays = camera.ray_generator();
 
-    let y_step = -camera.viewport.height / h;
-    let y_start = (camera.viewport.height - y_step) / 2.0;
+    let progressive = &render_params.progressive;
 
-    // Angle distances between two pixels
-    let fov_v = camera.fov_h / camera.viewport.aspect();
+    // Adaptive sampling keeps adding batches of sample_count samples to noisy pixels, and
+    // so does every pass of a progressive render
+    let mut max_sample_count = if render_params.adaptive.enabled {
+        cmp::max(sample_count, render_params.adaptive.max_samples)
+    } else if progressive.enabled {
+        u32::MAX
+    } else {
+        sample_count
+    };
 
-    let x_angle_step = camera.fov_h / w;
-    let x_angle_start = (x_angle_step - camera.fov_h) / 2.0;
+    if progressive.enabled && progressive.samples > 0 {
+        max_sample_count = cmp::min(max_sample_count, progressive.samples);
+    }
 
-    let y_angle_step = fov_v / h;
-    let y_angle_start = (y_angle_step - fov_v) / 2.0;
+    // Without a sample target, the sequences are stratified per pass instead
+    let sequence_length = if max_sample_count == u32::MAX {
+        sample_count
+    } else {
+        max_sample_count
+    };
 
-    let rt_width = render_target.width;
-    let rt_height = render_target.height;
+    let deadline = if progressive.enabled && progressive.time_limit > 0.0 {
+        Some(start + Duration::from_secs_f64(progressive.time_limit))
+    } else {
+        None
+    };
 
-    let render_target = std::sync::Mutex::new(render_target);
+    let filter = render_params.aa.filter;
 
-    (0..rt_height).into_par_iter().for_each(|y_ind| {
-        let y_ind_f = y_ind as f64;
-        let vp_y = y_start + y_ind_f * y_step;
-        let angle_y = y_angle_start + y_ind_f * y_angle_step;
+    let aovs = render_params.aov.enabled();
 
-        (0..rt_width).into_par_iter().for_each(|x_ind| {
-            let x_ind_f = x_ind as f64;
-            let vp_x = x_start + x_ind_f * x_step;
-            let angle_x = x_angle_start + x_ind_f * x_angle_step;
+    for &aov in &aovs {
+        render_target.add_aov(aov);
+    }
 
-            let mut rng = SmallRng::from_entropy();
+    if render_params.transparent_background {
+        render_target.add_alpha();
+    }
 
-            let origin = get_initial_ray_origin(camera, vp_x, vp_y);
+    // Takes samples for the pixel until it has sample_limit samples or is converged.
+    // Returns true if any samples were taken
+    let render_pixel = |pixel: &mut PixelAccumulator, x_ind: usize, y_ind: usize, sample_limit| {
+        let x_ind_f = x_ind as f64;
+        let y_ind_f = y_ind as f64;
 
-            // We render just a single ray if DoF is disabled
-            let color = if render_params.dof.max_angle == 0.0 {
-                let direction = get_initial_ray_direction(
-                    camera,
-                    &mut rng,
-                    render_params.dof.max_angle,
-                    angle_x,
-                    angle_y,
-                );
+        let start_count = pixel.taken;
 
-                raytrace_recursive(
-                    &raytrace_params,
-                    &mut rng,
-                    Ray { origin, direction },
-                    0,
-                    1.0,
-                )
+        // Samples are taken in batches of the regular sample count. Without adaptive
+        // sampling or progressive rendering, there is only a single batch
+        while pixel.taken < sample_limit && !pixel.converged {
+            let batch = cmp::min(sample_count, sample_limit - pixel.taken);
+
+            // Without AA, the box filter sends every sample through the pixel center. The other
+            // filters always spread them out, otherwise choosing one would have no effect
+            let filter_samples = if render_params.aa.samples > 1 || filter != PixelFilter::Box {
+                filter.gen_samples(&mut pixel.rng, batch)
             } else {
-                let mut color = RGBColor::BLACK;
+                vec![FilterSample::CENTER; batch as usize]
+            };
 
-                let ray_influence = 1.0 / render_params.dof.samples as f64;
+            for (batch_index, filter_sample) in filter_samples.into_iter().enumerate() {
+                let FilterSample { dx, dy, negative: is_negative } = filter_sample;
+                let sample_index = pixel.taken + batch_index as u32;
+
+                let mut sampler = Sampler::new(
+                    render_params.sampler,
+                    render_params.seed,
+                    (x_ind, y_ind),
+                    rt_width,
+                    sample_index,
+                    sequence_length,
+                );
 
-                for _ in 0..render_params.dof.samples {
-                    let direction = get_initial_ray_direction(
-                        camera,
-                        &mut rng,
-                        render_params.dof.max_angle,
-                        angle_x,
-                        angle_y,
-                    );
+                let ray = rays.gen_ray(
+                    &mut sampler,
+                    x_ind_f + 0.5 + dx,
+                    y_ind_f + 0.5 + dy,
+                    rt_width,
+                    rt_height,
+                    render_params.dof.max_angle,
+                );
+
+                let mut contributions = PrimaryContributions::default();
 
-                    color += raytrace_recursive(
+                // Parts of the image that the projection doesn't cover stay black
+                let sample = match (ray, render_params.integrator) {
+                    (None, _) => RGBColor::BLACK,
+                    (Some(ray), Integrator::Whitted) => raytrace_recursive(
                         &raytrace_params,
-                        &mut rng,
-                        Ray { origin, direction },
+                        &mut sampler,
+                        ray,
                         0,
                         1.0,
-                    ) * ray_influence;
+                        render_params.transparent_background,
+                        Some(&mut contributions),
+                    ),
+                    (Some(ray), Integrator::Path) => path_tracing::trace_path(
+                        &raytrace_params,
+                        &mut sampler,
+                        ray,
+                        Some(&mut contributions),
+                    ),
+                };
+
+                let alpha = match ray {
+                    Some(ray) if render_params.transparent_background => {
+                        trace_alpha(&raytrace_params, &ray)
+                    }
+                    Some(_) => 1.0,
+                    None => 0.0,
+                };
+
+                let brightness = sample.luminance();
+                pixel.brightness_sum += brightness;
+                pixel.brightness_sqr_sum += brightness * brightness;
+
+                if is_negative {
+                    pixel.negative += sample;
+                    pixel.negative_alpha += alpha;
+                    pixel.negative_count += 1;
+                } else {
+                    pixel.positive += sample;
+                    pixel.positive_alpha += alpha;
+                    pixel.positive_count += 1;
                 }
 
-                color
-            };
+                if !aovs.is_empty() {
+                    trace_aovs(
+                        &raytrace_params,
+                        &mut sampler,
+                        ray.as_ref(),
+                        &contributions,
+                        &aovs,
+                        &mut pixel.sample_aovs,
+                    );
+
+                    let offset = dx * dx + dy * dy;
+                    let is_closest = offset < pixel.closest_offset;
+
+                    if is_closest {
+                        pixel.closest_offset = offset;
+                    }
+
+                    // Negative lobes would make normals and colors overshoot,
+                    // so the filtered AOVs only average the positive samples
+                    for ((aov, value), &sample_value) in
+                        aovs.iter().zip(&mut pixel.aovs).zip(&pixel.sample_aovs)
+                    {
+                        if aov.is_filtered() {
+                            if !is_negative {
+                                *value += sample_value;
+                            }
+                        } else if is_closest {
+                            *value = sample_value;
+                        }
+                    }
+                }
+            }
+
+            pixel.taken += batch;
+
+            // The variance estimate of a single batch is too unreliable, because paths
+            // that find a light are rare. So there are always at least two batches
+            pixel.converged = render_params.adaptive.enabled
+                && pixel.taken >= 2 * sample_count
+                && is_converged(
+                    render_params.adaptive.threshold,
+                    pixel.brightness_sum,
+                    pixel.brightness_sqr_sum,
+                    pixel.taken,
+                );
+        }
+
+        pixel.taken > start_count
+    };
+
+    let tiles = tiles::gen_tiles(
+        rt_width,
+        rt_height,
+        render_params.tiles.size as usize,
+        render_params.tiles.order,
+    );
+
+    // The samples of every pixel are kept between passes
+    let mut accumulators = tiles
+        .iter()
+        .map(|tile| {
+            tile.pixels()
+                .map(|(x, y)| {
+                    PixelAccumulator::new(render_params.seed, y * rt_width + x, aovs.len())
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    let threads = rayon::current_num_threads();
+
+    let snapshot_interval = if progressive.enabled && progressive.snapshot_interval > 0.0 {
+        Some(Duration::from_secs_f64(progressive.snapshot_interval))
+    } else {
+        None
+    };
+
+    let mut pass = 0;
+    let mut stopped = false;
+    let mut last_callback = start;
+
+    let is_cancelled = || {
+        stop.load(Ordering::Relaxed) || deadline.is_some_and(|deadline| Instant::now() >= deadline)
+    };
+
+    let max_taken = |accumulators: &[Vec<PixelAccumulator>]| {
+        accumulators
+            .iter()
+            .flat_map(|pixels| pixels.iter().map(|pixel| pixel.taken).max())
+            .max()
+            .unwrap_or(0)
+    };
+
+    loop {
+        // Without progressive rendering, the first pass renders all samples at once
+        let pass_samples = if progressive.enabled {
+            sample_count
+        } else {
+            max_sample_count
+        };
+
+        // Whenever a thread is done with a tile, it takes the next one in the requested order,
+        // so threads that got expensive tiles don't leave the others idle. Tiles that are
+        // claimed through a shared counter can't be borrowed mutably, so every thread renders
+        // into a copy of the tile, which replaces the original when the threads are done
+        let next_tile = AtomicUsize::new(0);
+        let mut sampled_any = false;
+
+        // If the snapshot interval is up before all tiles are done, the pass is interrupted
+        // for the callback and then continues with the next tile
+        loop {
+            let snapshot_time = snapshot_interval.map(|interval| last_callback + interval);
+
+            let is_snapshot_due = || snapshot_time.is_some_and(|time| Instant::now() >= time);
+
+            let rendered = (0..threads)
+                .into_par_iter()
+                .map(|_| {
+                    let mut rendered = vec![];
+
+                    while !is_cancelled() {
+                        let tile_index = next_tile.fetch_add(1, Ordering::Relaxed);
+
+                        if tile_index >= tiles.len() {
+                            break;
+                        }
+
+                        let tile = tiles[tile_index];
+                        let mut pixels = accumulators[tile_index].clone();
+                        let mut sampled = false;
+
+                        for (pixel, (x, y)) in pixels.iter_mut().zip(tile.pixels()) {
+                            let sample_limit = pixel.taken.saturating_add(pass_samples);
+                            let sample_limit = cmp::min(sample_limit, max_sample_count);
+
+                            sampled |= render_pixel(pixel, x, y, sample_limit);
+                        }
+
+                        if sampled {
+                            rendered.push((tile_index, pixels));
+                        }
+
+                        // Checked after the tile, so that every thread makes progress
+                        // even with very short intervals
+                        if is_snapshot_due() {
+                            break;
+                        }
+                    }
+
+                    rendered
+                })
+                .collect::<Vec<_>>();
+
+            // Copy the current state of all tiles that got new samples into the render target
+            for (tile_index, pixels) in rendered.into_iter().flatten() {
+                let tile = tiles[tile_index];
+
+                for ((x, y), pixel) in tile.pixels().zip(&pixels) {
+                    let rendered = pixel.resolve(filter, &aovs);
 
-            {
-                // TODO: Wrap this in unsafe code. We will never write to the same pixel twice anyway
-                let mut lock = render_target.lock().unwrap();
+                    render_target.set_pixel(x, y, rendered.color);
 
-                lock.set_pixel(x_ind, y_ind, color);
+                    if render_params.transparent_background {
+                        render_target.set_alpha(x, y, rendered.alpha);
+                    }
+
+                    for (&aov, &value) in aovs.iter().zip(&rendered.aovs) {
+                        render_target.set_aov(aov, x, y, value);
+                    }
+                }
+
+                accumulators[tile_index] = pixels;
+                sampled_any = true;
             }
-        });
-    });
+
+            // Every tile that was claimed is finished by now
+            if next_tile.load(Ordering::Relaxed) >= tiles.len() || is_cancelled() {
+                break;
+            }
+
+            on_pass(
+                render_target,
+                &RenderProgress {
+                    pass,
+                    samples: max_taken(&accumulators),
+                    elapsed: start.elapsed(),
+                    end_of_pass: false,
+                },
+            );
+
+            last_callback = Instant::now();
+        }
+
+        if !sampled_any {
+            stopped = is_cancelled();
+            break;
+        }
+
+        pass += 1;
+
+        on_pass(
+            render_target,
+            &RenderProgress {
+                pass,
+                samples: max_taken(&accumulators),
+                elapsed: start.elapsed(),
+                end_of_pass: true,
+            },
+        );
+
+        last_callback = Instant::now();
+
+        if !progressive.enabled {
+            break;
+        }
+
+        if is_cancelled() {
+            stopped = true;
+            break;
+        }
+    }
+
+    let samples = accumulators
+        .iter()
+        .map(|pixels| pixels.iter().map(|pixel| pixel.taken as u64).sum::<u64>())
+        .sum();
+
+    Ok(RenderStats {
+        elapsed: start.elapsed(),
+        passes: pass,
+        samples,
+        stopped,
+        warnings: diagnostics.into_vec(),
+    })
+}
+
+// Everything that was sampled for a single pixel so far. Samples are distributed like the
+// filter, so they are simply summed up per filter lobe
+#[derive(Clone)]
+struct PixelAccumulator {
+    // Generates the filter samples. Every pixel gets its own random stream, so the result
+    // doesn't depend on which thread renders which pixel
+    rng: SmallRng,
+
+    positive: RGBColor,
+    positive_count: u32,
+    negative: RGBColor,
+    negative_count: u32,
+
+    // Alpha is summed up the same way, so it matches the premultiplied color
+    positive_alpha: f64,
+    negative_alpha: f64,
+
+    // Brightness statistics for adaptive sampling
+    brightness_sum: f64,
+    brightness_sqr_sum: f64,
+
+    taken: u32,
+    converged: bool,
+
+    aovs: Vec<RGBColor>,
+    sample_aovs: Vec<RGBColor>,
+    closest_offset: f64,
 }
 
-fn get_initial_ray_origin(camera: &Camera, viewport_x: f64, viewport_y: f64) -> Vec3 {
-    let mut origin = Vec3::new(viewport_x, viewport_y, 0.0).rotate(camera.rotation);
+impl PixelAccumulator {
+    fn new(seed: u64, pixel_index: usize, aov_count: usize) -> PixelAccumulator {
+        PixelAccumulator {
+            rng: SmallRng::seed_from_u64(util::hash(seed, &[pixel_index as u64])),
+            positive: RGBColor::BLACK,
+            positive_count: 0,
+            negative: RGBColor::BLACK,
+            negative_count: 0,
+            positive_alpha: 0.0,
+            negative_alpha: 0.0,
+            brightness_sum: 0.0,
+            brightness_sqr_sum: 0.0,
+            taken: 0,
+            converged: false,
+            aovs: vec![RGBColor::BLACK; aov_count],
+            sample_aovs: vec![RGBColor::BLACK; aov_count],
+            closest_offset: f64::INFINITY,
+        }
+    }
+
+    fn resolve(&self, filter: PixelFilter, aovs: &[Aov]) -> RenderedPixel {
+        let color = filter.combine_lobes(
+            self.positive,
+            self.positive_count,
+            self.negative,
+            self.negative_count,
+        );
 
-    origin += camera.position;
+        let alpha = filter.combine_lobes(
+            self.positive_alpha,
+            self.positive_count,
+            self.negative_alpha,
+            self.negative_count,
+        );
 
-    origin
+        let aovs = aovs
+            .iter()
+            .zip(&self.aovs)
+            .map(|(&aov, &value)| {
+                if aov.is_filtered() {
+                    if self.positive_count > 0 {
+                        value / self.positive_count as f64
+                    } else {
+                        aov.background()
+                    }
+                } else if aov == Aov::Samples {
+                    let taken = self.taken as f64;
+                    RGBColor::new(taken, taken, taken)
+                } else if self.closest_offset == f64::INFINITY {
+                    aov.background()
+                } else {
+                    value
+                }
+            })
+            .collect();
+
+        RenderedPixel {
+            color,
+            alpha: alpha.clamp(0.0, 1.0),
+            aovs,
+        }
+    }
 }
 
-fn get_initial_ray_direction<R: Rng + ?Sized>(
-    camera: &Camera,
-    rng: &mut R,
-    dof_angle: f64,
-    fov_angle_x: f64,
-    fov_angle_y: f64,
-) -> Vec3Norm {
-    let mut direction = Vec3Norm::FORWARD;
-
-    // Randomization for DoF
-    if dof_angle != 0.0 {
-        let dof_rx = rng.gen::<f64>() * dof_angle;
-        let dof_rz = rng.gen::<f64>() * 360.0;
-
-        direction = direction.rotate_x(dof_rx).rotate_z(dof_rz);
+// A pixel is converged once the standard error of its mean brightness is below the
+// threshold, relative to the square root of the brightness. This roughly follows how
+// visible noise is after tone mapping. Dark pixels use a minimum brightness, so they
+// don't need a huge number of samples to reach a tiny absolute error
+fn is_converged(threshold: f64, sum: f64, sqr_sum: f64, count: u32) -> bool {
+    if count < 2 {
+        return false;
     }
 
-    direction = direction
-        // Fov Influence
-        .rotate_y(fov_angle_x)
-        .rotate_x(fov_angle_y)
-        // Camera orientation influence
-        .rotate(camera.rotation);
+    let n = count as f64;
+    let mean = sum / n;
+    let variance = ((sqr_sum - sum * mean) / (n - 1.0)).max(0.0);
 
-    direction
+    (variance / n).sqrt() <= threshold * mean.max(0.1).sqrt()
 }
 
-fn raytrace_recursive<R: Rng + ?Sized>(
+// Fills in the AOV values of a single camera ray. Everything except the reflection and
+// refraction contributions is taken directly from the first hit
+fn trace_aovs(
     params: &RaytraceParameters,
-    rng: &mut R,
+    sampler: &mut Sampler,
+    ray: Option<&Ray>,
+    contributions: &PrimaryContributions,
+    aovs: &[Aov],
+    values: &mut [RGBColor],
+) {
+    let closest = ray.and_then(|ray| {
+        params
+            .scene
+            .closest_hit_index(ray, |obj| obj.is_visible_to_camera())
+            .map(|(obj_index, hit)| (ray, obj_index, hit))
+    });
+
+    for (&aov, value) in aovs.iter().zip(values) {
+        let (ray, obj_index, hit) = match &closest {
+            Some(closest) => closest,
+            None => {
+                *value = aov.background();
+                continue;
+            }
+        };
+
+        *value = match aov {
+            Aov::Depth => {
+                let depth = (hit.position - ray.origin).length();
+                RGBColor::new(depth, depth, depth)
+            }
+            Aov::Normal => RGBColor::new(hit.normal.x(), hit.normal.y(), hit.normal.z()),
+            Aov::Albedo => {
+                params.scene.object(*obj_index)
+                    .get_uv_mapper()
+                    .get_material_at(hit)
+                    .color
+            }
+            Aov::Uv => RGBColor::new(hit.uv.u, hit.uv.v, 0.0),
+            Aov::ObjectId => {
+                let id = (*obj_index + 1) as f64;
+                RGBColor::new(id, id, id)
+            }
+            Aov::Ao => {
+                let visibility = 1.0 - calc_occlusion(sampler, params, hit);
+                RGBColor::new(visibility, visibility, visibility)
+            }
+            Aov::Reflection => contributions.reflection,
+            Aov::Refraction => contributions.refraction,
+            // Only known once the whole pixel is done
+            Aov::Samples => RGBColor::BLACK,
+        };
+    }
+}
+
+// Follows a camera ray straight through all refractive surfaces and returns how much of
+// the pixel is covered by objects (1) instead of the transparent background (0).
+// Objects that are invisible to the camera count as background
+fn trace_alpha(params: &RaytraceParameters, ray: &Ray) -> f64 {
+    let quality = &params.render_params.quality;
+
+    let mut alpha = 0.0;
+    let mut transmittance = 1.0;
+    let mut ray = *ray;
+    let mut bounces = 0;
+
+    loop {
+        let (obj, hit) = match params.scene.closest_hit(&ray, |obj| obj.is_visible_to_camera()) {
+            Some(closest) => closest,
+            None => return alpha,
+        };
+
+        let mat = obj.get_uv_mapper().get_material_at(&hit);
+        let surface_alpha =
+            calc_scaled_alpha(&mat, calc_steepness(ray.direction, hit.normal)).clamp(0.0, 1.0);
+
+        alpha += transmittance * surface_alpha;
+        transmittance *= 1.0 - surface_alpha;
+
+        // Where the integrators stop following the refraction, the surface is opaque
+        let cut_off = params.render_params.integrator == Integrator::Whitted
+            && transmittance <= quality.min_intensity;
+
+        if bounces >= quality.max_bounces || cut_off {
+            return alpha + transmittance;
+        }
+
+        if transmittance == 0.0 {
+            return alpha;
+        }
+
+        let hit_info = HitInfo {
+            mat: &mat,
+            hit: &hit,
+            ray: &ray,
+            bounces,
+            intensity: transmittance,
+            transparent_background: true,
+        };
+
+        ray = get_refracted_ray(params, &hit_info).0;
+        bounces += 1;
+    }
+}
+
+// The contributions are only filled in for camera rays and are None for all other rays
+fn raytrace_recursive(
+    params: &RaytraceParameters,
+    sampler: &mut Sampler,
     ray: Ray,
     bounces: u32,
     intensity: f64,
+    transparent_background: bool,
+    contributions: Option<&mut PrimaryContributions>,
 ) -> RGBColor {
     let closest_hit = get_closest_hit(params, &ray, bounces);
 
@@ -202,7 +773,7 @@ fn raytrace_recursive<R: Rng + ?Sized>(
         if bounces < params.render_params.quality.max_bounces
             && params.render_params.ao.strength != 0.0
         {
-            apply_ao(&mut intensity_scale, rng, params, &hit);
+            apply_ao(&mut intensity_scale, sampler, params, &hit);
         }
 
         let hit_info = HitInfo {
@@ -211,30 +782,46 @@ fn raytrace_recursive<R: Rng + ?Sized>(
             ray: &ray,
             bounces,
             intensity: intensity * intensity_scale,
+            transparent_background,
         };
 
-        hit_object(params, rng, &hit_info)
+        hit_object(params, sampler, &hit_info, contributions)
+    } else if transparent_background {
+        // Premultiplied alpha: The background doesn't add any color
+        RGBColor::BLACK
     } else {
         // Ray didn't hit anything
-        params.render_params.sky_color * intensity
+        let background = match params.scene.environment() {
+            Some(environment) => environment.radiance(ray.direction),
+            None => params.render_params.sky_color,
+        };
+
+        background * intensity
     }
 }
 
-fn apply_ao<R: Rng + ?Sized>(
+fn apply_ao(
     intensity: &mut f64,
-    rng: &mut R,
+    sampler: &mut Sampler,
     params: &RaytraceParameters,
     hit: &GeometryHitInfo,
 ) {
-    // Generate ray cone with full spread
+    *intensity *= 1.0 - calc_occlusion(sampler, params, hit) * params.render_params.ao.strength;
+}
+
+// Range: 0-1. How much the closest occluder darkens the hit, regardless of the AO strength
+fn calc_occlusion(
+    sampler: &mut Sampler,
+    params: &RaytraceParameters,
+    hit: &GeometryHitInfo,
+) -> f64 {
+    // Cosine weighted, because occluders near the horizon hardly block any light
     let origin = hit.position + hit.normal * params.render_params.quality.bias;
-    let directions = gen_sample_ray_cone(
-        rng,
-        90.0,
-        params.render_params.ao.samples,
-        hit.normal,
-        hit.normal,
-    );
+    let directions = sampler
+        .points_2d(params.render_params.ao.samples)
+        .into_iter()
+        .map(|point| sampler::cosine_hemisphere(point, hit.normal))
+        .collect::<Vec<_>>();
 
     let closest = directions
         .into_iter()
@@ -243,52 +830,43 @@ fn apply_ao<R: Rng + ?Sized>(
         // .filter(|(_, other_hit)| hit.normal.dot(other_hit.normal) > T::zero()) // Only do AO on EXTERNAL reflections
         .min_by(|a, b| hit_dist_comp(origin, &a.1, &b.1));
 
-    if let Some((_, hit)) = closest {
-        let distance_normalized =
-            ((hit.position - origin).length() / params.render_params.ao.distance).min(1.0);
-
-        let ao_strength = (1.0 - distance_normalized).powi(2) * params.render_params.ao.strength;
+    match closest {
+        Some((_, hit)) => {
+            let distance_normalized =
+                ((hit.position - origin).length() / params.render_params.ao.distance).min(1.0);
 
-        *intensity = *intensity * (1.0 - ao_strength);
+            (1.0 - distance_normalized).powi(2)
+        }
+        None => 0.0,
     }
 }
 
-fn get_closest_hit<'a>(
+pub fn get_closest_hit<'a>(
     params: &'a RaytraceParameters,
     ray: &Ray,
     bounces: u32,
-) -> Option<(&'a Box<dyn SceneObject>, GeometryHitInfo)> {
-    let potential_hits = params
+) -> Option<(&'a dyn SceneObject, GeometryHitInfo)> {
+    // Only consider objects that are visible to the camera for primary rays
+    params
         .scene
-        .objects
-        .iter()
-        .map(|obj| (obj, obj.test_intersection(&ray)))
-        // Filter out the objects that are actually hit AND visible to the camera
-        .filter(|(obj, rch)| rch.is_some() && (bounces > 0 || obj.is_visible_to_camera()))
-        .map(|(obj, rch)| (obj, rch.unwrap()));
-
-    potential_hits.min_by(|a, b| hit_dist_comp(ray.origin, &a.1, &b.1))
+        .closest_hit(ray, |obj| bounces > 0 || obj.is_visible_to_camera())
 }
 
-fn hit_object<R: Rng + ?Sized>(
+fn hit_object(
     params: &RaytraceParameters,
-    rng: &mut R,
+    sampler: &mut Sampler,
     hit_info: &HitInfo,
+    mut contributions: Option<&mut PrimaryContributions>,
 ) -> RGBColor {
     // Calculate the angle btween our incoming ray and surface normal
     let incidence_angle_steepness = calc_steepness(hit_info.ray.direction, hit_info.hit.normal);
 
     // Calculate the effect of the angle of incidence on reflectivity
-    let incidence_reflection_influence =
-        incidence_angle_steepness.powf(hit_info.mat.reflection.power);
-    let scaled_reflection_intensity = (1.0 - incidence_reflection_influence)
-        * hit_info.mat.reflection.center
-        + incidence_reflection_influence * hit_info.mat.reflection.edges;
+    let scaled_reflection_intensity =
+        calc_scaled_reflection(hit_info.mat, incidence_angle_steepness);
 
     // Calculate the effect of the angle of incidence on refraction (object alpha)
-    let incidence_alpha_influence = incidence_angle_steepness.powf(hit_info.mat.opacity.power);
-    let scaled_alpha = (1.0 - incidence_alpha_influence) * hit_info.mat.opacity.center
-        + incidence_alpha_influence * hit_info.mat.opacity.edges;
+    let scaled_alpha = calc_scaled_alpha(hit_info.mat, incidence_angle_steepness);
 
     // Useful for debugging: Return some interesting value as a color
     //return RGBColor::PINK * scaled_reflection_intensity;
@@ -300,8 +878,22 @@ fn hit_object<R: Rng + ?Sized>(
         scaled_alpha * scaled_reflection_intensity * hit_info.intensity;
     let total_refraction_intensity = (1.0 - scaled_alpha) * hit_info.intensity;
 
-    // Influence of material color (all rays that are neither reflected nor refracted)
-    let mut output = RGBColor::from(hit_info.mat.color) * mat_color_intensity;
+    // Influence of material color (all rays that are neither reflected nor refracted).
+    // Without any lights in the scene, the material color is shown as-is
+    let mut output = if !params.scene.has_lights() {
+        hit_info.mat.color * mat_color_intensity
+    } else {
+        let (diffuse, specular) = direct_lighting(params, sampler, hit_info);
+        let ambient = RGBColor::WHITE * params.render_params.lights.ambient;
+
+        hit_info.mat.color * (diffuse + ambient) * mat_color_intensity
+            + specular * (scaled_alpha * hit_info.intensity)
+    };
+
+    // Light emitted by the material itself
+    if hit_info.mat.is_emissive() {
+        output += hit_info.mat.emitted() * (scaled_alpha * hit_info.intensity);
+    }
 
     // Abort recursion if we hit the bounce limit
     if hit_info.bounces == params.render_params.quality.max_bounces {
@@ -312,42 +904,208 @@ fn hit_object<R: Rng + ?Sized>(
     if total_reflection_intensity > params.render_params.quality.min_intensity {
         let tint = hit_info.mat.reflection.color.unwrap_or(hit_info.mat.color);
 
-        output += tint * reflect(params, rng, hit_info, total_reflection_intensity);
+        let reflection = tint * reflect(params, sampler, hit_info, total_reflection_intensity);
+
+        if let Some(contributions) = contributions.as_deref_mut() {
+            contributions.reflection = reflection;
+        }
+
+        output += reflection;
     }
 
     // Add refractive influence to output if the influence threshold is met
     if total_refraction_intensity > params.render_params.quality.min_intensity {
         let tint = hit_info.mat.refraction.color.unwrap_or(hit_info.mat.color);
 
-        output += tint * refract(params, rng, hit_info, total_refraction_intensity)
+        let refraction = tint * refract(params, sampler, hit_info, total_refraction_intensity);
+
+        if let Some(contributions) = contributions {
+            contributions.refraction = refraction;
+        }
+
+        output += refraction;
     }
 
     output
 }
 
-fn reflect<R: Rng + ?Sized>(
+// Returns the diffuse and specular light that arrives at the hit point from all light sources
+pub fn direct_lighting<R: Rng + ?Sized>(
     params: &RaytraceParameters,
     rng: &mut R,
     hit_info: &HitInfo,
+) -> (RGBColor, RGBColor) {
+    // Lights can only illuminate the side of the surface that the ray came from
+    let normal = if hit_info.ray.direction.dot(hit_info.hit.normal) > 0.0 {
+        -hit_info.hit.normal
+    } else {
+        hit_info.hit.normal
+    };
+
+    let origin = hit_info.hit.position + normal * params.render_params.quality.bias;
+
+    let mut diffuse = RGBColor::BLACK;
+    let mut specular = RGBColor::BLACK;
+
+    // Adds the light arriving from a single direction
+    let mut add_light = |direction: Vec3Norm, incoming: RGBColor, cos: f64| {
+        diffuse += incoming * cos;
+
+        // Blinn-Phong highlights
+        if hit_info.mat.specular.strength > 0.0 {
+            let half = (direction - hit_info.ray.direction).normalized();
+            let highlight = normal
+                .dot(half)
+                .max(0.0)
+                .powf(hit_info.mat.specular.exponent);
+
+            specular += incoming * (hit_info.mat.specular.strength * highlight);
+        }
+    };
+
+    for light in params.scene.lights() {
+        let sample = light.illuminate(origin);
+
+        let cos = normal.dot(sample.direction);
+
+        if cos <= 0.0 {
+            continue;
+        }
+
+        let incoming = sample.color
+            * shadow_transmittance(params, origin, sample.direction, sample.distance);
+
+        add_light(sample.direction, incoming, cos);
+    }
+
+    // Area lights are estimated by sampling random points on their surface, which gives soft shadows
+    let light_samples = params.render_params.lights.samples;
+
+    for light in params.scene.area_lights() {
+        for _ in 0..light_samples {
+            let sample = match light.sample_surface(rng.gen(), rng.gen()) {
+                Some(sample) => sample,
+                None => break,
+            };
+
+            let to_light = sample.hit.position - origin;
+            let distance = to_light.length();
+
+            if distance <= params.render_params.quality.bias {
+                continue;
+            }
+
+            let direction = (to_light / distance).into_normalized_unsafe();
+
+            let cos = normal.dot(direction);
+            let cos_light = -sample.hit.normal.dot(direction);
+
+            if cos <= 0.0 || cos_light <= 0.0 {
+                continue;
+            }
+
+            let emitted = light.get_uv_mapper().get_material_at(&sample.hit).emitted();
+
+            // Divided by pi to convert radiance to a diffuse contribution, just like the environment
+            let pdf = light::area_light_pdf(sample.area, distance, cos_light);
+            let weight = 1.0 / (pdf * std::f64::consts::PI * light_samples as f64);
+
+            // Stop the shadow ray just in front of the light, so it doesn't shadow itself
+            let transmittance = shadow_transmittance(
+                params,
+                origin,
+                direction,
+                distance - params.render_params.quality.bias,
+            );
+
+            add_light(direction, emitted * transmittance * weight, cos);
+        }
+    }
+
+    // The environment is importance sampled, so bright spots like the sun cast sharp shadows
+    if let Some(environment) = params.scene.environment() {
+        for _ in 0..light_samples {
+            let sample = environment.sample(rng.gen(), rng.gen());
+
+            let cos = normal.dot(sample.direction);
+
+            if cos <= 0.0 || sample.pdf <= 0.0 {
+                continue;
+            }
+
+            let weight = 1.0 / (sample.pdf * std::f64::consts::PI * light_samples as f64);
+
+            let transmittance =
+                shadow_transmittance(params, origin, sample.direction, f64::INFINITY);
+
+            add_light(sample.direction, sample.radiance * transmittance * weight, cos);
+        }
+    }
+
+    (diffuse, specular)
+}
+
+// Follows a shadow ray towards a light and returns how much of the light makes it through.
+// Partially transparent objects let some of the light through, tinted by their color
+pub(crate) fn shadow_transmittance(
+    params: &RaytraceParameters,
+    origin: Vec3,
+    direction: Vec3Norm,
+    distance: f64,
+) -> RGBColor {
+    // Upper limit for the number of transparent objects that a shadow ray passes through
+    const MAX_OCCLUDERS: u32 = 16;
+
+    let mut transmittance = RGBColor::WHITE;
+    let mut ray = Ray { origin, direction };
+    let mut remaining_distance = distance;
+
+    for _ in 0..MAX_OCCLUDERS {
+        let (obj, hit) = match get_closest_hit(params, &ray, 1) {
+            Some(closest) => closest,
+            None => return transmittance,
+        };
+
+        let hit_distance = (hit.position - ray.origin).length();
+
+        if hit_distance >= remaining_distance {
+            return transmittance;
+        }
+
+        let mat = obj.get_uv_mapper().get_material_at(&hit);
+        let alpha = calc_scaled_alpha(&mat, calc_steepness(direction, hit.normal));
+        let tint = mat.refraction.color.unwrap_or(mat.color);
+
+        transmittance = transmittance * tint * (1.0 - alpha).clamp(0.0, 1.0);
+
+        if transmittance.r.max(transmittance.g).max(transmittance.b)
+            <= params.render_params.quality.min_intensity
+        {
+            return RGBColor::BLACK;
+        }
+
+        // Continue behind the occluder
+        let step = hit_distance + params.render_params.quality.bias;
+        ray.origin += direction * step;
+        remaining_distance -= step;
+    }
+
+    RGBColor::BLACK
+}
+
+fn reflect(
+    params: &RaytraceParameters,
+    sampler: &mut Sampler,
+    hit_info: &HitInfo,
     total_intensity: f64,
 ) -> RGBColor {
-    // Origin of all reflected rays including bias
-    let origin = hit_info.hit.position + hit_info.hit.normal * params.render_params.quality.bias;
-    let direction = hit_info
-        .ray
-        .direction
-        .reflect(hit_info.hit.normal)
-        .interpolate_towards(
-            hit_info.hit.normal,
-            hit_info.mat.reflection.max_angle / 90.0,
-        )
-        .normalized();
+    let Ray { origin, direction } = get_reflected_ray(params, hit_info);
 
     // Special case for perfect reflection; We only need to send out a single ray
     if hit_info.mat.reflection.max_angle == 0.0 {
         let ray = Ray { origin, direction };
 
-        raytrace_recursive(params, rng, ray, hit_info.bounces + 1, total_intensity)
+        raytrace_recursive(params, sampler, ray, hit_info.bounces + 1, total_intensity, false, None)
     } else {
         let ray_count = get_ray_count_for_intensity(
             total_intensity,
@@ -355,7 +1113,7 @@ fn reflect<R: Rng + ?Sized>(
         );
 
         let ray_directions = gen_sample_ray_cone(
-            rng,
+            sampler,
             hit_info.mat.reflection.max_angle,
             ray_count,
             hit_info.hit.normal,
@@ -372,19 +1130,102 @@ fn reflect<R: Rng + ?Sized>(
                 direction: dir,
             };
 
-            output += raytrace_recursive(params, rng, ray, hit_info.bounces + 1, ray_intensity);
+            output += raytrace_recursive(
+                params,
+                sampler,
+                ray,
+                hit_info.bounces + 1,
+                ray_intensity,
+                false,
+                None,
+            );
         }
 
         output
     }
 }
 
-fn refract<R: Rng + ?Sized>(
+// Center of the reflection cone, including the bias that moves it off the surface
+pub fn get_reflected_ray(params: &RaytraceParameters, hit_info: &HitInfo) -> Ray {
+    let origin = hit_info.hit.position + hit_info.hit.normal * params.render_params.quality.bias;
+    let direction = hit_info
+        .ray
+        .direction
+        .reflect(hit_info.hit.normal)
+        .interpolate_towards(
+            hit_info.hit.normal,
+            hit_info.mat.reflection.max_angle / 90.0,
+        )
+        .normalized();
+
+    Ray { origin, direction }
+}
+
+fn refract(
     params: &RaytraceParameters,
-    rng: &mut R,
+    sampler: &mut Sampler,
     hit_info: &HitInfo,
     total_intensity: f64,
 ) -> RGBColor {
+    let (refr_ray, cutoff_normal) = get_refracted_ray(params, hit_info);
+
+    if hit_info.mat.refraction.max_angle == 0.0 {
+        // Special case for perfect refraction: We only need to send out a single ray
+        raytrace_recursive(
+            params,
+            sampler,
+            refr_ray,
+            hit_info.bounces + 1,
+            total_intensity,
+            hit_info.transparent_background,
+            None,
+        )
+    } else {
+        // Otherwise, we send many rays
+
+        let origin = refr_ray.origin;
+
+        let ray_count = get_ray_count_for_intensity(
+            total_intensity,
+            params.render_params.max_samples.refraction,
+        );
+
+        let directions = gen_sample_ray_cone(
+            sampler,
+            hit_info.mat.refraction.max_angle,
+            ray_count,
+            cutoff_normal,
+            refr_ray.direction,
+        );
+
+        let ray_intensity = total_intensity / directions.len() as f64;
+
+        let mut output = RGBColor::BLACK;
+
+        for dir in directions {
+            let ray = Ray {
+                origin,
+                direction: dir,
+            };
+
+            output += raytrace_recursive(
+                params,
+                sampler,
+                ray,
+                hit_info.bounces + 1,
+                ray_intensity,
+                hit_info.transparent_background,
+                None,
+            );
+        }
+
+        output
+    }
+}
+
+// Returns the refracted ray (or the internally reflected one) together with the normal
+// of the side of the surface that it leaves on. Sample cones must not cross that side
+pub fn get_refracted_ray(params: &RaytraceParameters, hit_info: &HitInfo) -> (Ray, Vec3Norm) {
     // This closure is magic and was stolen from:
     // https://www.scratchapixel.com/lessons/3d-basic-rendering/introduction-to-shading/reflection-refraction-fresnel
     let get_refr_ray = |ior_from: f64, ior_into: f64, n: Vec3Norm, hit_cos: f64| {
@@ -429,51 +1270,16 @@ fn refract<R: Rng + ?Sized>(
         )
     };
 
-    if hit_info.mat.refraction.max_angle == 0.0 {
-        // Special case for perfect refraction: We only need to send out a single ray
-        raytrace_recursive(params, rng, refr_ray, hit_info.bounces + 1, total_intensity)
+    let cutoff_normal = if going_inside_object {
+        -hit_info.hit.normal
     } else {
-        // Otherwise, we send many rays
-
-        let origin = refr_ray.origin;
-
-        let cutoff_normal = if going_inside_object {
-            -hit_info.hit.normal
-        } else {
-            hit_info.hit.normal
-        };
-
-        let ray_count = get_ray_count_for_intensity(
-            total_intensity,
-            params.render_params.max_samples.refraction,
-        );
-
-        let directions = gen_sample_ray_cone(
-            rng,
-            hit_info.mat.refraction.max_angle,
-            ray_count,
-            cutoff_normal,
-            refr_ray.direction,
-        );
-
-        let ray_intensity = total_intensity / directions.len() as f64;
-
-        let mut output = RGBColor::BLACK;
-
-        for dir in directions {
-            let ray = Ray {
-                origin,
-                direction: dir,
-            };
-
-            output += raytrace_recursive(params, rng, ray, hit_info.bounces + 1, ray_intensity);
-        }
+        hit_info.hit.normal
+    };
 
-        output
-    }
+    (refr_ray, cutoff_normal)
 }
 
-fn calc_steepness(incoming: Vec3Norm, normal: Vec3Norm) -> f64 {
+pub fn calc_steepness(incoming: Vec3Norm, normal: Vec3Norm) -> f64 {
     let i_dot_n = incoming.dot(normal);
 
     let angle_rad = if i_dot_n <= 0.0 {
@@ -487,31 +1293,66 @@ fn calc_steepness(incoming: Vec3Norm, normal: Vec3Norm) -> f64 {
     angle_rad / std::f64::consts::FRAC_PI_2
 }
 
-fn gen_sample_ray_cone<R: Rng + ?Sized>(
-    rng: &mut R,
+// Reflectivity of a material, depending on the steepness of the angle that it is seen from
+pub fn calc_scaled_reflection(mat: &Material, incidence_angle_steepness: f64) -> f64 {
+    let incidence_reflection_influence = incidence_angle_steepness.powf(mat.reflection.power);
+
+    (1.0 - incidence_reflection_influence) * mat.reflection.center
+        + incidence_reflection_influence * mat.reflection.edges
+}
+
+// Opacity of a material, depending on the steepness of the angle that it is seen from
+pub fn calc_scaled_alpha(mat: &Material, incidence_angle_steepness: f64) -> f64 {
+    let incidence_alpha_influence = incidence_angle_steepness.powf(mat.opacity.power);
+
+    (1.0 - incidence_alpha_influence) * mat.opacity.center
+        + incidence_alpha_influence * mat.opacity.edges
+}
+
+// Directions that penetrate the geometry behind the cutoff normal are replaced by new random
+// ones, so the number of rays stays the same unless the cone is almost completely blocked
+pub fn gen_sample_ray_cone(
+    sampler: &mut Sampler,
     max_angle: f64,
     max_rays: u32,
     cutoff_normal: Vec3Norm,
     cone_direction: Vec3Norm,
 ) -> Vec<Vec3Norm> {
-    let cone_dir_right_angle = cone_direction.get_random_90_deg_vector().normalized();
-
-    (0..max_rays)
-        .map(|_| {
-            let deviation = rng.gen::<f64>() * max_angle;
-            let spin = rng.gen::<f64>() * 360.0;
-
-            // Copy initial direction
-            cone_direction
-                // Deviate from initial direction by up to max_angle
-                .rotate_around_axis(cone_dir_right_angle, deviation)
-                // Randomize the direction
-                .rotate_around_axis(cone_direction, spin)
+    sampler
+        .points_2d(max_rays)
+        .into_iter()
+        .filter_map(|point| {
+            resample_cone(sampler, point, max_angle, cutoff_normal, cone_direction)
         })
-        .filter(move |v| v.dot(cutoff_normal) > 0.0) // Filter out the ones that penetrate the geometry
         .collect::<Vec<_>>()
 }
 
+/// Maps the point into the cone and retries with random points while the direction
+/// penetrates the geometry behind the cutoff normal. Gives up after a few tries
+pub fn resample_cone(
+    sampler: &mut Sampler,
+    point: [f64; 2],
+    max_angle: f64,
+    cutoff_normal: Vec3Norm,
+    cone_direction: Vec3Norm,
+) -> Option<Vec3Norm> {
+    const MAX_TRIES: u32 = 8;
+
+    let mut point = point;
+
+    for _ in 0..MAX_TRIES {
+        let direction = sampler::uniform_cone(point, max_angle, cone_direction);
+
+        if direction.dot(cutoff_normal) > 0.0 {
+            return Some(direction);
+        }
+
+        point = [sampler.gen(), sampler.gen()];
+    }
+
+    None
+}
+
 fn get_ray_count_for_intensity(intensity: f64, max_rays: u32) -> u32 {
     (1.0 + intensity * (max_rays - 1) as f64).round() as u32
 }
@@ -524,3 +1365,115 @@ pub fn hit_dist_comp(point: Vec3, a: &GeometryHitInfo, b: &GeometryHitInfo) -> c
 
     dist_a.partial_cmp(&dist_b).unwrap_or(cmp::Ordering::Equal)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::{DirectionalLight, Sphere, SphereInit, StaticUvMapper};
+    use std::sync::Arc;
+
+    #[test]
+    fn render_needs_a_limit_for_progressive_renders() {
+        let scene = Scene::new();
+        let camera = Camera::default();
+        let mut render_params = RenderParams::default();
+        render_params.progressive.enabled = true;
+
+        let result = render(&scene, &camera, &mut RenderTarget::new(4, 4).unwrap(), &render_params);
+        assert!(matches!(result, Err(RenderError::Invalid(_))));
+
+        render_params.progressive.samples = 2;
+
+        let stats = render(&scene, &camera, &mut RenderTarget::new(4, 4).unwrap(), &render_params).unwrap();
+        assert!(!stats.stopped);
+    }
+
+    #[test]
+    fn snapshots_interrupt_long_passes() {
+        let mut scene = Scene::new();
+        let material = Material::pure(RGBColor::new(0.8, 0.2, 0.2));
+        scene.add(Sphere::new(&SphereInit::new(Vec3::ZERO, 3.0), Arc::new(StaticUvMapper(material))));
+        scene.add_light(DirectionalLight::new(Vec3::new(-1.0, -1.0, 1.0), RGBColor::WHITE, 1.0));
+
+        let camera = Camera::default();
+        let mut render_params = RenderParams::default();
+        render_params.tiles.size = 4;
+        render_params.progressive.enabled = true;
+        render_params.progressive.samples = 2 * render_params.samples_per_pixel(&camera);
+
+        let mut uninterrupted = RenderTarget::new(16, 16).unwrap();
+        let stats = render(&scene, &camera, &mut uninterrupted, &render_params).unwrap();
+        assert_eq!(stats.passes, 2);
+
+        // Shorter than any tile, so every pass is interrupted
+        render_params.progressive.snapshot_interval = 1e-9;
+
+        let mut snapshots = 0;
+        let mut passes = 0;
+        let mut interrupted = RenderTarget::new(16, 16).unwrap();
+
+        let stats = render_progressive(
+            &scene,
+            &camera,
+            &mut interrupted,
+            &render_params,
+            &AtomicBool::new(false),
+            |_, progress| {
+                if progress.end_of_pass {
+                    passes += 1;
+                } else {
+                    snapshots += 1;
+                }
+            },
+        )
+        .unwrap();
+
+        assert_eq!((stats.passes, passes), (2, 2));
+        assert!(snapshots > 0);
+        let colors = |target: &RenderTarget| {
+            target.pixels().iter().map(|c| (c.r, c.g, c.b)).collect::<Vec<_>>()
+        };
+
+        assert_eq!(colors(&interrupted), colors(&uninterrupted));
+    }
+
+    #[test]
+    fn filters_spread_samples_without_aa() {
+        let mut scene = Scene::new();
+        let material = Material::pure(RGBColor::new(0.8, 0.2, 0.2));
+        scene.add(Sphere::new(&SphereInit::new(Vec3::ZERO, 3.0), Arc::new(StaticUvMapper(material))));
+
+        // The hybrid projection picks rays randomly, the perspective one always hits the sample position
+        let camera = Camera {
+            projection: Projection::Perspective,
+            ..Camera::default()
+        };
+
+        // Coverage of the sphere after some passes with one sample each
+        let coverage = |filter: PixelFilter, passes: u32| {
+            let mut render_params = RenderParams::default();
+            render_params.aa.filter = filter;
+            render_params.transparent_background = true;
+            render_params.progressive.enabled = true;
+            render_params.progressive.samples = passes * render_params.samples_per_pixel(&camera);
+
+            let mut target = RenderTarget::new(16, 16).unwrap();
+            target.add_alpha();
+            render(&scene, &camera, &mut target, &render_params).unwrap();
+
+            target.alpha().unwrap().to_vec()
+        };
+
+        // Only changes at the edge of the sphere if the samples are spread across the pixels
+        let max_change = |filter: PixelFilter| {
+            coverage(filter, 1)
+                .iter()
+                .zip(coverage(filter, 8))
+                .map(|(a, b)| (a - b).abs())
+                .fold(0.0, f64::max)
+        };
+
+        assert!(max_change(PixelFilter::Box) < 1e-9);
+        assert!(max_change(PixelFilter::Gaussian) > 0.1);
+    }
+}
@@ -1,103 +1,159 @@
 use crate::util;
+use crate::camera::Camera;
 use crate::color::RGBColor;
+use crate::diagnostics::Diagnostics;
+use crate::filter::PixelFilter;
+use crate::display::{ToneMap, Dither};
+use crate::tiles::TileOrder;
+use crate::sampler::SamplerType;
+use crate::util::generate_optional_variant;
 use serde::Deserialize;
 
-// This macro generates a deserializable version of the structs where any member is
-// wrapped inside of an Option. This allows us to deserialize a kind of 'override-struct'
-// which can then be used to override only specific values inside of a render-params struct
-
-macro_rules! generate_optional_variant {
-    (
-    $(
-    $(#[$outer:meta])*    
-    pub struct $name:ident 
-    {
-        $(
-        $(#[$inner:meta])*    
-        pub $field:ident : $t:ty
-        ),* 
-    })*
-    ) => {
-        $(
-        #[derive(Copy, Clone, Debug, Deserialize)]
-        #[serde(default)]
-        #[serde(deny_unknown_fields)]
-        pub struct $name {
-            $(
-            $(#[$inner])*    
-            pub $field : $t,
-            )* 
-        }
-        
-        impl $name {
-            pub fn override_with(&mut self, or: override_structs::$name) {
-                $(
-                    if let Some($field) = or.$field {
-                        self.$field = $field;
-                    }
-                )*
-            }
-        }
-        )*
-
-        pub mod override_structs {
-            use serde::Deserialize;
-
-            $(
-            #[derive(Default)]
-            #[derive(Copy, Clone, Debug, Deserialize)]
-            #[serde(default)]
-            #[serde(deny_unknown_fields)] 
-            pub struct $name {
-                $(  
-                pub $field : Option<$t>,
-                )* 
-            })*
-        }
-    }
-}
-
 #[derive(Copy, Clone, Default, Debug)]
 pub struct RenderParams {
     pub quality: QualityParameters,
     pub dof: DoFParameters,
+    pub aa: AaParameters,
     pub max_samples: MaxSamples,
     pub ao: AoParameters,
+    pub lights: LightParameters,
+    pub path: PathParameters,
+    pub display: DisplayParameters,
+    pub aov: AovParameters,
+    pub tiles: TileParameters,
+    pub adaptive: AdaptiveParameters,
+    pub progressive: ProgressiveParameters,
+
+    /// Algorithm that is used to calculate the color of each pixel
+    pub integrator: Integrator,
+
+    /// How the directions of AO, glossy and DoF rays are distributed
+    pub sampler: SamplerType,
 
     // This is the color returned when a ray doesn't hit anything
     // If you want a more complex skybox, add it manually as an object
-    pub sky_color: RGBColor
+    pub sky_color: RGBColor,
+
+    /// If set, camera rays that don't hit anything (also through refractive objects) are
+    /// transparent instead of showing the sky, and the render target gets an alpha channel.
+    /// The sky still lights the scene and shows up in reflections
+    pub transparent_background: bool,
+
+    /// Start value for all random numbers. Renders with the same seed are identical,
+    /// no matter how many threads are used
+    pub seed: u64
 }
 
-#[derive(Copy, Clone, Default, Debug, Deserialize)]
+#[derive(Clone, Default, Debug, Deserialize)]
 #[serde(default)]
 #[serde(deny_unknown_fields)]
 pub struct RenderParamsOverride {
     pub quality: override_structs::QualityParameters,
     pub dof: override_structs::DoFParameters,
+    pub aa: override_structs::AaParameters,
     pub max_samples: override_structs::MaxSamples,
     pub ao: override_structs::AoParameters,
+    pub lights: override_structs::LightParameters,
+    pub path: override_structs::PathParameters,
+    pub display: override_structs::DisplayParameters,
+    pub aov: override_structs::AovParameters,
+    pub tiles: override_structs::TileParameters,
+    pub adaptive: override_structs::AdaptiveParameters,
+    pub progressive: override_structs::ProgressiveParameters,
+
+    pub integrator: Option<Integrator>,
+
+    pub sampler: Option<SamplerType>,
 
     #[serde(rename = "sky-color")]
-    pub sky_color: Option<RGBColor>
+    pub sky_color: Option<RGBColor>,
+
+    #[serde(rename = "transparent-background")]
+    pub transparent_background: Option<bool>,
+
+    pub seed: Option<u64>
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Integrator {
+
+    /// Recursive raytracing that splits into several reflection and refraction rays at
+    /// every hit. Fast and noise-free for previews, but there is no diffuse interreflection
+    #[default]
+    Whitted,
+
+    /// Unidirectional path tracing. Every camera ray follows a single random path through
+    /// the scene, which gives physically plausible indirect light and colour bleeding
+    Path
 }
 
 impl RenderParams {
     pub fn override_with(mut self, or: &RenderParamsOverride) -> RenderParams {
-        self.quality.override_with(or.quality);
-        self.dof.override_with(or.dof);
-        self.max_samples.override_with(or.max_samples);
-        self.ao.override_with(or.ao);
+        self.quality.override_with(&or.quality);
+        self.dof.override_with(&or.dof);
+        self.aa.override_with(&or.aa);
+        self.max_samples.override_with(&or.max_samples);
+        self.ao.override_with(&or.ao);
+        self.lights.override_with(&or.lights);
+        self.path.override_with(&or.path);
+        self.display.override_with(&or.display);
+        self.aov.override_with(&or.aov);
+        self.tiles.override_with(&or.tiles);
+        self.adaptive.override_with(&or.adaptive);
+        self.progressive.override_with(&or.progressive);
+
+        if let Some(integrator) = or.integrator {
+            self.integrator = integrator;
+        }
+
+        if let Some(sampler) = or.sampler {
+            self.sampler = sampler;
+        }
         
         if let Some(sky_color) = or.sky_color {
             self.sky_color = sky_color;
         }
 
+        if let Some(transparent_background) = or.transparent_background {
+            self.transparent_background = transparent_background;
+        }
+
+        if let Some(seed) = or.seed {
+            self.seed = seed;
+        }
+
         self
     }
 }
 
+impl RenderParamsOverride {
+    /// Values that are not set here are taken from the parent. Tables like aa or dof are merged
+    /// value by value, so only the values that are actually set here replace those of the parent
+    pub fn inherit_from(&mut self, parent: &RenderParamsOverride) {
+        self.quality.inherit_from(&parent.quality);
+        self.dof.inherit_from(&parent.dof);
+        self.aa.inherit_from(&parent.aa);
+        self.max_samples.inherit_from(&parent.max_samples);
+        self.ao.inherit_from(&parent.ao);
+        self.lights.inherit_from(&parent.lights);
+        self.path.inherit_from(&parent.path);
+        self.display.inherit_from(&parent.display);
+        self.aov.inherit_from(&parent.aov);
+        self.tiles.inherit_from(&parent.tiles);
+        self.adaptive.inherit_from(&parent.adaptive);
+        self.progressive.inherit_from(&parent.progressive);
+
+        self.integrator = self.integrator.or(parent.integrator);
+        self.sampler = self.sampler.or(parent.sampler);
+        self.sky_color = self.sky_color.or(parent.sky_color);
+        self.transparent_background = self.transparent_background.or(parent.transparent_background);
+        self.seed = self.seed.or(parent.seed);
+    }
+}
+
 generate_optional_variant!(
+#[derive(Copy)]
 pub struct QualityParameters {
 
     /// Range: 0-1
@@ -108,7 +164,7 @@ pub struct QualityParameters {
 
     /// How often the raytracing function is allowed to recurse. If set to 0, no
     /// reflective and refractive effects will be visible at all.
-    /// You can safely set this to std::u32::MAX if you set min_intensity instead
+    /// You can safely set this to u32::MAX if you set min_intensity instead
     pub max_bounces: u32,
 
     /// Floating point errors can cause visual artifacts in reflections and refraction.
@@ -117,6 +173,7 @@ pub struct QualityParameters {
     pub bias: f64
 }
  
+#[derive(Copy)]
 pub struct MaxSamples {
  
     /// Maximum number of rays that might be sent out when a reflective surface is hit
@@ -126,6 +183,7 @@ pub struct MaxSamples {
     pub refraction: u32
 }
 
+#[derive(Copy)]
 pub struct DoFParameters {
 
     /// Sensible Range: Low single digit degrees
@@ -135,10 +193,23 @@ pub struct DoFParameters {
     pub max_angle: f64,
 
     /// Number of samples that each pixel in the final image consists of. This setting
-    /// is ignored (and treated as 1) when max_angle is set to 0
+    /// is ignored (and treated as 1) when max_angle is set to 0 and the camera has no aperture
     pub samples: u32
 }
 
+#[derive(Copy)]
+pub struct AaParameters {
+
+    /// Number of jittered samples that each pixel consists of. If set to 1, all rays
+    /// go through the pixel center. Samples are shared with DoF and path tracing:
+    /// A pixel always consists of the largest of the active sample counts
+    pub samples: u32,
+
+    /// How the samples are weighted and combined into the final pixel color
+    pub filter: PixelFilter
+}
+
+#[derive(Copy)]
 pub struct AoParameters {
 
     /// Range: 0-1
@@ -153,81 +224,287 @@ pub struct AoParameters {
     pub samples: u32
 }
 
+#[derive(Copy)]
+pub struct LightParameters {
+
+    /// Range: 0-1
+    /// Fraction of the material color that is visible without any direct light.
+    /// Only has an effect if the scene contains lights
+    pub ambient: f64,
+
+    /// Number of shadow rays that are sent towards each area light (emissive spheres
+    /// and planes). More samples result in smoother soft shadows
+    pub samples: u32
+}
+
+#[derive(Copy)]
+pub struct PathParameters {
+
+    /// Number of paths that are traced for each pixel. Noise decreases with
+    /// the square root of this value. Only used by the path integrator
+    pub samples: u32,
+
+    /// Number of bounces after which paths can be terminated early by russian roulette.
+    /// Lower values render faster, but result in more noise in indirectly lit areas
+    pub min_bounces: u32
+}
+
+#[derive(Copy)]
+pub struct DisplayParameters {
+
+    /// Unit: Stops
+    /// Brightens (positive) or darkens (negative) the image before tone mapping.
+    /// Only applies to PNG and PPM output, HDR formats always store the raw values
+    pub exposure: f64,
+
+    /// How colors brighter than 1 are brought into displayable range
+    pub tone_map: ToneMap,
+
+    /// Encodes the output with the sRGB transfer function instead of storing
+    /// linear values. Recommended, but brightens scenes that were tuned without it
+    pub srgb: bool,
+
+    /// Noise that hides banding in smooth gradients
+    pub dither: Dither
+}
+
+/// Extra buffers that are rendered alongside the final image. EXR output stores
+/// them as layers in the same file, other formats as separate files
+#[derive(Copy, Default)]
+pub struct AovParameters {
+
+    /// Distance from the camera to the first hit
+    pub depth: bool,
+
+    /// World space surface normal at the first hit
+    pub normal: bool,
+
+    /// Unlit material color at the first hit
+    pub albedo: bool,
+
+    /// Texture coordinates at the first hit
+    pub uv: bool,
+
+    /// Index of the first hit object, starting at 1 for the first object in the config
+    pub object_id: bool,
+
+    /// Ambient occlusion at full strength, using ao.distance and ao.samples
+    pub ao: bool,
+
+    /// Light that arrives through a reflection at the first hit
+    pub reflection: bool,
+
+    /// Light that arrives through a refraction at the first hit
+    pub refraction: bool,
+
+    /// Number of samples that were taken for each pixel. Mostly interesting
+    /// together with adaptive sampling
+    pub samples: bool
+}
+
+#[derive(Copy)]
+pub struct TileParameters {
+
+    /// Unit: Pixels
+    /// Width and height of the square tiles that the image is split into. Every tile
+    /// is rendered by a single thread
+    pub size: u32,
+
+    /// Order in which the tiles are rendered
+    pub order: TileOrder
+}
+
+#[derive(Copy)]
+pub struct AdaptiveParameters {
+
+    /// Renders the regular number of samples per pixel first, then keeps adding
+    /// batches of the same size to the pixels that are still noisy
+    pub enabled: bool,
+
+    /// Range: Positive, usually below 0.1
+    /// A pixel is done once the estimated error of its brightness, relative to the
+    /// square root of the brightness, falls below this value. Lower values give less
+    /// noise, but take longer
+    pub threshold: f64,
+
+    /// Upper limit for the number of samples of a single pixel
+    pub max_samples: u32
+}
+
+#[derive(Copy)]
+pub struct ProgressiveParameters {
+
+    /// Renders the image in passes of the regular number of samples per pixel, which
+    /// all add to the image, until one of the limits below is reached
+    pub enabled: bool,
+
+    /// Samples per pixel after which the render is done. 0 means no limit
+    pub samples: u32,
+
+    /// Range: Positive
+    /// In seconds. The current pass is stopped when the time is up. 0 means no limit
+    pub time_limit: f64,
+
+    /// Range: Positive
+    /// In seconds. The command line renderer writes the current image to the output
+    /// file this often. Passes that take longer are interrupted after the tile that
+    /// exceeds it. 0 disables snapshots
+    pub snapshot_interval: f64
+}
+
 );
 
 impl RenderParams {
 
-    pub fn validate(&self) -> bool {
+    /// A pixel consists of the largest of the sample counts that are active with this camera
+    pub fn samples_per_pixel(&self, camera: &Camera) -> u32 {
+
+        let mut samples = self.aa.samples;
+
+        if self.dof.max_angle != 0.0 || camera.has_lens() {
+            samples = samples.max(self.dof.samples);
+        }
+
+        if self.integrator == Integrator::Path {
+            samples = samples.max(self.path.samples);
+        }
 
-        let mut success = true;
+        samples
+    }
+
+    /// True for progressive renders that only end when they are stopped from the outside.
+    /// Adaptive sampling limits them with adaptive.max_samples
+    pub fn is_unlimited(&self) -> bool {
+        self.progressive.enabled
+            && self.progressive.samples == 0
+            && self.progressive.time_limit == 0.0
+            && !self.adaptive.enabled
+    }
+
+    /// Checks that depend on the camera, which isn't known yet while the scene is parsed
+    pub fn validate_for_camera(&self, camera: &Camera, diagnostics: &mut Diagnostics) {
+
+        if self.adaptive.enabled && self.adaptive.max_samples <= self.samples_per_pixel(camera) {
+            diagnostics.warning("Adaptive sampling has no effect if adaptive.max_samples is not larger than the samples per pixel");
+        }
+    }
+
+    pub fn validate(&self, diagnostics: &mut Diagnostics) {
 
         // Quality settings
 
         if !util::is_in_range(self.quality.min_intensity, 0.0, 1.0) {
-            println!("Error: Minimum intensity needs to be within 0-1 range");
-            success = false;
+            diagnostics.error("Minimum intensity needs to be within 0-1 range");
         }
 
         if self.quality.max_bounces == 0 {
-            println!("Warning: Reflections won't work with 0 max_bounces");
+            diagnostics.warning("Reflections won't work with 0 max_bounces");
         }
 
         if self.quality.max_bounces < 2 {
-            println!("Warning: Refraction won't work properly with less than 2 max_bounces");
+            diagnostics.warning("Refraction won't work properly with less than 2 max_bounces");
         }
 
-        if !util::is_in_range(self.quality.bias, 0.0, std::f64::INFINITY) {
-            println!("Error: Float correction bias must be 0 or positive");
-            success = false;
+        if !util::is_in_range(self.quality.bias, 0.0, f64::INFINITY) {
+            diagnostics.error("Float correction bias must be 0 or positive");
         }
 
         // DoF
 
         if !util::is_in_range(self.dof.max_angle, 0.0, 360.0) {
-            println!("Error: dof.max_angle needs to be between 0 and 360 degrees");
-            success = false;
+            diagnostics.error("dof.max_angle needs to be between 0 and 360 degrees");
         }
 
         if self.dof.max_angle != 0.0 && self.dof.samples == 0 {
-            println!("Warning: Image will render black because of zero DoF samples, but non-zero DoF max angle.")
+            diagnostics.warning("Image will render black because of zero DoF samples, but non-zero DoF max angle.");
+        }
+
+        // Anti-Aliasing
+
+        if self.aa.samples == 0 {
+            diagnostics.warning("Image will render black because of zero AA samples");
         }
 
         // Sample Limits
 
         if self.max_samples.reflection == 0 {
-            println!("Warning: Reflections will not work when max_reflection_samples is 0");
+            diagnostics.warning("Reflections will not work when max_reflection_samples is 0");
         }
 
         if self.max_samples.refraction == 0 {
-            println!("Warning: Refraction won't work when max_refraction_samples is 0");
+            diagnostics.warning("Refraction won't work when max_refraction_samples is 0");
         }
 
         // Ao
 
         if !util::is_in_range(self.ao.strength, 0.0, 1.0) {
-            println!("Error: AO strength must be in range 0-1");
-            success = false;
+            diagnostics.error("AO strength must be in range 0-1");
         }
 
-        if !util::is_in_range(self.ao.distance, 0.0, std::f64::INFINITY) {
-            println!("Error: AO distance must be 0 or positive");
-            success = false;
+        if !util::is_in_range(self.ao.distance, 0.0, f64::INFINITY) {
+            diagnostics.error("AO distance must be 0 or positive");
         }
 
         if self.ao.strength > 0.0 {
 
             if self.ao.distance == 0.0 {
-                println!("Warning: AO will not work if distance is 0");
+                diagnostics.warning("AO will not work if distance is 0");
             }
 
             if self.ao.samples == 0 {
-                println!("Warning: AO will not work if samples are 0");
+                diagnostics.warning("AO will not work if samples are 0");
             }
         }
 
-        success = success && self.sky_color.validate();
+        // Lights
+
+        if !util::is_in_range(self.lights.ambient, 0.0, f64::INFINITY) {
+            diagnostics.error("Ambient light must be 0 or positive");
+        }
+
+        if self.lights.samples == 0 {
+            diagnostics.warning("Area lights will not work if light samples are 0");
+        }
+
+        // Path tracing
+
+        if self.integrator == Integrator::Path && self.path.samples == 0 {
+            diagnostics.warning("Image will render black because of zero path samples");
+        }
+
+        // Tiles
+
+        if self.tiles.size == 0 {
+            diagnostics.error("tiles.size must be at least 1 pixel");
+        }
+
+        // Adaptive sampling
+
+        if !util::is_in_range(self.adaptive.threshold, 0.0, f64::INFINITY) {
+            diagnostics.error("adaptive.threshold must be 0 or positive");
+        }
+
+        // Progressive rendering
+
+        if !util::is_in_range(self.progressive.time_limit, 0.0, f64::MAX) {
+            diagnostics.error("progressive.time_limit must be 0 or positive");
+        }
+
+        if !util::is_in_range(self.progressive.snapshot_interval, 0.0, f64::MAX) {
+            diagnostics.error("progressive.snapshot_interval must be 0 or positive");
+        }
+
+        if self.is_unlimited() {
+            diagnostics.warning("Progressive render has no sample or time limit and only stops when it is interrupted");
+        }
+
+        // Display
+
+        if !self.display.exposure.is_finite() {
+            diagnostics.error("display.exposure must be a finite number");
+        }
 
-        success
+        self.sky_color.validate(diagnostics);
     }
 
     pub fn preset_sketch() -> RenderParams {
@@ -242,6 +519,8 @@ impl RenderParams {
         rp.dof.samples = 1;
         rp.ao.strength = 0.0;
         rp.ao.samples = 0;
+        rp.lights.samples = 1;
+        rp.path.samples = 4;
 
         rp
     }
@@ -254,6 +533,8 @@ impl RenderParams {
         rp.quality.max_bounces = 2;
         rp.ao.strength = 0.0;
         rp.ao.samples = 1;
+        rp.lights.samples = 2;
+        rp.path.samples = 16;
 
         rp
     }
@@ -268,7 +549,10 @@ impl RenderParams {
 
         rp.quality.max_bounces = 6;
         rp.dof.samples = 40;
+        rp.aa.samples = 4;
         rp.ao.samples = 4;
+        rp.lights.samples = 8;
+        rp.path.samples = 256;
 
         rp
     }
@@ -278,9 +562,12 @@ impl RenderParams {
         let mut rp = RenderParams::default();
 
         rp.quality.min_intensity = 0.01;
-        rp.quality.max_bounces = std::u32::MAX;
+        rp.quality.max_bounces = u32::MAX;
         rp.dof.samples = 70;
+        rp.aa.samples = 16;
         rp.ao.samples = 6;
+        rp.lights.samples = 16;
+        rp.path.samples = 1024;
 
         rp
     }
@@ -316,6 +603,15 @@ impl Default for DoFParameters {
     }
 }
 
+impl Default for AaParameters {
+    fn default() -> Self {
+        AaParameters {
+            samples: 1,
+            filter: PixelFilter::Box
+        }
+    }
+}
+
 impl Default for AoParameters {
     fn default() -> Self {
         AoParameters {
@@ -324,4 +620,62 @@ impl Default for AoParameters {
             samples: 2
         }
     }
-}
\ No newline at end of file
+}
+impl Default for LightParameters {
+    fn default() -> Self {
+        LightParameters {
+            ambient: 0.15,
+            samples: 4
+        }
+    }
+}
+
+impl Default for DisplayParameters {
+    fn default() -> Self {
+        DisplayParameters {
+            exposure: 0.0,
+            tone_map: ToneMap::None,
+            srgb: false,
+            dither: Dither::None
+        }
+    }
+}
+
+impl Default for TileParameters {
+    fn default() -> Self {
+        TileParameters {
+            size: 32,
+            order: TileOrder::Scanline
+        }
+    }
+}
+
+impl Default for ProgressiveParameters {
+    fn default() -> Self {
+        ProgressiveParameters {
+            enabled: false,
+            samples: 0,
+            time_limit: 0.0,
+            snapshot_interval: 0.0
+        }
+    }
+}
+
+impl Default for AdaptiveParameters {
+    fn default() -> Self {
+        AdaptiveParameters {
+            enabled: false,
+            threshold: 0.05,
+            max_samples: 256
+        }
+    }
+}
+
+impl Default for PathParameters {
+    fn default() -> Self {
+        PathParameters {
+            samples: 64,
+            min_bounces: 3
+        }
+    }
+}
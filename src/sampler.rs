@@ -0,0 +1,469 @@
+// Sample points for everything that sends out rays in random directions: AO, glossy
+// reflection and refraction, DoF and the path integrator
+
+use crate::display;
+use crate::util;
+use crate::vec::*;
+use rand::prelude::*;
+use serde::Deserialize;
+
+/// Way that the points in the unit square are distributed, which are then mapped to
+/// directions or lens positions
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SamplerType {
+    /// Independent random numbers. Clumps together and leaves holes. The default, so
+    /// scenes that don't choose a sampler keep their noise pattern
+    #[default]
+    Random,
+
+    /// One random point in every cell of a grid
+    Stratified,
+
+    /// Halton sequence with a random shift per pixel
+    Halton,
+
+    /// Sobol sequence with Owen scrambling. Converges fastest for most scenes
+    Sobol,
+
+    /// Sobol sequence that is shifted by a blue noise texture, so the remaining noise
+    /// is spread evenly over the image instead of forming blotches
+    BlueNoise,
+}
+
+/// Hands out the sample points of a single camera sample. Also works as a regular random
+/// number generator for everything that doesn't need well distributed samples
+pub struct Sampler {
+    sampler_type: SamplerType,
+    rng: SmallRng,
+
+    seed: u64,
+    pixel_seed: u64,
+    x: usize,
+    y: usize,
+
+    sample_index: u32,
+    sample_count: u32,
+
+    // Every call to next_2d uses the next dimension of the sequence
+    dimension: u32,
+}
+
+// Bases for the Halton sequence, two per dimension. Dimensions beyond this fall back to
+// random numbers, because high bases are badly distributed anyway
+const PRIMES: [u32; 32] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89,
+    97, 101, 103, 107, 109, 113, 127, 131,
+];
+
+impl Sampler {
+    /// The sampler for the sample with the given index, out of sample_count samples
+    /// of the pixel at (x, y). Samplers for the same arguments always return the same points
+    pub fn new(
+        sampler_type: SamplerType,
+        seed: u64,
+        (x, y): (usize, usize),
+        width: usize,
+        sample_index: u32,
+        sample_count: u32,
+    ) -> Sampler {
+        let pixel_index = (y * width + x) as u64;
+
+        Sampler {
+            sampler_type,
+            rng: SmallRng::seed_from_u64(util::hash(seed, &[pixel_index, sample_index as u64])),
+            seed,
+            pixel_seed: util::hash(seed, &[pixel_index]),
+            x,
+            y,
+            sample_index,
+            sample_count: sample_count.max(1),
+            dimension: 0,
+        }
+    }
+
+    /// Point in the unit square for the next decision along the camera ray. The n-th call
+    /// of all samples of a pixel together gives a well distributed set of points
+    pub fn next_2d(&mut self) -> [f64; 2] {
+        let dimension = self.dimension;
+        self.dimension += 1;
+
+        if self.sampler_type == SamplerType::BlueNoise {
+            // The same scrambling for every pixel, but a different shift from the
+            // blue noise texture in every dimension
+            let [u, v] = sobol_2d(
+                self.sample_index,
+                util::hash(self.seed, &[dimension as u64]),
+            );
+
+            let (x, y) = (self.x + 23 * dimension as usize, self.y + 41 * dimension as usize);
+
+            return [
+                (u + display::blue_noise(x, y)).fract(),
+                (v + display::blue_noise(x + 32, y + 32)).fract(),
+            ];
+        }
+
+        let scramble = util::hash(self.pixel_seed, &[dimension as u64]);
+
+        self.point(self.sample_index, self.sample_count, dimension, scramble)
+    }
+
+    /// Set of well distributed points for rays that are all sent out at once, like the
+    /// AO rays of a single hit. The blue noise sampler uses Sobol points here
+    pub fn points_2d(&mut self, count: u32) -> Vec<[f64; 2]> {
+        let scramble = self.rng.gen();
+
+        (0..count)
+            .map(|index| self.point(index, count, 0, scramble))
+            .collect()
+    }
+
+    fn point(&mut self, index: u32, count: u32, dimension: u32, scramble: u64) -> [f64; 2] {
+        match self.sampler_type {
+            SamplerType::Random => [self.rng.gen(), self.rng.gen()],
+            SamplerType::Stratified => {
+                let columns = (count as f64).sqrt().ceil() as u32;
+                let cells = columns * columns;
+
+                // Visit the cells in a random order, which is different for every dimension
+                let cell = permute(index % cells, cells, scramble as u32);
+
+                [
+                    ((cell % columns) as f64 + self.rng.gen::<f64>()) / columns as f64,
+                    ((cell / columns) as f64 + self.rng.gen::<f64>()) / columns as f64,
+                ]
+            }
+            SamplerType::Halton => {
+                let bases = PRIMES.get(2 * dimension as usize..2 * dimension as usize + 2);
+
+                match bases {
+                    Some(&[base_u, base_v]) => {
+                        // Cranley-Patterson rotation: The whole point set is shifted by a
+                        // random offset and wraps around
+                        let shift_u = to_unit(util::hash(scramble, &[0]));
+                        let shift_v = to_unit(util::hash(scramble, &[1]));
+
+                        [
+                            (radical_inverse(base_u, index) + shift_u).fract(),
+                            (radical_inverse(base_v, index) + shift_v).fract(),
+                        ]
+                    }
+                    _ => [self.rng.gen(), self.rng.gen()],
+                }
+            }
+            SamplerType::Sobol | SamplerType::BlueNoise => sobol_2d(index, scramble),
+        }
+    }
+}
+
+impl RngCore for Sampler {
+    fn next_u32(&mut self) -> u32 {
+        self.rng.next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.rng.next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.rng.fill_bytes(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.rng.try_fill_bytes(dest)
+    }
+}
+
+/// Maps a point in the unit square to a direction inside of a cone around the axis.
+/// The directions are spread evenly over the solid angle of the cone
+pub fn uniform_cone([u, v]: [f64; 2], max_angle: f64, axis: Vec3Norm) -> Vec3Norm {
+    let cos_max = max_angle.min(180.0).to_radians().cos();
+
+    let cos_theta = 1.0 - u * (1.0 - cos_max);
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = v * 2.0 * std::f64::consts::PI;
+
+    from_local(axis, sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+}
+
+/// Maps a point in the unit square to a direction on the hemisphere around the normal,
+/// with a density proportional to the cosine of the angle to the normal
+pub fn cosine_hemisphere([u, v]: [f64; 2], normal: Vec3Norm) -> Vec3Norm {
+    let r = u.sqrt();
+    let phi = v * 2.0 * std::f64::consts::PI;
+
+    from_local(normal, r * phi.cos(), r * phi.sin(), (1.0 - u).max(0.0).sqrt())
+}
+
+// Converts coordinates in a frame where z points along the axis to world space
+fn from_local(axis: Vec3Norm, x: f64, y: f64, z: f64) -> Vec3Norm {
+    let tangent = axis.get_random_90_deg_vector().normalized();
+    let bitangent = axis.cross(tangent);
+
+    (tangent * x + bitangent * y + axis * z).normalized()
+}
+
+fn to_unit(bits: u64) -> f64 {
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+fn radical_inverse(base: u32, mut index: u32) -> f64 {
+    let inv_base = 1.0 / base as f64;
+    let mut factor = inv_base;
+    let mut result = 0.0;
+
+    while index > 0 {
+        result += (index % base) as f64 * factor;
+        index /= base;
+        factor *= inv_base;
+    }
+
+    result
+}
+
+// First two dimensions of the Sobol sequence with hash based Owen scrambling. The index
+// is scrambled too, so different seeds also give the points in a different order.
+// See Burley 2020, "Practical Hash-based Owen Scrambling"
+fn sobol_2d(index: u32, seed: u64) -> [f64; 2] {
+    let index = nested_uniform_scramble(index, util::hash(seed, &[0]) as u32);
+
+    // The first dimension is the van der Corput sequence, the second one has the
+    // direction numbers v_k+1 = v_k ^ (v_k >> 1)
+    let u = index.reverse_bits();
+
+    let mut v = 0;
+    let mut direction = 1u32 << 31;
+    let mut bits = index;
+
+    while bits != 0 {
+        if bits & 1 != 0 {
+            v ^= direction;
+        }
+
+        bits >>= 1;
+        direction ^= direction >> 1;
+    }
+
+    let u = nested_uniform_scramble(u, util::hash(seed, &[1]) as u32);
+    let v = nested_uniform_scramble(v, util::hash(seed, &[2]) as u32);
+
+    let scale = 1.0 / (1u64 << 32) as f64;
+
+    [u as f64 * scale, v as f64 * scale]
+}
+
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+// Hash that only lets bits influence higher bits. Applied to a bit-reversed number,
+// this is a random permutation of every subtree of binary digits
+fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+    x ^= x.wrapping_mul(0x3d20_adea);
+    x = x.wrapping_add(seed);
+    x = x.wrapping_mul((seed >> 16) | 1);
+    x ^= x.wrapping_mul(0x0552_6c56);
+    x ^= x.wrapping_mul(0x53a2_2864);
+
+    x
+}
+
+// Random permutation of 0..length that is different for every seed, without building a
+// table. See Kensler 2013, "Correlated Multi-Jittered Sampling"
+fn permute(mut index: u32, length: u32, seed: u32) -> u32 {
+    let mut mask = length - 1;
+    mask |= mask >> 1;
+    mask |= mask >> 2;
+    mask |= mask >> 4;
+    mask |= mask >> 8;
+    mask |= mask >> 16;
+
+    // Permutes inside of the next power of two until the result is in range
+    loop {
+        index ^= seed;
+        index = index.wrapping_mul(0xe170_893d);
+        index ^= seed >> 16;
+        index ^= (index & mask) >> 4;
+        index ^= seed >> 8;
+        index = index.wrapping_mul(0x0929_eb3f);
+        index ^= seed >> 23;
+        index ^= (index & mask) >> 1;
+        index = index.wrapping_mul(1 | seed >> 27);
+        index = index.wrapping_mul(0x6935_fa69);
+        index ^= (index & mask) >> 11;
+        index = index.wrapping_mul(0x74dc_b303);
+        index ^= (index & mask) >> 2;
+        index = index.wrapping_mul(0x9e50_1cc3);
+        index ^= (index & mask) >> 2;
+        index = index.wrapping_mul(0xc860_a3df);
+        index &= mask;
+        index ^= index >> 5;
+
+        if index < length {
+            break;
+        }
+    }
+
+    index.wrapping_add(seed) % length
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+
+    const COUNT: usize = 40000;
+
+    fn random_points(seed: u64) -> Vec<[f64; 2]> {
+        let mut rng = SmallRng::seed_from_u64(seed);
+        (0..COUNT).map(|_| [rng.gen(), rng.gen()]).collect()
+    }
+
+    fn axis() -> Vec3Norm {
+        Vec3::new(1.0, -2.0, 0.5).normalized()
+    }
+
+    // Angle around the axis, in the range 0-1
+    fn azimuth(direction: Vec3Norm) -> f64 {
+        let tangent = axis().cross(Vec3Norm::UP).normalized();
+        let bitangent = axis().cross(tangent);
+
+        (direction.dot(bitangent).atan2(direction.dot(tangent)) / (2.0 * PI)).rem_euclid(1.0)
+    }
+
+    // Every bin gets roughly the same share of the values in the range 0-1
+    fn assert_uniform(values: impl Iterator<Item = f64>, bins: usize) {
+        let mut counts = vec![0; bins];
+
+        for value in values {
+            counts[((value * bins as f64) as usize).min(bins - 1)] += 1;
+        }
+
+        let expected = COUNT as f64 / bins as f64;
+
+        for &count in &counts {
+            assert!((count as f64 - expected).abs() < 0.1 * expected, "{:?}", counts);
+        }
+    }
+
+    #[test]
+    fn uniform_cone_is_uniform_over_the_solid_angle() {
+        for &max_angle in &[10.0, 60.0, 180.0] {
+            let cos_max = f64::cos(f64::to_radians(max_angle));
+
+            let directions = random_points(1)
+                .into_iter()
+                .map(|p| uniform_cone(p, max_angle, axis()))
+                .collect::<Vec<_>>();
+
+            for direction in &directions {
+                assert!(direction.dot(axis()) >= cos_max - 1e-9);
+            }
+
+            // The solid angle of a cap grows linearly with 1 - cos(theta)
+            assert_uniform(directions.iter().map(|d| (1.0 - d.dot(axis())) / (1.0 - cos_max)), 10);
+            assert_uniform(directions.iter().map(|&d| azimuth(d)), 8);
+        }
+    }
+
+    #[test]
+    fn cosine_hemisphere_is_cosine_weighted() {
+        let directions = random_points(2)
+            .into_iter()
+            .map(|p| cosine_hemisphere(p, axis()))
+            .collect::<Vec<_>>();
+
+        for direction in &directions {
+            assert!(direction.dot(axis()) >= -1e-9);
+        }
+
+        // With a density of cos(theta) / pi, cos²(theta) is uniformly distributed
+        assert_uniform(directions.iter().map(|d| d.dot(axis()).powi(2)), 10);
+        assert_uniform(directions.iter().map(|&d| azimuth(d)), 8);
+
+        let mean_cos = directions.iter().map(|d| d.dot(axis())).sum::<f64>() / COUNT as f64;
+        assert!((mean_cos - 2.0 / 3.0).abs() < 0.01, "{}", mean_cos);
+    }
+
+    // Checks that every cell of every grid with 2^m cells in total contains exactly one point
+    fn assert_net(points: &[[f64; 2]]) {
+        let m = points.len().trailing_zeros();
+        assert_eq!(points.len(), 1 << m);
+
+        for x_bits in 0..=m {
+            let (columns, rows) = (1 << x_bits, 1 << (m - x_bits));
+            let mut cells = vec![0; points.len()];
+
+            for &[u, v] in points {
+                let column = (u * columns as f64) as usize;
+                let row = (v * rows as f64) as usize;
+
+                cells[row * columns + column] += 1;
+            }
+
+            assert!(cells.iter().all(|&c| c == 1), "{}x{} grid", columns, rows);
+        }
+    }
+
+    #[test]
+    fn scrambled_sobol_points_are_stratified() {
+        for seed in 0..10 {
+            for &count in &[16, 64, 256] {
+                let points = (0..count).map(|i| sobol_2d(i, seed)).collect::<Vec<_>>();
+                assert_net(&points);
+            }
+        }
+
+        // Different seeds give different points
+        assert_ne!(sobol_2d(3, 1), sobol_2d(3, 2));
+    }
+
+    #[test]
+    fn samplers_stratify_the_samples_of_a_pixel() {
+        // Halton and blue noise shift the points, which keeps them evenly spread, but
+        // not aligned with the grid
+        for &sampler_type in &[SamplerType::Stratified, SamplerType::Sobol] {
+            for dimension in 0..4 {
+                let points = (0..16)
+                    .map(|index| {
+                        let mut sampler = Sampler::new(sampler_type, 5, (3, 7), 10, index, 16);
+
+                        (0..dimension).for_each(|_| {
+                            sampler.next_2d();
+                        });
+
+                        sampler.next_2d()
+                    })
+                    .collect::<Vec<_>>();
+
+                // A 4x4 grid for all of them
+                let mut cells = [0; 16];
+
+                for &[u, v] in &points {
+                    cells[(v * 4.0) as usize * 4 + (u * 4.0) as usize] += 1;
+                }
+
+                assert!(cells.iter().all(|&c| c == 1), "{:?} {}: {:?}", sampler_type, dimension, cells);
+            }
+        }
+    }
+
+    #[test]
+    fn samplers_are_reproducible() {
+        let points = |sampler_type| {
+            let mut sampler = Sampler::new(sampler_type, 9, (1, 2), 4, 3, 8);
+            (0..5).map(|_| sampler.next_2d()).collect::<Vec<_>>()
+        };
+
+        for &sampler_type in &[
+            SamplerType::Random,
+            SamplerType::Stratified,
+            SamplerType::Halton,
+            SamplerType::Sobol,
+            SamplerType::BlueNoise,
+        ] {
+            assert_eq!(points(sampler_type), points(sampler_type));
+        }
+    }
+}
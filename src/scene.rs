@@ -1,29 +1,196 @@
+use super::bvh::*;
+use super::diagnostics::Diagnostics;
+use super::environment::Environment;
+use super::light::Light;
 use super::ray_target::*;
+use super::raytracing::Ray;
 use super::uv_mappers::*;
+use std::sync::OnceLock;
 
 pub trait SceneObject: RayTarget + HasUvMapper + Send + Sync {}
-impl<'a, X> SceneObject for X where X: RayTarget + HasUvMapper + Send + Sync {}
+impl<X> SceneObject for X where X: RayTarget + HasUvMapper + Send + Sync {}
 
-// TODO: Remove pub
 pub struct Scene {
-    pub objects: Vec<Box<dyn SceneObject>>,
+    objects: Vec<Box<dyn SceneObject>>,
+
+    lights: Vec<Light>,
+
+    // Indices of objects that are sampled as area lights
+    area_lights: Vec<usize>,
+
+    environment: Option<Environment>,
+
+    // Built lazily on the first intersection query after the last call to add()
+    acceleration: OnceLock<Acceleration>,
+}
+
+struct Acceleration {
+    bvh: Bvh,
+
+    // Maps bvh primitive indices to object indices
+    bounded: Vec<usize>,
+
+    // Objects without a bounding box are always tested
+    unbounded: Vec<usize>,
 }
 
 impl Scene {
     pub fn new() -> Scene {
-        Scene { objects: vec![] }
+        Scene {
+            objects: vec![],
+            lights: vec![],
+            area_lights: vec![],
+            environment: None,
+            acceleration: OnceLock::new(),
+        }
     }
 
     pub fn add<O>(&mut self, object: O)
     where
         O: 'static + SceneObject,
     {
-        self.objects.push(Box::new(object))
+        if Scene::is_area_light(&object) {
+            self.area_lights.push(self.objects.len());
+        }
+
+        self.objects.push(Box::new(object));
+        self.acceleration = OnceLock::new();
+    }
+
+    /// Objects in the order they were added
+    pub fn objects(&self) -> impl Iterator<Item = &dyn SceneObject> {
+        self.objects.iter().map(|obj| obj.as_ref())
+    }
+
+    /// Object with the index returned by `closest_hit_index`
+    pub fn object(&self, index: usize) -> &dyn SceneObject {
+        self.objects[index].as_ref()
     }
 
-    pub fn validate(&self) -> bool {
-        self.objects
-            .iter()
-            .all(|obj| obj.get_uv_mapper().validate())
+    /// Emissive geometry that supports surface sampling doubles as an area light
+    pub fn is_area_light(object: &dyn SceneObject) -> bool {
+        object.get_uv_mapper().is_emissive() && object.sample_surface(0.5, 0.5).is_some()
+    }
+
+    pub fn add_light<L: Into<Light>>(&mut self, light: L) {
+        self.lights.push(light.into());
+    }
+
+    pub fn lights(&self) -> &[Light] {
+        &self.lights
+    }
+
+    pub fn area_lights(&self) -> impl Iterator<Item = &dyn SceneObject> {
+        self.area_lights.iter().map(move |&i| self.objects[i].as_ref())
+    }
+
+    /// Replaces the sky color of the render parameters with an image that also lights the scene
+    pub fn set_environment(&mut self, environment: Environment) {
+        self.environment = Some(environment);
+    }
+
+    pub fn environment(&self) -> Option<&Environment> {
+        self.environment.as_ref()
+    }
+
+    /// Returns true if the scene contains any kind of light, including emissive area lights
+    /// and environment maps
+    pub fn has_lights(&self) -> bool {
+        !self.lights.is_empty() || !self.area_lights.is_empty() || self.environment.is_some()
+    }
+
+    pub fn validate(&self, diagnostics: &mut Diagnostics) {
+        for obj in &self.objects {
+            obj.get_uv_mapper().validate(diagnostics);
+        }
+
+        for light in &self.lights {
+            light.validate(diagnostics);
+        }
+
+        if let Some(environment) = &self.environment {
+            environment.validate(diagnostics);
+        }
+    }
+
+    /// Returns the closest object hit by the ray among those that pass the filter.
+    /// If multiple objects are hit at exactly the same distance, the one added first wins.
+    pub fn closest_hit<F>(&self, ray: &Ray, filter: F) -> Option<(&dyn SceneObject, GeometryHitInfo)>
+    where
+        F: Fn(&dyn SceneObject) -> bool,
+    {
+        self.closest_hit_index(ray, filter)
+            .map(|(obj_index, hit)| (self.objects[obj_index].as_ref(), hit))
+    }
+
+    /// Same as `closest_hit`, but returns the index of the object in the order it was added
+    pub fn closest_hit_index<F>(&self, ray: &Ray, filter: F) -> Option<(usize, GeometryHitInfo)>
+    where
+        F: Fn(&dyn SceneObject) -> bool,
+    {
+        let acceleration = self.acceleration.get_or_init(|| self.build_acceleration());
+
+        let test = |obj_index: usize| {
+            let obj = &self.objects[obj_index];
+
+            if !filter(obj.as_ref()) {
+                return None;
+            }
+
+            obj.test_intersection(ray).map(|hit| {
+                let sqr_dist = (hit.position - ray.origin).sqr_length();
+                (hit, sqr_dist)
+            })
+        };
+
+        let mut closest = acceleration
+            .bvh
+            .closest_hit(ray, |i| test(acceleration.bounded[i]))
+            .map(|(i, hit, sqr_dist)| (acceleration.bounded[i], hit, sqr_dist));
+
+        for &obj_index in &acceleration.unbounded {
+            if let Some((hit, sqr_dist)) = test(obj_index) {
+                let is_closer = match closest {
+                    Some((best_index, _, best_sqr_dist)) => {
+                        sqr_dist < best_sqr_dist
+                            || (sqr_dist == best_sqr_dist && obj_index < best_index)
+                    }
+                    None => true,
+                };
+
+                if is_closer {
+                    closest = Some((obj_index, hit, sqr_dist));
+                }
+            }
+        }
+
+        closest.map(|(obj_index, hit, _)| (obj_index, hit))
+    }
+
+    fn build_acceleration(&self) -> Acceleration {
+        let mut bounded = vec![];
+        let mut bounds = vec![];
+        let mut unbounded = vec![];
+
+        for (index, obj) in self.objects.iter().enumerate() {
+            if let Some(aabb) = obj.bounding_box() {
+                bounded.push(index);
+                bounds.push(aabb.padded());
+            } else {
+                unbounded.push(index);
+            }
+        }
+
+        Acceleration {
+            bvh: Bvh::build(&bounds),
+            bounded,
+            unbounded,
+        }
+    }
+}
+
+impl Default for Scene {
+    fn default() -> Self {
+        Scene::new()
     }
 }
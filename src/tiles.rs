@@ -0,0 +1,117 @@
+// Splits the image into rectangular tiles that are rendered independently
+
+use serde::Deserialize;
+
+/// Order in which the tiles of an image are handed out to the render threads
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TileOrder {
+    /// Row by row, from the top left to the bottom right
+    #[default]
+    Scanline,
+
+    /// Outwards from the center of the image, so the interesting part is done first
+    Spiral,
+
+    /// Along a Hilbert curve. Consecutive tiles are always neighbours, which is
+    /// friendly to caches
+    Hilbert,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Tile {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Tile {
+    /// Pixel coordinates of the tile, row by row
+    pub fn pixels(self) -> impl Iterator<Item = (usize, usize)> {
+        (self.y..self.y + self.height)
+            .flat_map(move |y| (self.x..self.x + self.width).map(move |x| (x, y)))
+    }
+}
+
+/// Covers the image with square tiles of the given size. Tiles at the right and bottom
+/// edges are cut off where the image ends
+pub fn gen_tiles(width: usize, height: usize, size: usize, order: TileOrder) -> Vec<Tile> {
+    assert!(size > 0);
+
+    let columns = width.div_ceil(size);
+    let rows = height.div_ceil(size);
+
+    let mut grid = (0..rows)
+        .flat_map(|row| (0..columns).map(move |column| (column, row)))
+        .collect::<Vec<_>>();
+
+    match order {
+        TileOrder::Scanline => {}
+        TileOrder::Spiral => {
+            // Rings of tiles around the center, each ring in clockwise order
+            let center_x = (columns as f64 - 1.0) / 2.0;
+            let center_y = (rows as f64 - 1.0) / 2.0;
+
+            let ring = |&(column, row): &(usize, usize)| {
+                let dx = column as f64 - center_x;
+                let dy = row as f64 - center_y;
+
+                (dx.abs().max(dy.abs()), dy.atan2(dx))
+            };
+
+            grid.sort_by(|a, b| {
+                let (ring_a, angle_a) = ring(a);
+                let (ring_b, angle_b) = ring(b);
+
+                ring_a.total_cmp(&ring_b).then(angle_a.total_cmp(&angle_b))
+            });
+        }
+        TileOrder::Hilbert => {
+            let curve_size = columns.max(rows).next_power_of_two();
+
+            grid.sort_by_key(|&(column, row)| hilbert_index(curve_size, column, row));
+        }
+    }
+
+    grid.into_iter()
+        .map(|(column, row)| {
+            let x = column * size;
+            let y = row * size;
+
+            Tile {
+                x,
+                y,
+                width: size.min(width - x),
+                height: size.min(height - y),
+            }
+        })
+        .collect()
+}
+
+// Position of the cell on a Hilbert curve that fills a square with power of two size
+fn hilbert_index(size: usize, mut x: usize, mut y: usize) -> usize {
+    let mut index = 0;
+    let mut s = size / 2;
+
+    while s > 0 {
+        let rx = (x & s > 0) as usize;
+        let ry = (y & s > 0) as usize;
+
+        index += s * s * ((3 * rx) ^ ry);
+
+        // Rotate the quadrant, so the curve inside of it has the right orientation
+        if ry == 0 {
+            if rx == 1 {
+                x = size - 1 - x;
+                y = size - 1 - y;
+            }
+
+            std::mem::swap(&mut x, &mut y);
+        }
+
+        s /= 2;
+    }
+
+    index
+}
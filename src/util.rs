@@ -8,4 +8,94 @@ pub fn is_in_range_exclusive(v: f64, min: f64, max: f64) -> bool {
 
     v.is_finite() && v > min && v < max
 
-}
\ No newline at end of file
+}
+
+// SplitMix64 finalizer over all inputs. Neighbouring inputs give completely unrelated hashes
+pub fn hash(seed: u64, values: &[u64]) -> u64 {
+
+    let mut h = seed;
+
+    for &value in values {
+        h = (h ^ value).wrapping_add(0x9e37_79b9_7f4a_7c15);
+        h = (h ^ (h >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        h = (h ^ (h >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        h ^= h >> 31;
+    }
+
+    h
+
+}
+
+// This macro generates a deserializable version of the structs where any member is
+// wrapped inside of an Option. This allows us to deserialize a kind of 'override-struct'
+// which can then be used to override only specific values inside of the full struct.
+// Override-structs can also inherit the values that they don't set from each other,
+// which is how `extends` works in scene files.
+// Only one invocation per module is possible, because all override-structs end up in
+// the same override_structs module. Add #[derive(Copy)] to structs that should be Copy
+
+macro_rules! generate_optional_variant {
+    (
+    $(
+    $(#[$outer:meta])*
+    pub struct $name:ident
+    {
+        $(
+        $(#[$inner:meta])*
+        pub $field:ident : $t:ty
+        ),*
+    })*
+    ) => {
+        $(
+        $(#[$outer])*
+        #[derive(Clone, Debug, Deserialize)]
+        #[serde(default)]
+        #[serde(deny_unknown_fields)]
+        pub struct $name {
+            $(
+            $(#[$inner])*
+            pub $field : $t,
+            )*
+        }
+
+        impl $name {
+            pub fn override_with(&mut self, or: &override_structs::$name) {
+                $(
+                    if let Some($field) = &or.$field {
+                        self.$field = $field.clone();
+                    }
+                )*
+            }
+        }
+        )*
+
+        pub mod override_structs {
+            use super::*;
+
+            $(
+            #[derive(Default)]
+            #[derive(Clone, Debug, Deserialize)]
+            #[serde(default)]
+            #[serde(deny_unknown_fields)]
+            pub struct $name {
+                $(
+                pub $field : Option<$t>,
+                )*
+            }
+
+            impl $name {
+                /// Values that are not set here are taken from the parent
+                pub fn inherit_from(&mut self, parent: &$name) {
+                    $(
+                        if self.$field.is_none() {
+                            self.$field = parent.$field.clone();
+                        }
+                    )*
+                }
+            }
+            )*
+        }
+    }
+}
+
+pub(crate) use generate_optional_variant;
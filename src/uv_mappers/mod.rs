@@ -1,5 +1,6 @@
 use crate::material::*;
 use crate::color::*;
+use crate::diagnostics::Diagnostics;
 use crate::ray_target::*;
 
 mod texture_uv_mapper;
@@ -10,8 +11,11 @@ pub trait UvMapper: Send + Sync {
     
     fn get_material_at(&self, rch: &GeometryHitInfo) -> Material;
 
-    /// Should return true of the UvMapper contains (and can produce) only legal materials
-    fn validate(&self) -> bool;
+    /// Should report every illegal material that the UvMapper contains (or can produce)
+    fn validate(&self, diagnostics: &mut Diagnostics);
+
+    /// Should return true if the UvMapper can produce any emissive materials
+    fn is_emissive(&self) -> bool;
 }
 
 pub trait HasUvMapper {
@@ -30,8 +34,12 @@ impl UvMapper for StaticUvMapper /*where Self: Send + Sync*/ {
         self.0
     }
 
-    fn validate(&self) -> bool {
-        self.0.validate()
+    fn validate(&self, diagnostics: &mut Diagnostics) {
+        self.0.validate(diagnostics)
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.0.is_emissive()
     }
 }
 
@@ -51,8 +59,13 @@ impl UvMapper for CheckerboardUvMapper /*where Self: Send + Sync*/ {
         }
     }
 
-    fn validate(&self) -> bool {
-        self.0.validate() && self.1.validate()
+    fn validate(&self, diagnostics: &mut Diagnostics) {
+        self.0.validate(diagnostics);
+        self.1.validate(diagnostics);
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.0.is_emissive() || self.1.is_emissive()
     }
 }
 
@@ -64,7 +77,9 @@ impl UvMapper for DebugUvMapper /* where Self: Send + Sync*/ {
         Material::pure(RGBColor::new(rch.uv.u, rch.uv.v, 0.0))
     }
 
-    fn validate(&self) -> bool {
-        true
+    fn validate(&self, _diagnostics: &mut Diagnostics) {}
+
+    fn is_emissive(&self) -> bool {
+        false
     }
 }
\ No newline at end of file
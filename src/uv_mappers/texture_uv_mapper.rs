@@ -1,22 +1,18 @@
 use crate::uv_mappers::*;
+use crate::diagnostics::RenderError;
 use lodepng::*;
 use serde::Deserialize;
 use std::io;
 use std::path::Path;
 
-#[derive(Copy, Clone, Deserialize)]
+#[derive(Copy, Clone, Default, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub enum SamplingMethod {
     POINT,
+    #[default]
     BILINEAR,
 }
 
-impl Default for SamplingMethod {
-    fn default() -> Self {
-        SamplingMethod::BILINEAR
-    }
-}
-
 #[derive(Clone)]
 pub struct TextureUvMapper {
     base_mat: Material,
@@ -33,7 +29,7 @@ impl TextureUvMapper {
         sampling_method: SamplingMethod,
     ) -> Result<TextureUvMapper, io::Error> {
         let decoded =
-            decode24_file(filepath).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            decode24_file(filepath).map_err(io::Error::other)?;
 
         let pixels = decoded
             .buffer
@@ -47,13 +43,31 @@ impl TextureUvMapper {
             })
             .collect::<Vec<_>>();
 
-        assert!(decoded.width > 0 && decoded.height > 0);
+        Ok(TextureUvMapper::new(
+            pixels,
+            decoded.width,
+            decoded.height,
+            base_mat,
+            sampling_method,
+        )?)
+    }
+
+    /// Texture from pixels in row-major order, starting at the top left corner.
+    /// The color of the base material is replaced by the texture color
+    pub fn new(
+        pixels: Vec<RGBColor>,
+        width: usize,
+        height: usize,
+        base_mat: Material,
+        sampling_method: SamplingMethod,
+    ) -> Result<TextureUvMapper, RenderError> {
+        RenderError::check_image_size("Texture", width, height, pixels.len())?;
 
         Ok(TextureUvMapper {
             base_mat,
             pixels,
-            tex_width: decoded.width,
-            tex_height: decoded.height,
+            tex_width: width,
+            tex_height: height,
             sampling_method,
         })
     }
@@ -73,12 +87,13 @@ impl UvMapper for TextureUvMapper {
             }
 
             SamplingMethod::BILINEAR => {
-                // Get the four pixel coordinates needed for bilinear sampling
+                // Get the four pixel coordinates needed for bilinear sampling.
+                // The right and bottom ones are clamped for uvs of exactly 1 and one pixel wide textures
                 let x_left = w.floor() as usize;
-                let x_right = x_left + 1;
+                let x_right = (x_left + 1).min(self.tex_width - 1);
 
                 let y_top = h.floor() as usize;
-                let y_bottom = y_top + 1;
+                let y_bottom = (y_top + 1).min(self.tex_height - 1);
 
                 // The four colors we need to interpolate
                 let tl = self.pixels[x_left + y_top * self.tex_width];
@@ -105,7 +120,11 @@ impl UvMapper for TextureUvMapper {
         }
     }
 
-    fn validate(&self) -> bool {
-        self.base_mat.validate()
+    fn validate(&self, diagnostics: &mut Diagnostics) {
+        self.base_mat.validate(diagnostics)
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.base_mat.is_emissive()
     }
 }
@@ -103,7 +103,7 @@ pub trait Vec3View: Copy {
 
     // Free implementations
 
-    fn interpolate_towards<V: Vec3View>(self, target: V, t: f64) -> Vec3 where V: Mul<f64, Output=Vec3>, Self: Mul<f64, Output=Vec3> {
+    fn interpolate_towards<V>(self, target: V, t: f64) -> Vec3 where V: Vec3View + Mul<f64, Output=Vec3>, Self: Mul<f64, Output=Vec3> {
         self * (1.0 - t) + target * t
     }
 